@@ -0,0 +1,104 @@
+//! The 0x0BADC0DE demo: builds a number from nibbles with function calls, and writes the payload for the kernel.
+//!
+//! Run with `cargo run --example demo`, it writes out.bin, out.map, out.rel, out.lst and out.o in the current directory.
+
+use ais_asm::{ais, elf, listing};
+use ais_asm::DynAsm;
+
+use std::fs::File;
+use std::io::Write;
+use std::process::Command;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Gen position independent code, so the kernel can place the payload anywhere.
+    // R5 holds the runtime base address of the payload.
+    let mut asm = DynAsm::new_pic("R5".into())?;
+
+    // Add x86 to AIS transition header, this also loads the base register.
+    // The header is the entry point of the payload.
+    let entry = asm.new_named_sym("ais_demo")?;
+    let main = asm.new_named_sym("demo_main")?;
+    ais!(asm =>
+        entry:
+        .header;
+        main:
+    )?;
+
+    // Forward declare push function
+    let push = asm.new_named_sym("demo_push")?;
+
+    // Push some nibbles, the result is a fresh value that the allocator gives a register
+    asm.comment("result = 0");
+    let mut result = asm.new_vreg();
+    asm.gen_load(result.clone(), 0x0)?;
+    for nibble in [0xB, 0xA, 0xD, 0xC, 0x0, 0xD, 0xE] {
+        let x = asm.new_vreg();
+        asm.gen_load(x.clone(), nibble)?;
+        result = asm.gen_call(push, &[result, x])?;
+    }
+
+    // The payload returns the result in EAX. Append footer, this is just a return, so it will return from the
+    // payload back into the kernel.
+    ais!(asm =>
+        ORI EAX, {result}, 0;
+        .footer;
+    )?;
+
+    // Function that will push a nibble in the result, push(result, x) = result << 4 | x.
+    // Calls go through the stack, so it could call other functions or itself.
+    asm.function("demo_push", |f| {
+        let (result, x) = (f.arg(0)?, f.arg(1)?);
+        let shift = f.new_vreg();
+        f.comment("result = result << 4 | x");
+        ais!(f =>
+            load {shift}, 4;
+            XALUR.SHL {result}, {result}, {shift};
+            XALUR.OR {result}, {result}, {x};
+        )?;
+        f.ret(Some(result))
+    })?;
+
+    // Give the fresh values their registers
+    println!("Allocator: {}", asm.allocate()?);
+
+    // Drop the jump from the end of the push function to its return code, and other waste
+    println!("Optimiser: {}", asm.optimize());
+
+    // Check that all symbols are resolved, and get the final payload
+    let image = asm.finish()?;
+
+    // Show dynamic assembled instructions
+    image.dump();
+
+    // Write payload to out.bin, the kernel will included this as the payload
+    let mut output = File::create("out.bin")?;
+    output.by_ref().write_all(image.bytes())?;
+    output.flush()?;
+
+    // Write map file next to the payload, to map addresses back to labels
+    let mut map = File::create("out.map")?;
+    image.write_map(&mut map)?;
+
+    // Write relocation table, so the kernel can rebase any absolute addresses in the payload
+    let mut relocs = File::create("out.rel")?;
+    image.write_relocs(&mut relocs)?;
+
+    // Write annotated listing, with AIS and x86 view of every instruction
+    let mut listing = File::create("out.lst")?;
+    listing::write_listing(&image, &mut listing)?;
+
+    // Write the payload as object file too, so it can be linked instead of included
+    let mut object = File::create("out.o")?;
+    elf::write_elf(&image, &mut object)?;
+
+    // Show generated disassembly in regular x86 instructions, when objdump is installed
+    match Command::new("objdump")
+        .args(["-D", "-bbinary", "-mi386", "-Mintel", "out.bin"])
+        .output()
+    {
+        Ok(output) => println!("{}", String::from_utf8_lossy(&output.stdout)),
+        Err(e) => eprintln!("objdump not available ({}), skipping x86 disassembly", e),
+    }
+
+    Ok(())
+}
@@ -1,10 +1,23 @@
-use num::{FromPrimitive};
-use num_derive::{FromPrimitive};
+//! Encoder and decoder for single AIS instructions.
+//!
+//! Every AIS instruction is wrapped in the x86 `bound` opcode: `62 80` followed by the 32bit little endian
+//! instruction word. With AIS enabled the CPU executes the word instead of the `bound`.
 
+use num_derive::FromPrimitive;
+use num_traits::FromPrimitive;
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Error while encoding or decoding an instruction
 #[derive(Debug)]
 pub enum AisError {
     InvalidRegisterIndex(u8),
     InvalidRegisterName(String),
+    VirtualRegister(u32),
 
     Unsupported(Instruction),
 
@@ -13,34 +26,103 @@ pub enum AisError {
     MissingRt(Instruction),
     MissingRd(Instruction),
     MissingConstant(Instruction),
+    ConstantRange(i8),
     MissingOffset(Instruction),
     MissingFunction(Instruction),
 
-    DecodeError(Vec<u8>),
-    DecodeIssue,
-
+    // Decoding, these hold the instruction word and not the buffer
+    Truncated(usize),
+    NotWrapped([u8; 2]),
     UnknownOpcode(u32),
+    UnsupportedEncoding(Opcode, u32),
+    InvalidFunction(u32),
+}
+
+impl fmt::Display for AisError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AisError::InvalidRegisterIndex(x) => write!(f, "invalid register R{}, registers go up to R31", x),
+            AisError::InvalidRegisterName(x) => write!(f, "invalid register name `{}`", x),
+            AisError::VirtualRegister(x) => write!(f, "virtual register V{} has no physical register yet", x),
+            AisError::Unsupported(x) => write!(f, "can't encode `{}`", x),
+            AisError::MissingImmediate(x) => write!(f, "`{}` needs an immediate", x),
+            AisError::MissingRs(x) => write!(f, "`{}` needs a rs register", x),
+            AisError::MissingRt(x) => write!(f, "`{}` needs a rt register", x),
+            AisError::MissingRd(x) => write!(f, "`{}` needs a rd register", x),
+            AisError::MissingConstant(x) => write!(f, "`{}` needs a constant", x),
+            AisError::ConstantRange(x) => write!(f, "constant {} doesn't fit in 5 bits, the range is -16..=15", x),
+            AisError::MissingOffset(x) => write!(f, "`{}` needs an offset", x),
+            AisError::MissingFunction(x) => write!(f, "`{}` needs a function", x),
+            AisError::Truncated(x) => write!(f, "instruction truncated, {} of 6 bytes left", x),
+            AisError::NotWrapped([a, b]) => {
+                write!(f, "no AIS wrapper, found 0x{:02X} 0x{:02X} instead of 0x62 0x80", a, b)
+            }
+            AisError::UnknownOpcode(x) => write!(f, "unknown opcode 0o{:02o}", x),
+            AisError::UnsupportedEncoding(opcode, word) => {
+                write!(f, "decoding {:?} is not supported, word 0x{:08X}", opcode, word)
+            }
+            AisError::InvalidFunction(x) => write!(f, "invalid function field in word 0x{:08X}", x),
+        }
+    }
 }
 
+impl core::error::Error for AisError {}
+
+/// AIS register, by number 0..=31 or by name. R0 always reads as zero, EAX..EDI are R16..R23.
+/// Virtual registers are handed out by a DynAsm, its register allocator replaces them before encoding.
 #[derive(Debug, Clone, PartialEq)]
 pub enum Register {
     Index(u8),
     Name(String),
+    Virtual(u32),
 }
 
 impl Register {
-    fn bits(&self) -> Result<u32, AisError> {
+    /// Register number as encoded in an instruction field
+    pub fn bits(&self) -> Result<u32, AisError> {
         match self {
             Register::Index(x) if *x > 31 => Err(AisError::InvalidRegisterIndex(*x)),
             Register::Index(x) => Ok((*x).into()),
             Register::Name(x) => match x.as_str() {
-                "R4" => Ok(4),
                 "EAX" => Ok(16),
                 "ECX" => Ok(17),
                 "EDX" => Ok(18),
                 "EBX" => Ok(19),
-                _ => Err(AisError::InvalidRegisterName(x.clone())),
+                "ESP" => Ok(20),
+                "EBP" => Ok(21),
+                "ESI" => Ok(22),
+                "EDI" => Ok(23),
+                _ => x
+                    .strip_prefix('R')
+                    .and_then(|x| x.parse::<u8>().ok())
+                    .filter(|x| *x <= 31)
+                    .map(|x| x.into())
+                    .ok_or_else(|| AisError::InvalidRegisterName(x.clone())),
             },
+            Register::Virtual(x) => Err(AisError::VirtualRegister(*x)),
+        }
+    }
+}
+
+/// Zero register, and the x86 registers as constants, e.g. for `asm.or(EAX, EAX, EDX)`
+pub const R0: Register = Register::Index(0);
+pub const EAX: Register = Register::Index(16);
+pub const ECX: Register = Register::Index(17);
+pub const EDX: Register = Register::Index(18);
+pub const EBX: Register = Register::Index(19);
+pub const ESP: Register = Register::Index(20);
+pub const EBP: Register = Register::Index(21);
+pub const ESI: Register = Register::Index(22);
+pub const EDI: Register = Register::Index(23);
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const X86: [&str; 8] = ["EAX", "ECX", "EDX", "EBX", "ESP", "EBP", "ESI", "EDI"];
+        match self {
+            Register::Index(x @ 16..=23) => write!(f, "{}", X86[(*x - 16) as usize]),
+            Register::Index(x) => write!(f, "R{}", x),
+            Register::Name(x) => write!(f, "{}", x),
+            Register::Virtual(x) => write!(f, "V{}", x),
         }
     }
 }
@@ -81,11 +163,15 @@ pub enum Sel {
     Flat = 0b1010,
 }
 
+/// Sub operation of the load, store and IO instructions
 #[derive(Debug, Copy, Clone)]
 pub enum SubOpXls {
+    // Plain memory access
+    Norm,
     Xio(SubOpXio),
 }
 
+/// Sub operation of the port IO instructions
 #[derive(Debug, Copy, Clone, FromPrimitive)]
 pub enum SubOpXio {
     Norm = 0,
@@ -97,12 +183,14 @@ pub enum AddrSize {
     Bits32 = 0b11,
 }
 
+/// Function field, the low bits of XALU and load/store type instructions
 #[derive(Debug, Copy, Clone)]
 pub enum Function {
     Xls(SubOpXls, AddrSize, Size, Sel),
     Xalu(SubOpXalu, DpCntl),
 }
 
+/// Data path control of XALU instructions, selects the operand size and halves
 #[derive(Debug, Copy, Clone, FromPrimitive)]
 pub enum DpCntl {
     Word = 0b000,
@@ -113,6 +201,7 @@ pub enum DpCntl {
     HH = 0b101,
 }
 
+/// Primary opcode, bits 31..26 of the instruction word
 #[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Copy, Clone, PartialEq, FromPrimitive)]
 pub enum Opcode {
@@ -157,6 +246,7 @@ pub enum Opcode {
     XPUSH = 0o77,
 }
 
+/// Sub operation of the XALU instructions, the x86 ALU operation that is performed
 #[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Copy, Clone, FromPrimitive)]
 pub enum SubOpXalu {
@@ -187,6 +277,63 @@ pub enum SubOpXalu {
     MFLOI = 0o37,
 }
 
+/// x86 condition that SETCC tests on the flags of the last CMPS, numbered like the x86 `SETcc` and `Jcc` opcodes.
+/// The comparison is unsigned for B, AE, BE and A, signed for L, GE, LE and G.
+///
+/// Experimental: the reference doesn't document the operands of SETCC. The condition number in the rt field is an
+/// assumption that hasn't been checked on hardware.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Copy, Clone, PartialEq, FromPrimitive)]
+pub enum Cond {
+    O = 0x0,
+    NO = 0x1,
+    B = 0x2,
+    AE = 0x3,
+    E = 0x4,
+    NE = 0x5,
+    BE = 0x6,
+    A = 0x7,
+    S = 0x8,
+    NS = 0x9,
+    P = 0xA,
+    NP = 0xB,
+    L = 0xC,
+    GE = 0xD,
+    LE = 0xE,
+    G = 0xF,
+}
+
+impl Cond {
+    /// Every condition, in encoding order
+    pub const ALL: [Cond; 16] = [
+        Cond::O,
+        Cond::NO,
+        Cond::B,
+        Cond::AE,
+        Cond::E,
+        Cond::NE,
+        Cond::BE,
+        Cond::A,
+        Cond::S,
+        Cond::NS,
+        Cond::P,
+        Cond::NP,
+        Cond::L,
+        Cond::GE,
+        Cond::LE,
+        Cond::G,
+    ];
+}
+
+// The opposite condition, like x86 it only differs in the lowest bit
+impl core::ops::Not for Cond {
+    type Output = Cond;
+    fn not(self) -> Cond {
+        Cond::ALL[self as usize ^ 1]
+    }
+}
+
+/// One AIS instruction. Fields that the opcode doesn't use are None.
 #[derive(Debug, Clone)]
 pub struct Instruction {
     pub opcode: Opcode,
@@ -200,6 +347,7 @@ pub struct Instruction {
 }
 
 impl Instruction {
+    /// Instruction without any operands, fill in the fields that the opcode needs
     pub fn new(opcode: Opcode) -> Self {
         Self {
             opcode,
@@ -213,6 +361,7 @@ impl Instruction {
         }
     }
 
+    /// Instruction with a 16bit immediate, `dst = src op imm`
     pub fn i_type(opcode: Opcode, dst: Register, src: Register, imm: u16) -> Self {
         let mut ret = Self::new(opcode);
         ret.rs = Some(src);
@@ -221,6 +370,7 @@ impl Instruction {
         ret
     }
 
+    /// Register to register ALU operation, `dst = src op extra`
     pub fn xalur(
         subop: SubOpXalu,
         dpcntl: DpCntl,
@@ -236,6 +386,22 @@ impl Instruction {
         ret
     }
 
+    /// Compare a with b like the x86 `cmp`, this only sets the flags for a following SETCC
+    pub fn cmps(a: Register, b: Register) -> Self {
+        Self::xalur(SubOpXalu::CMPS, DpCntl::Word, 0.into(), a, b)
+    }
+
+    /// dst = 1 when cond holds for the flags, else 0. The condition is encoded in the rt field.
+    ///
+    /// Experimental, the layout is not documented and not checked on hardware, see [`Cond`].
+    pub fn setcc(cond: Cond, dst: Register) -> Self {
+        Self::xalur(SubOpXalu::SETCC, DpCntl::Word, dst, 0.into(), Register::Index(cond as u8))
+    }
+
+    /// ALU operation with a small constant, `dst = src op constant`
+    ///
+    /// Experimental: the constant is encoded as a signed 5bit number in the rt field. The reference doesn't document
+    /// the layout, and it hasn't been checked on hardware.
     pub fn xaluir(
         subop: SubOpXalu,
         dpcntl: DpCntl,
@@ -251,6 +417,7 @@ impl Instruction {
         ret
     }
 
+    /// Write value to an IO port
     pub fn xiow(size: Size, port: Register, value: Register) -> Self {
         let mut instr = Instruction::xls_type(Opcode::XIOW, value, port, Offset::Number(0));
         instr.function = Some(Function::Xls(
@@ -262,6 +429,7 @@ impl Instruction {
         instr
     }
 
+    /// Read an IO port into value
     pub fn xior(size: Size, port: Register, value: Register) -> Self {
         let mut instr = Instruction::xls_type(Opcode::XIOR, value, port, Offset::Number(0));
         instr.function = Some(Function::Xls(
@@ -273,12 +441,28 @@ impl Instruction {
         instr
     }
 
+    /// Load value from the flat address in base
+    pub fn xl(size: Size, base: Register, value: Register) -> Self {
+        let mut instr = Instruction::xls_type(Opcode::XL, value, base, Offset::Number(0));
+        instr.function = Some(Function::Xls(SubOpXls::Norm, AddrSize::Bits32, size, Sel::Flat));
+        instr
+    }
+
+    /// Store value at the flat address in base
+    pub fn xs(size: Size, base: Register, value: Register) -> Self {
+        let mut instr = Instruction::xls_type(Opcode::XS, value, base, Offset::Number(0));
+        instr.function = Some(Function::Xls(SubOpXls::Norm, AddrSize::Bits32, size, Sel::Flat));
+        instr
+    }
+
+    /// Jump to the address in base, and stay in AIS mode
     pub fn xj(base: Register) -> Self {
         let mut ret = Self::new(Opcode::XJ);
         ret.rt = Some(base);
         ret
     }
 
+    /// Load, store or IO instruction, addressing `[base+offset]`
     pub fn xls_type(opcode: Opcode, rs: Register, base: Register, offset: Offset) -> Self {
         let mut ret = Self::new(opcode);
         ret.rs = Some(rs);
@@ -301,6 +485,10 @@ impl Instruction {
         )
     }
 
+    fn is_xls_type(&self) -> bool {
+        matches!(self.opcode, Opcode::XL | Opcode::XS | Opcode::XIOR | Opcode::XIOW)
+    }
+
     fn is_xalu_type(&self) -> bool {
         matches!(self.opcode, Opcode::XALU | Opcode::XALUR)
     }
@@ -343,12 +531,17 @@ impl Instruction {
             .map(|x| x.into())
     }
 
+    // The constant takes the place of the rt field, as a signed 5bit number. Like the SETCC condition this is an
+    // unverified assumption, the reference doesn't show the layout. See Instruction::xaluir.
     fn encode_const(&self) -> Result<u32, AisError> {
-        let c = self
+        let Const::Number(c) = self
             .constant
             .ok_or_else(|| AisError::MissingConstant(self.clone()))?;
 
-        Ok(0 /*c as u32*/) // FIXME
+        match c {
+            -16..=15 => Ok((c as u32 & 0x1F) << 16),
+            _ => Err(AisError::ConstantRange(c)),
+        }
     }
 
     fn encode_offset(&self) -> Result<u32, AisError> {
@@ -366,6 +559,7 @@ impl Instruction {
 
     fn encode_sub_op_xls(&self, subop: SubOpXls) -> Result<u32, AisError> {
         let bits = match subop {
+            SubOpXls::Norm => 0,
             SubOpXls::Xio(x) => x as u32,
         };
 
@@ -393,6 +587,9 @@ impl Instruction {
         Ok(bits)
     }
 
+    /// Encode as the 6 bytes that the CPU fetches, including the wrapper
+    // Literals are grouped by instruction field, not by nibble
+    #[allow(clippy::unusual_byte_groupings)]
     pub fn encode(&self) -> Result<Vec<u8>, AisError> {
         let instr = if self.is_i_type() {
             let op = self.encode_opcode()?;
@@ -422,14 +619,16 @@ impl Instruction {
             let rt = self.encode_rt()?;
 
             op | rt | 0b01_0001_00 // 32bit & stay in AIS mode
-        } else if matches!(self.opcode, Opcode::XIOR | Opcode::XIOW) {
+        } else if self.is_xls_type() {
             let op = self.encode_opcode()?;
             let rs = self.encode_rs()?;
             let base = self.encode_rt()?;
             let offset = self.encode_offset()?;
             let function = self.encode_function()?;
 
-            assert!(function == 0b00_1_00_1010_1_0);
+            if matches!(self.opcode, Opcode::XIOR | Opcode::XIOW) {
+                assert!(function == 0b00_1_00_1010_1_0);
+            }
 
             op | rs | base | offset | function
         } else {
@@ -442,14 +641,24 @@ impl Instruction {
         Ok(data)
     }
 
+    /// Decode all instructions in bytes, with the offset of each. See Recovery for what happens after an error.
+    pub fn iter(bytes: &[u8], recovery: Recovery) -> Instructions<'_> {
+        Instructions {
+            bytes,
+            offset: 0,
+            recovery,
+        }
+    }
+
+    /// Decode the instruction at the start of bytes, returns the instruction and its length
     pub fn decode(bytes: &[u8]) -> Result<(Instruction, usize), AisError> {
         if bytes.len() < 6 {
-            return Err(AisError::DecodeError(bytes.into()));
+            return Err(AisError::Truncated(bytes.len()));
         }
 
-        let header = &bytes[0..2];
+        let header = [bytes[0], bytes[1]];
         if header != [0x62, 0x80] {
-            return Err(AisError::DecodeError(bytes.into()));
+            return Err(AisError::NotWrapped(header));
         }
 
         let word = u32::from_le_bytes(bytes[2..6].try_into().unwrap());
@@ -475,29 +684,145 @@ impl Instruction {
             instr.function = Some(decode_xalu_function(word)?);
             instr.rs = Some(Register::Index(rs_bits));
             instr.rd = Some(Register::Index(rd_bits));
+            instr.constant = Some(Const::Number((rt_bits << 3) as i8 >> 3));
         } else if instr.opcode == Opcode::XJ {
             instr.rt = Some(Register::Index(rt_bits));
-        } else if matches!(instr.opcode, Opcode::XIOR | Opcode::XIOW) {
+        } else if instr.is_xls_type() {
             instr.rs = Some(Register::Index(rs_bits));
             instr.rt = Some(Register::Index(rt_bits));
             instr.offset = Some(Offset::Number(0)); //FIXME
-                                                    //instr.function = Some()
+            instr.function = Some(decode_xls_function(opcode, word)?);
         } else {
-            return Err(AisError::DecodeError(bytes.into()));
+            return Err(AisError::UnsupportedEncoding(opcode, word));
         }
 
         Ok((instr, 6))
     }
 }
 
+/// Where an Instructions iterator continues after bytes that don't decode
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Recovery {
+    /// Try again at the next byte
+    SkipByte,
+    /// Continue at the next 0x62 0x80 wrapper, x86 code and data in between is skipped
+    SkipToWrapper,
+    /// End after the first error
+    Stop,
+}
+
+/// Iterator over the instructions in a byte slice, yields the offset and decode result of each
+pub struct Instructions<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    recovery: Recovery,
+}
+
+impl Iterator for Instructions<'_> {
+    type Item = (usize, Result<Instruction, AisError>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.offset;
+        let rest = self.bytes.get(offset..).filter(|x| !x.is_empty())?;
+
+        match Instruction::decode(rest) {
+            Ok((instr, len)) => {
+                self.offset += len;
+                Some((offset, Ok(instr)))
+            }
+            Err(e) => {
+                self.offset = match self.recovery {
+                    Recovery::SkipByte => offset + 1,
+                    Recovery::SkipToWrapper => rest[1..]
+                        .windows(2)
+                        .position(|x| x == [0x62, 0x80])
+                        .map_or(self.bytes.len(), |x| offset + 1 + x),
+                    Recovery::Stop => self.bytes.len(),
+                };
+                Some((offset, Err(e)))
+            }
+        }
+    }
+}
+
+// Assembly like view, e.g. `ORI EAX, R0, 0x000B` or `XALUR.OR EAX, EAX, EDX`
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut mnemonic = format!("{:?}", self.opcode);
+        match self.function {
+            Some(Function::Xalu(sub_op, DpCntl::Word)) => mnemonic += &format!(".{:?}", sub_op),
+            Some(Function::Xalu(sub_op, dp_cntl)) => {
+                mnemonic += &format!(".{:?}.{:?}", sub_op, dp_cntl)
+            }
+            Some(Function::Xls(_, _, size, _)) => mnemonic += &format!(".{:?}", size),
+            None => (),
+        }
+
+        let reg = |x: &Option<Register>| x.as_ref().map(|x| x.to_string());
+        let operands: Vec<String> = if self.is_i_type() {
+            vec![
+                reg(&self.rt),
+                reg(&self.rs),
+                self.imm.map(|x| format!("0x{:04X}", x)),
+            ]
+        } else if self.is_xalu_type() {
+            // The rt field of SETCC holds the condition
+            let extra = match (self.function, self.rt.as_ref().map(Register::bits)) {
+                (Some(Function::Xalu(SubOpXalu::SETCC, _)), Some(Ok(x))) => {
+                    Some(format!("{:?}", Cond::ALL[x as usize & 15]))
+                }
+                _ => reg(&self.rt),
+            };
+            vec![reg(&self.rd), reg(&self.rs), extra]
+        } else if self.is_xalui_type() {
+            let constant = self.constant.map(|Const::Number(x)| x.to_string());
+            vec![reg(&self.rd), reg(&self.rs), constant]
+        } else if self.offset.is_some() {
+            let offset = self.offset.map(|Offset::Number(x)| x).unwrap_or(0);
+            let base = reg(&self.rt).unwrap_or_default();
+            vec![reg(&self.rs), Some(format!("[{}{:+}]", base, offset))]
+        } else {
+            vec![reg(&self.rd), reg(&self.rs), reg(&self.rt)]
+        }
+        .into_iter()
+        .flatten()
+        .collect();
+
+        if operands.is_empty() {
+            write!(f, "{}", mnemonic)
+        } else {
+            write!(f, "{} {}", mnemonic, operands.join(", "))
+        }
+    }
+}
+
 fn decode_xalu_function(word: u32) -> Result<Function, AisError> {
     let sub_op_bits = word & 0x1F;
-    let dp_cntl_bits = (word >> 5) & 0x3;
-    let sub_op = FromPrimitive::from_u32(sub_op_bits).ok_or(AisError::DecodeIssue)?;
-    let dp_cntl = FromPrimitive::from_u32(dp_cntl_bits).ok_or(AisError::DecodeIssue)?;
+    let dp_cntl_bits = (word >> 5) & 0x7;
+    let sub_op = FromPrimitive::from_u32(sub_op_bits).ok_or(AisError::InvalidFunction(word))?;
+    let dp_cntl = FromPrimitive::from_u32(dp_cntl_bits).ok_or(AisError::InvalidFunction(word))?;
     Ok(Function::Xalu(sub_op, dp_cntl))
 }
 
+// Inverse of the Xls arm of encode_function, the size and address size are split over two places
+fn decode_xls_function(opcode: Opcode, word: u32) -> Result<Function, AisError> {
+    let sub_op_bits = (word >> 9) & 0x3;
+    let addr_size_bits = (word >> 7) & 0x2 | word & 0x1;
+    let size_bits = (word >> 5) & 0x6 | (word >> 1) & 0x1;
+    let sel_bits = (word >> 2) & 0xF;
+
+    let invalid = || AisError::InvalidFunction(word);
+    let sub_op = match opcode {
+        Opcode::XIOR | Opcode::XIOW => SubOpXls::Xio(FromPrimitive::from_u32(sub_op_bits).ok_or_else(invalid)?),
+        _ if sub_op_bits == 0 => SubOpXls::Norm,
+        _ => return Err(invalid()),
+    };
+    let addr_size = FromPrimitive::from_u32(addr_size_bits).ok_or_else(invalid)?;
+    let size = FromPrimitive::from_u32(size_bits).ok_or_else(invalid)?;
+    let sel = FromPrimitive::from_u32(sel_bits).ok_or_else(invalid)?;
+    Ok(Function::Xls(sub_op, addr_size, size, sel))
+}
+
 fn decode_opcode(word: u32) -> Result<Opcode, AisError> {
     let opcode_bits = (word >> 26) & 0x3F;
     FromPrimitive::from_u32(opcode_bits).ok_or(AisError::UnknownOpcode(opcode_bits))
@@ -0,0 +1,80 @@
+//! Constant synthesiser: the shortest sequence of I type instructions that loads a 32bit value.
+//!
+//! Any value takes at most two instructions, ORI for the low half and ORIU for the high half. One instruction is
+//! enough when a single I type instruction maps the zero register, or a register with a known value, to the
+//! wanted value. E.g. `0xFFFFFFFF` is `ADDI dst, R0, 0xFFFF`, and a register that holds `0x1000` becomes
+//! `0x1004` with `ADDI dst, reg, 4`.
+
+use crate::ais::{Instruction, Opcode, Register};
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// I type instructions, in order of preference
+pub const I_TYPE: [Opcode; 8] = [
+    Opcode::ORI,
+    Opcode::ORIU,
+    Opcode::ADDI,
+    Opcode::XORI,
+    Opcode::XORIU,
+    Opcode::ANDI,
+    Opcode::ANDIL,
+    Opcode::ANDIU,
+];
+
+/// Result of an I type instruction for the value of its source register, None for other opcodes.
+/// ADDI sign extends the immediate, the other low half instructions zero extend it. The `U` instructions work
+/// on the high half, ANDIL and ANDIU only mask their own half and keep the other one.
+pub fn i_type_result(opcode: Opcode, rs: u32, imm: u16) -> Option<u32> {
+    let low = imm as u32;
+    let high = low << 16;
+    let result = match opcode {
+        Opcode::ORI => rs | low,
+        Opcode::ORIU => rs | high,
+        Opcode::ADDI => rs.wrapping_add(imm as i16 as u32),
+        Opcode::XORI => rs ^ low,
+        Opcode::XORIU => rs ^ high,
+        Opcode::ANDI => rs & low,
+        Opcode::ANDIL => rs & (0xFFFF0000 | low),
+        Opcode::ANDIU => rs & (high | 0xFFFF),
+        _ => return None,
+    };
+    Some(result)
+}
+
+// Single instruction that maps rs to value
+fn single(rs: u32, value: u32) -> Option<(Opcode, u16)> {
+    // Every working immediate is one of these: a half of the value, of the difference or of the changed bits
+    let diff = value.wrapping_sub(rs);
+    let changed = value ^ rs;
+    let candidates = [value as u16, (value >> 16) as u16, diff as u16, changed as u16, (changed >> 16) as u16];
+
+    I_TYPE.into_iter().find_map(|opcode| {
+        candidates
+            .into_iter()
+            .find(|imm| i_type_result(opcode, rs, *imm) == Some(value))
+            .map(|imm| (opcode, imm))
+    })
+}
+
+/// Shortest instruction sequence that loads value into dst. known lists registers with a known value, that can be
+/// used as starting point. A known dst that already holds the value needs no instruction at all.
+pub fn synthesize(dst: &Register, value: u32, known: &[(Register, u32)]) -> Vec<Instruction> {
+    let same = |a: &Register, b: &Register| a.bits().is_ok() && a.bits().ok() == b.bits().ok();
+    if known.iter().any(|(reg, x)| same(reg, dst) && *x == value) {
+        return Vec::new();
+    }
+
+    // The zero register first, then the known registers
+    let zero = (Register::Index(0), 0);
+    for (reg, rs) in core::iter::once(&zero).chain(known) {
+        if let Some((opcode, imm)) = single(*rs, value) {
+            return vec![Instruction::i_type(opcode, dst.clone(), reg.clone(), imm)];
+        }
+    }
+
+    vec![
+        Instruction::i_type(Opcode::ORI, dst.clone(), 0.into(), value as u16),
+        Instruction::i_type(Opcode::ORIU, dst.clone(), dst.clone(), (value >> 16) as u16),
+    ]
+}
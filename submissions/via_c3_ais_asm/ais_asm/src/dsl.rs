@@ -0,0 +1,256 @@
+//! The [`ais!`](crate::ais!) macro, AIS assembly inside Rust that expands at compile time into [`DynAsm`](crate::DynAsm) calls.
+//!
+//! The syntax follows the text front end in [`crate::parse`], with `;` ending every statement:
+//!
+//! ```text
+//!   top:                        place the Sym in the Rust variable top, `{expr}:` for any expression
+//!   ORI EAX, R0, 0x000B         I type instruction, the immediate is a literal or `{expr}`
+//!   XALUR.SHL EAX, EAX, ECX     XALU instruction with sub operation and optional DpCntl, `XALUR.ADD.Short`
+//!   XALUIR.SHL EAX, EAX, 4      XALU instruction with a constant, a literal or `{expr}`
+//!   XALUR.SETCC EAX, R0, NE     SETCC takes an x86 condition instead of the last register
+//!   XL.Bits32 EAX, [ESP]        memory load, XS store and XIOR, XIOW port IO, value then address
+//!   XJ EAX                      jump to the address in a register
+//!   load EAX, 0x1234            load a 32bit value, a literal or `{expr}`
+//!   load EAX, table + 4         load the address of a Sym, or of a SymExpr
+//!   jump top                    jump to a Sym via a scratch register
+//!   .header / .footer           x86 to AIS transition and return to x86
+//!   .text .rodata .data .bss    switch to a standard section
+//!   .long 0x1234 / .long top    data word with a value or an address, `.align 4` pads
+//! ```
+//!
+//! Registers are R0..R31 and EAX..EDI, or `{expr}` for a [`Register`](crate::Register) such as a virtual register,
+//! which is cloned. The assembler is a [`DynAsm`](crate::DynAsm) or a function body, and the macro evaluates to
+//! `Result<(), DynAsmError>`. Every statement is one level of macro
+//! recursion, so very long blocks need a higher `#![recursion_limit]`.
+//!
+//! ```
+//! use ais_asm::{ais, DynAsm};
+//!
+//! let mut asm = DynAsm::new(0x1000);
+//! let (top, count) = (asm.new_sym(), asm.new_vreg());
+//! ais!(asm =>
+//!     .header;
+//!     load {count}, 10;
+//!     top:
+//!     ADDI {count}, {count}, 0xFFFF;
+//!     jump top;
+//! )?;
+//! # Ok::<(), ais_asm::DynAsmError>(())
+//! ```
+//!
+//! Mistakes in mnemonics and registers are compile errors:
+//!
+//! ```compile_fail
+//! # let mut asm = ais_asm::DynAsm::new(0);
+//! ais_asm::ais!(asm => ORX EAX, R0, 1;)?;
+//! # Ok::<(), ais_asm::DynAsmError>(())
+//! ```
+//!
+//! ```compile_fail
+//! # let mut asm = ais_asm::DynAsm::new(0);
+//! ais_asm::ais!(asm => ORI EAX, R32, 1;)?;
+//! # Ok::<(), ais_asm::DynAsmError>(())
+//! ```
+
+/// Registers by the names the macro accepts
+#[doc(hidden)]
+pub mod regs {
+    use crate::ais::Register;
+
+    pub use crate::ais::{EAX, EBP, EBX, ECX, EDI, EDX, ESI, ESP, R0};
+
+    macro_rules! numbered {
+        ($($name:ident = $x:literal,)*) => {
+            $(pub const $name: Register = Register::Index($x);)*
+        };
+    }
+
+    numbered! {
+        R1 = 1, R2 = 2, R3 = 3, R4 = 4, R5 = 5, R6 = 6, R7 = 7, R8 = 8, R9 = 9, R10 = 10, R11 = 11, R12 = 12,
+        R13 = 13, R14 = 14, R15 = 15, R16 = 16, R17 = 17, R18 = 18, R19 = 19, R20 = 20, R21 = 21, R22 = 22,
+        R23 = 23, R24 = 24, R25 = 25, R26 = 26, R27 = 27, R28 = 28, R29 = 29, R30 = 30, R31 = 31,
+    }
+}
+
+/// AIS assembly in Rust, `ais!(asm => statements)`, see the [`dsl`](crate::dsl) module for the syntax
+#[macro_export]
+macro_rules! ais {
+    // Statements, one at a time
+    (@stmts $a:ident;) => {};
+    (@stmts $a:ident; $label:ident : $($rest:tt)*) => {
+        $a.set_sym_here($label)?;
+        $crate::ais!(@stmts $a; $($rest)*);
+    };
+    (@stmts $a:ident; { $($label:tt)* } : $($rest:tt)*) => {
+        $a.set_sym_here({ $($label)* })?;
+        $crate::ais!(@stmts $a; $($rest)*);
+    };
+    (@stmts $a:ident; . header ; $($rest:tt)*) => {
+        $a.gen_header()?;
+        $crate::ais!(@stmts $a; $($rest)*);
+    };
+    (@stmts $a:ident; . footer ; $($rest:tt)*) => {
+        $a.gen_footer()?;
+        $crate::ais!(@stmts $a; $($rest)*);
+    };
+    (@stmts $a:ident; . align $n:literal ; $($rest:tt)*) => {
+        $a.gen_align($n)?;
+        $crate::ais!(@stmts $a; $($rest)*);
+    };
+    (@stmts $a:ident; . long $value:literal ; $($rest:tt)*) => {
+        $a.gen_u32($value)?;
+        $crate::ais!(@stmts $a; $($rest)*);
+    };
+    (@stmts $a:ident; . long { $($value:tt)* } ; $($rest:tt)*) => {
+        $a.gen_u32({ $($value)* })?;
+        $crate::ais!(@stmts $a; $($rest)*);
+    };
+    (@stmts $a:ident; . long $expr:expr ; $($rest:tt)*) => {
+        $a.gen_u32_expr($crate::SymExpr::from($expr))?;
+        $crate::ais!(@stmts $a; $($rest)*);
+    };
+    (@stmts $a:ident; . $section:ident ; $($rest:tt)*) => {
+        $a.set_section($crate::ais!(@section $section))?;
+        $crate::ais!(@stmts $a; $($rest)*);
+    };
+    (@stmts $a:ident; load $dst:tt , $value:literal ; $($rest:tt)*) => {
+        $a.gen_load($crate::ais!(@reg $dst), $value)?;
+        $crate::ais!(@stmts $a; $($rest)*);
+    };
+    (@stmts $a:ident; load $dst:tt , { $($value:tt)* } ; $($rest:tt)*) => {
+        $a.gen_load($crate::ais!(@reg $dst), { $($value)* })?;
+        $crate::ais!(@stmts $a; $($rest)*);
+    };
+    (@stmts $a:ident; load $dst:tt , $expr:expr ; $($rest:tt)*) => {
+        $a.gen_load_expr($crate::ais!(@reg $dst), $crate::SymExpr::from($expr))?;
+        $crate::ais!(@stmts $a; $($rest)*);
+    };
+    (@stmts $a:ident; jump $target:expr ; $($rest:tt)*) => {
+        $a.gen_jump($target)?;
+        $crate::ais!(@stmts $a; $($rest)*);
+    };
+    (@stmts $a:ident; XJ $base:tt ; $($rest:tt)*) => {
+        $a.gen($crate::Instruction::xj($crate::ais!(@reg $base)))?;
+        $crate::ais!(@stmts $a; $($rest)*);
+    };
+    (@stmts $a:ident; XALUR . SETCC $dst:tt , R0 , $cond:ident ; $($rest:tt)*) => {
+        $a.gen($crate::Instruction::setcc($crate::Cond::$cond, $crate::ais!(@reg $dst)))?;
+        $crate::ais!(@stmts $a; $($rest)*);
+    };
+    // With and without DpCntl suffix in separate arms, a `.` could also be the first operand
+    (@stmts $a:ident; $op:ident . $sub:ident . $dp:ident $dst:tt , $src:tt , $c:literal ; $($rest:tt)*) => {
+        $crate::ais!(@xalui $a, $op, $sub, [$dp], $dst, $src, $c);
+        $crate::ais!(@stmts $a; $($rest)*);
+    };
+    (@stmts $a:ident; $op:ident . $sub:ident $dst:tt , $src:tt , $c:literal ; $($rest:tt)*) => {
+        $crate::ais!(@xalui $a, $op, $sub, [], $dst, $src, $c);
+        $crate::ais!(@stmts $a; $($rest)*);
+    };
+    (@stmts $a:ident; $op:ident . $size:ident $value:tt , [ $base:tt $(+ 0)? ] ; $($rest:tt)*) => {
+        $a.gen($crate::ais!(@xls $op)(
+            $crate::ais::Size::$size,
+            $crate::ais!(@reg $base),
+            $crate::ais!(@reg $value),
+        ))?;
+        $crate::ais!(@stmts $a; $($rest)*);
+    };
+    (@stmts $a:ident; $op:ident . $sub:ident . $dp:ident $dst:tt , $src:tt , $extra:tt ; $($rest:tt)*) => {
+        $crate::ais!(@xalu $a, $op, $sub, [$dp], $dst, $src, $extra);
+        $crate::ais!(@stmts $a; $($rest)*);
+    };
+    (@stmts $a:ident; $op:ident . $sub:ident $dst:tt , $src:tt , $extra:tt ; $($rest:tt)*) => {
+        $crate::ais!(@xalu $a, $op, $sub, [], $dst, $src, $extra);
+        $crate::ais!(@stmts $a; $($rest)*);
+    };
+    (@stmts $a:ident; $op:ident $dst:tt , $src:tt , $imm:expr ; $($rest:tt)*) => {
+        $a.gen($crate::Instruction::i_type(
+            $crate::ais!(@itype $op),
+            $crate::ais!(@reg $dst),
+            $crate::ais!(@reg $src),
+            $imm,
+        ))?;
+        $crate::ais!(@stmts $a; $($rest)*);
+    };
+    (@stmts $a:ident; $($rest:tt)*) => {
+        ::core::compile_error!(::core::concat!("can't parse AIS statement: ", ::core::stringify!($($rest)*)))
+    };
+
+    // XALU with a register or with a constant. The XALUI variants take the constant in braces too.
+    (@xalu $a:ident, XALUI, $sub:ident, [$($dp:ident)?], $dst:tt, $src:tt, { $($c:tt)* }) => {
+        $crate::ais!(@xalui $a, XALUI, $sub, [$($dp)?], $dst, $src, { $($c)* })
+    };
+    (@xalu $a:ident, XALUIR, $sub:ident, [$($dp:ident)?], $dst:tt, $src:tt, { $($c:tt)* }) => {
+        $crate::ais!(@xalui $a, XALUIR, $sub, [$($dp)?], $dst, $src, { $($c)* })
+    };
+    (@xalu $a:ident, $op:ident, $sub:ident, [$($dp:ident)?], $dst:tt, $src:tt, $extra:tt) => {{
+        let mut instr = $crate::Instruction::xalur(
+            $crate::SubOpXalu::$sub,
+            $crate::ais!(@dpcntl $($dp)?),
+            $crate::ais!(@reg $dst),
+            $crate::ais!(@reg $src),
+            $crate::ais!(@reg $extra),
+        );
+        instr.opcode = $crate::ais!(@xalu_op $op);
+        $a.gen(instr)?;
+    }};
+    (@xalui $a:ident, $op:ident, $sub:ident, [$($dp:ident)?], $dst:tt, $src:tt, $c:expr) => {{
+        let mut instr = $crate::Instruction::xaluir(
+            $crate::SubOpXalu::$sub,
+            $crate::ais!(@dpcntl $($dp)?),
+            $crate::ais!(@reg $dst),
+            $crate::ais!(@reg $src),
+            $crate::ais::Const::Number($c),
+        );
+        instr.opcode = $crate::ais!(@xalui_op $op);
+        $a.gen(instr)?;
+    }};
+
+    // Operands and opcodes, anything else is a compile error
+    (@reg { $($e:tt)* }) => {
+        ::core::clone::Clone::clone(&($($e)*))
+    };
+    (@reg $r:ident) => {
+        $crate::dsl::regs::$r
+    };
+    (@dpcntl) => {
+        $crate::DpCntl::Word
+    };
+    (@dpcntl $dp:ident) => {
+        $crate::DpCntl::$dp
+    };
+    (@section text) => { $crate::dynasm::Section::TEXT };
+    (@section rodata) => { $crate::dynasm::Section::RODATA };
+    (@section data) => { $crate::dynasm::Section::DATA };
+    (@section bss) => { $crate::dynasm::Section::BSS };
+    (@section $x:ident) => {
+        ::core::compile_error!(::core::concat!("unknown AIS directive `.", ::core::stringify!($x), "`"))
+    };
+    (@itype ORIU) => { $crate::Opcode::ORIU };
+    (@itype ADDI) => { $crate::Opcode::ADDI };
+    (@itype ANDIU) => { $crate::Opcode::ANDIU };
+    (@itype ANDIL) => { $crate::Opcode::ANDIL };
+    (@itype ANDI) => { $crate::Opcode::ANDI };
+    (@itype ORI) => { $crate::Opcode::ORI };
+    (@itype XORI) => { $crate::Opcode::XORI };
+    (@itype XORIU) => { $crate::Opcode::XORIU };
+    (@xalu_op XALU) => { $crate::Opcode::XALU };
+    (@xalu_op XALUR) => { $crate::Opcode::XALUR };
+    (@xalui_op XALUI) => { $crate::Opcode::XALUI };
+    (@xalui_op XALUIR) => { $crate::Opcode::XALUIR };
+    (@xls XL) => { $crate::Instruction::xl };
+    (@xls XS) => { $crate::Instruction::xs };
+    (@xls XIOR) => { $crate::Instruction::xior };
+    (@xls XIOW) => { $crate::Instruction::xiow };
+    (@$kind:ident $op:ident) => {
+        ::core::compile_error!(::core::concat!("unknown AIS instruction `", ::core::stringify!($op), "`"))
+    };
+
+    // Structured::asm() takes a DynAsm or a FunctionBuilder, by value or through a reference
+    ($asm:expr => $($body:tt)*) => {{
+        use $crate::Structured as _;
+        (|asm: &mut $crate::DynAsm| -> ::core::result::Result<(), $crate::DynAsmError> {
+            $crate::ais!(@stmts asm; $($body)*);
+            ::core::result::Result::Ok(())
+        })($asm.asm())
+    }};
+}
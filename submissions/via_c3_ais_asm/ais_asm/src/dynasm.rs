@@ -1,12 +1,54 @@
+//! Dynamic assembler: generate AIS code from Rust, with symbols, sections and fixups.
+//!
+//! Code is generated with the `gen*` methods of [`DynAsm`]. Symbols can be used before they get an
+//! address. Nothing is placed until [`DynAsm::finish`]: it lays out the sections, gives every address load
+//! the shortest encoding its final value allows, patches every reference and hands out the final [`Image`].
 
-use crate::ais::{AisError, Instruction, Opcode, Register};
 
+use crate::ais::{AisError, DpCntl, Instruction, Opcode, Register, Size, SubOpXalu};
+use crate::constant;
+
+mod alu;
+mod control;
+mod function;
+mod peephole;
+mod regalloc;
+pub use self::control::Structured;
+pub use self::function::FunctionBuilder;
+pub use self::peephole::OptReport;
+pub use self::regalloc::AllocReport;
+use crate::reloc::{self, BitField, RelocEntry, RelocKind};
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::{Add, Sub};
+use core::panic::Location;
+use core::sync::atomic::{AtomicU32, Ordering};
+#[cfg(feature = "std")]
+use std::io::Write;
+
+/// Error while generating or finishing a payload
 #[derive(Debug)]
 pub enum DynAsmError {
     AisError(AisError),
     InvalidSym,
-    SymbolRedefined,
-    ResolveUnstable,
+    SymbolRedefined(String),
+    DuplicateSymbol(String),
+    ForeignSym,
+    Unresolved(Vec<UnresolvedSym>),
+    InvalidSection,
+    DuplicateSection(String),
+    NoBitsData(String),
+    HeaderPlacement,
+    NoScratchRegister,
+    ScratchLive(Register),
+    NotAllocatable(Register),
+    OutOfRegisters,
+    TooManyArguments(usize),
 }
 
 impl From<AisError> for DynAsmError {
@@ -15,77 +57,495 @@ impl From<AisError> for DynAsmError {
     }
 }
 
-#[derive(Copy, Clone)]
-pub struct Sym(usize);
+impl fmt::Display for DynAsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DynAsmError::AisError(_) => write!(f, "failed to encode instruction"),
+            DynAsmError::InvalidSym => write!(f, "symbol doesn't exist"),
+            DynAsmError::SymbolRedefined(x) => write!(f, "symbol `{}` already has an address", x),
+            DynAsmError::DuplicateSymbol(x) => write!(f, "symbol `{}` is already defined", x),
+            DynAsmError::ForeignSym => write!(f, "symbol belongs to another DynAsm"),
+            DynAsmError::Unresolved(x) => {
+                write!(f, "unresolved symbols: ")?;
+                for (i, sym) in x.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", sym)?;
+                }
+                Ok(())
+            }
+            DynAsmError::InvalidSection => write!(f, "section doesn't exist"),
+            DynAsmError::DuplicateSection(x) => write!(f, "section `{}` already exists", x),
+            DynAsmError::NoBitsData(x) => write!(f, "section `{}` can only hold zeros", x),
+            DynAsmError::HeaderPlacement => {
+                write!(f, "position independent header must be in .text, within 0x8000 bytes of the start")
+            }
+            DynAsmError::NoScratchRegister => write!(f, "no scratch register configured"),
+            DynAsmError::ScratchLive(x) => write!(f, "scratch register {} holds a live value", x),
+            DynAsmError::NotAllocatable(x) => write!(f, "register {} can't be handed out for virtual registers", x),
+            DynAsmError::OutOfRegisters => write!(f, "spilling needs two free registers in the allocation pool"),
+            DynAsmError::TooManyArguments(x) => {
+                write!(f, "{} arguments, functions take at most {}", x, function::ARGS.len())
+            }
+        }
+    }
+}
 
-enum Symbol {
-    Unresolved(Vec<SymRef>),
-    Resolved(u32),
+impl core::error::Error for DynAsmError {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            DynAsmError::AisError(x) => Some(x),
+            _ => None,
+        }
+    }
 }
 
+/// Symbol that is referenced, but never got an address
 #[derive(Debug)]
-enum SymRefKind {
+pub struct UnresolvedSym {
+    pub name: String,
+    // Payload offsets of the instructions or data words that reference the symbol
+    pub refs: Vec<u32>,
+}
+
+impl fmt::Display for UnresolvedSym {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let refs: Vec<String> = self.refs.iter().map(|x| format!("0x{:X}", x)).collect();
+        write!(f, "`{}` referenced at {}", self.name, refs.join(", "))
+    }
+}
+
+// Every DynAsm gets an unique id, so a Sym can't be used with another instance
+static NEXT_ASM_ID: AtomicU32 = AtomicU32::new(0);
+
+/// Handle to a symbol of one DynAsm, an address that can be used before it is known
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct Sym {
+    owner: u32,
+    index: usize,
+}
+
+/// Address expression that is evaluated when all its symbols are resolved.
+/// Build them with operators, e.g. `end - start` or `table + 8`.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct SymExpr {
+    sym: Sym,
+    sub: Option<Sym>,
+    addend: i32,
+}
+
+impl From<Sym> for SymExpr {
+    fn from(sym: Sym) -> Self {
+        Self {
+            sym,
+            sub: None,
+            addend: 0,
+        }
+    }
+}
+
+impl Add<i32> for Sym {
+    type Output = SymExpr;
+    fn add(self, rhs: i32) -> SymExpr {
+        SymExpr::from(self) + rhs
+    }
+}
+
+impl Sub<i32> for Sym {
+    type Output = SymExpr;
+    fn sub(self, rhs: i32) -> SymExpr {
+        SymExpr::from(self) - rhs
+    }
+}
+
+impl Sub<Sym> for Sym {
+    type Output = SymExpr;
+    fn sub(self, rhs: Sym) -> SymExpr {
+        SymExpr {
+            sym: self,
+            sub: Some(rhs),
+            addend: 0,
+        }
+    }
+}
+
+impl Add<i32> for SymExpr {
+    type Output = SymExpr;
+    fn add(mut self, rhs: i32) -> SymExpr {
+        self.addend = self.addend.wrapping_add(rhs);
+        self
+    }
+}
+
+impl Sub<i32> for SymExpr {
+    type Output = SymExpr;
+    fn sub(mut self, rhs: i32) -> SymExpr {
+        self.addend = self.addend.wrapping_sub(rhs);
+        self
+    }
+}
+
+impl SymExpr {
+    fn syms(&self) -> impl Iterator<Item = Sym> {
+        core::iter::once(self.sym).chain(self.sub)
+    }
+}
+
+struct Symbol {
+    name: Option<String>,
+    // Section and index of the item the symbol points at, the end of a section is the number of items
+    loc: Option<(Section, usize)>,
+}
+
+/// Entry of the symbol table, as written to the map file
+#[derive(Debug, Clone)]
+pub struct SymbolInfo {
+    pub name: String,
+    pub section: String,
+    pub addr: u32,
+    pub size: u32,
+}
+
+/// Handle to a section of a DynAsm. The predefined sections are placed in this order: text, rodata, data, bss.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Section(usize);
+
+impl Section {
+    pub const TEXT: Section = Section(0);
+    pub const RODATA: Section = Section(1);
+    pub const DATA: Section = Section(2);
+    pub const BSS: Section = Section(3);
+}
+
+struct SectionData {
+    name: String,
+    align: u32,
+    // Only zeros can be emitted, like .bss
+    nobits: bool,
+    items: Vec<Item>,
+}
+
+/// What a range of emitted bytes is, for listings
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LineKind {
+    Header,
+    Instruction,
+    Data,
+    Zeros,
+    Footer,
+}
+
+/// Rust source location of the code that generated a line
+#[derive(Debug, Copy, Clone)]
+pub struct SourceLoc {
+    pub file: &'static str,
+    pub line: u32,
+}
+
+impl SourceLoc {
+    #[track_caller]
+    fn caller() -> Self {
+        let location = Location::caller();
+        Self {
+            file: location.file(),
+            line: location.line(),
+        }
+    }
+}
+
+impl fmt::Display for SourceLoc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+/// Range of bytes that was emitted by one gen call
+#[derive(Debug, Clone)]
+pub struct Line {
+    pub addr: u32,
+    pub len: u32,
+    pub kind: LineKind,
+    pub comment: Option<String>,
+    pub source: SourceLoc,
+}
+
+
+/// Placement of a section in the final image
+#[derive(Debug, Clone)]
+pub struct SectionInfo {
+    pub name: String,
+    pub addr: u32,
+    pub size: u32,
+    pub align: u32,
+}
+
+/// Part of an expression value that is patched into an immediate
+#[derive(Debug, Copy, Clone)]
+pub enum SymRefKind {
+    // Bits 31..16, for an instruction that is combined with a zero extended low half, like ORI
     HighImm,
+    // Bits 15..0
     LowImm,
+    // Bits 31..16, adjusted for a low half that is sign extended by an instruction like ADDI
+    HighImmAdj,
+    // Whole 32bit value as little endian data word
+    Word,
 }
 
-struct SymRef {
-    kind: SymRefKind,
-    offset: u32,
+impl SymRefKind {
+    fn reloc_kind(self) -> RelocKind {
+        match self {
+            SymRefKind::HighImm => RelocKind::HighImm,
+            SymRefKind::LowImm => RelocKind::LowImm,
+            SymRefKind::HighImmAdj => RelocKind::HighImmAdj,
+            SymRefKind::Word => RelocKind::Word,
+        }
+    }
 }
 
-pub struct DynAsm {
-    base: u32,
-    memory: Vec<u8>,
-    symbols: Vec<Symbol>,
+// Reference that is patched into the bytes of an item, only the bits of field are written
+#[derive(Clone)]
+struct Fixup {
+    expr: SymExpr,
+    kind: SymRefKind,
+    field: BitField,
 }
 
-trait Symbols {
-    fn get(&mut self, sym: Sym) -> Result<&mut Symbol, DynAsmError>;
+// Load of an address expression, it gets the shortest encoding that the final value allows
+#[derive(Clone)]
+struct Load {
+    dst: Register,
+    expr: SymExpr,
+    // Sign extending ADDI for the low half, instead of ORI
+    addi: bool,
+    // Runtime base register that is added in PIC mode
+    rebase: Option<Register>,
+    // Both halves are needed, for the value or so the relocation table can patch them
+    long: bool,
 }
 
-impl Symbols for Vec<Symbol> {
-    fn get(&mut self, sym: Sym) -> Result<&mut Symbol, DynAsmError> {
-        self.get_mut(sym.0).ok_or(DynAsmError::InvalidSym)
+impl Load {
+    fn needs_long(&self, value: u32) -> bool {
+        let fits = match self.addi {
+            true => value as i16 as u32 == value,
+            false => value >> 16 == 0,
+        };
+        !fits && value & 0xFFFF != 0
+    }
+
+    // Instructions for the final value, with the relocation kind of their immediate
+    fn instructions(&self, value: u32) -> Vec<(Instruction, Option<RelocKind>)> {
+        let dst = &self.dst;
+        let i_type = |opcode, src: Register, imm| Instruction::i_type(opcode, dst.clone(), src, imm);
+        let high = (value >> 16) as u16;
+        let high_adj = (value.wrapping_add(0x8000) >> 16) as u16;
+
+        let mut list = match (self.long || self.needs_long(value), self.addi) {
+            (true, false) => vec![
+                (i_type(Opcode::ORI, 0.into(), value as u16), Some(RelocKind::LowImm)),
+                (i_type(Opcode::ORIU, dst.clone(), high), Some(RelocKind::HighImm)),
+            ],
+            (true, true) => vec![
+                (i_type(Opcode::ORIU, 0.into(), high_adj), Some(RelocKind::HighImmAdj)),
+                (i_type(Opcode::ADDI, dst.clone(), value as u16), Some(RelocKind::LowImm)),
+            ],
+            // A single instruction, the half that isn't zero
+            (false, addi) if value & 0xFFFF != 0 || value == 0 => {
+                let opcode = if addi { Opcode::ADDI } else { Opcode::ORI };
+                vec![(i_type(opcode, 0.into(), value as u16), Some(RelocKind::LowImm))]
+            }
+            (false, _) => vec![(i_type(Opcode::ORIU, 0.into(), high), Some(RelocKind::HighImm))],
+        };
+
+        if let Some(base_reg) = &self.rebase {
+            let add = Instruction::xalur(SubOpXalu::ADD, DpCntl::Word, dst.clone(), dst.clone(), base_reg.clone());
+            list.push((add, None));
+        }
+        list
     }
 }
 
-trait Memory {
-    fn sym_ref_resolve(&mut self, sym_ref: SymRef, addr: u32) -> Result<(), DynAsmError>;
+#[derive(Clone)]
+enum ItemKind {
+    // Bytes that don't depend on the layout, apart from the bits of a fixup
+    Bytes(Vec<u8>, Option<Fixup>),
+    // Zero padding up to a multiple of the alignment
+    Align(u32),
+    Load(Load),
+    // Instruction with virtual registers, the register allocator replaces it
+    Instr(Instruction),
+    // ADDI that derives the runtime base from the address that a PIC header leaves in EAX
+    PicBase(Register),
+    Frame(Frame),
 }
 
-impl Memory for Vec<u8> {
-    fn sym_ref_resolve(&mut self, sym_ref: SymRef, addr: u32) -> Result<(), DynAsmError> {
-        // Decode
-        let start = sym_ref.offset.try_into().unwrap();
-        let end = self.len();
-        let bytes = self.get_mut(start..end).unwrap();
-        let (mut instr, len) = Instruction::decode(bytes)?;
+// Stack frame of a function, or of the code between header and footer. The register allocator fills it in, an
+// empty frame has no instructions.
+#[derive(Clone)]
+struct Frame {
+    // 0 for the code outside functions, else 1 + the index of the function
+    region: usize,
+    // Set up the frame, or release it
+    enter: bool,
+    // Callee saved registers, pushed in this order
+    saved: Vec<u32>,
+    // 4 byte slots for spilled values, below the saved registers
+    slots: u32,
+}
+
+impl Frame {
+    fn new(region: usize, enter: bool) -> Self {
+        Self {
+            region,
+            enter,
+            saved: Vec::new(),
+            slots: 0,
+        }
+    }
 
-        // Fixup
-        match sym_ref.kind {
-            SymRefKind::LowImm => {
-                instr.imm = Some((addr & 0xFFFF).try_into().unwrap());
+    fn instructions(&self) -> Vec<Instruction> {
+        let reg = |x: u32| Register::Index(x as u8);
+        let esp = || reg(20);
+        let addi = |imm: u32| Instruction::i_type(Opcode::ADDI, esp(), esp(), imm as u16);
+
+        let mut list = Vec::new();
+        if self.enter {
+            for x in &self.saved {
+                list.push(addi(4u32.wrapping_neg()));
+                list.push(Instruction::xs(Size::Bits32, esp(), reg(*x)));
+            }
+            if self.slots > 0 {
+                list.push(addi((4 * self.slots).wrapping_neg()));
+            }
+        } else {
+            if self.slots > 0 {
+                list.push(addi(4 * self.slots));
             }
-            SymRefKind::HighImm => {
-                instr.imm = Some((addr >> 16 & 0xFFFF).try_into().unwrap());
+            for x in self.saved.iter().rev() {
+                list.push(Instruction::xl(Size::Bits32, esp(), reg(*x)));
+                list.push(addi(4));
             }
         }
+        list
+    }
+}
 
-        // Encode
-        let new_bytes = instr.encode()?;
-        if new_bytes.len() != len {
-            return Err(DynAsmError::ResolveUnstable);
+// Instruction or data that gets its address and final encoding when the image is finished
+#[derive(Clone)]
+struct Item {
+    kind: ItemKind,
+    line: LineKind,
+    comment: Option<String>,
+    source: SourceLoc,
+}
+
+impl Item {
+    fn size(&self, offset: u32) -> u32 {
+        match &self.kind {
+            ItemKind::Bytes(bytes, _) => bytes.len() as u32,
+            ItemKind::Align(align) => (align - offset % align) % align,
+            ItemKind::Load(load) => 6 * (1 + load.long as u32 + load.rebase.is_some() as u32),
+            ItemKind::Instr(_) | ItemKind::PicBase(_) => 6,
+            ItemKind::Frame(frame) => 6 * frame.instructions().len() as u32,
         }
+    }
 
-        let old_bytes = &mut bytes[0..len];
-        old_bytes.copy_from_slice(&new_bytes);
+    fn expr(&self) -> Option<SymExpr> {
+        match &self.kind {
+            ItemKind::Bytes(_, Some(fixup)) => Some(fixup.expr),
+            ItemKind::Load(load) => Some(load.expr),
+            _ => None,
+        }
+    }
+}
 
-        Ok(())
+// Placement of the sections, and the offset of every item in its section. The last offset is the section size.
+struct Layout {
+    sections: Vec<SectionInfo>,
+    offsets: Vec<Vec<u32>>,
+}
+
+/// Dynamic assembler for one payload
+pub struct DynAsm {
+    base: u32,
+    sections: Vec<SectionData>,
+    current: Section,
+    symbols: SymbolTable,
+    // Comment for the next emitted line
+    comment: Option<String>,
+    // Registers that helpers like gen_jump may clobber
+    scratch: Vec<Register>,
+    // Registers that the user has marked as holding a value, helpers must not touch these
+    live: Vec<Register>,
+    // Register that holds the runtime base address, when generating position independent code
+    pic: Option<Register>,
+    // Source location to record instead of the Rust caller, for code generated from a source file
+    source: Option<SourceLoc>,
+    // Absolute addresses keep their full encoding, so the relocation table can move the image
+    relocatable: bool,
+    // Number of virtual registers handed out
+    vregs: u32,
+    // Physical registers that virtual registers may get, in order of preference
+    pool: Vec<Register>,
+    // Entry and end of every function
+    functions: Vec<(Sym, Sym)>,
+    // Return addresses of calls, the XJ right before each one is a call
+    calls: Vec<Sym>,
+    // Fall through position and target of conditional branches, the XJ right before the first one is a branch
+    branches: Vec<(Sym, Sym)>,
+}
+
+struct SymbolTable {
+    owner: u32,
+    entries: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    fn new() -> Self {
+        Self {
+            owner: NEXT_ASM_ID.fetch_add(1, Ordering::Relaxed),
+            entries: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, symbol: Symbol) -> Sym {
+        self.entries.push(symbol);
+        Sym {
+            owner: self.owner,
+            index: self.entries.len() - 1,
+        }
+    }
+
+    fn index(&self, sym: Sym) -> Result<usize, DynAsmError> {
+        if sym.owner != self.owner {
+            return Err(DynAsmError::ForeignSym);
+        }
+        Ok(sym.index)
+    }
+
+    fn get(&mut self, sym: Sym) -> Result<&mut Symbol, DynAsmError> {
+        let index = self.index(sym)?;
+        self.entries.get_mut(index).ok_or(DynAsmError::InvalidSym)
+    }
+
+    fn name(&self, sym: Sym) -> Result<String, DynAsmError> {
+        let index = self.index(sym)?;
+        let symbol = self.entries.get(index).ok_or(DynAsmError::InvalidSym)?;
+        Ok(symbol.display_name(index))
     }
 }
 
-const HEADER: &[u8] = &[
+impl Symbol {
+    fn display_name(&self, index: usize) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!("sym#{}", index),
+        }
+    }
+}
+
+pub(crate) const HEADER: &[u8] = &[
     0xE8, 0x00, 0x00, 0x00, 0x00,   //     call 1f
     0x58,                           // 1:  pop eax
     0x83, 0xC0, 0x06,               //     add eax, 6
@@ -93,164 +553,851 @@ const HEADER: &[u8] = &[
     // <- jmpai should jump to here, this is where the AI wrapper instruction start.
 ];
 
-const FOOTER: &[u8] = &[
+pub(crate) const FOOTER: &[u8] = &[
     0xC3, // ret
 ];
 
+/// Length and x86 view of each instruction in the header and footer, for listings and disassemblers
+pub const HEADER_X86: &[(usize, &str)] = &[
+    (5, "call $+5"),
+    (1, "pop eax"),
+    (3, "add eax,0x6"),
+    (2, "jmpai eax"),
+];
+pub const FOOTER_X86: &[(usize, &str)] = &[(1, "ret")];
+
 impl DynAsm {
+    /// Assembler for a payload that is loaded at base
     pub fn new(base: u32) -> Self {
+        let section = |name: &str, align, nobits| SectionData {
+            name: name.to_string(),
+            align,
+            nobits,
+            items: Vec::new(),
+        };
+
         Self {
             base,
-            memory: Vec::new(),
-            symbols: Vec::new(),
+            sections: vec![
+                section(".text", 1, false),
+                section(".rodata", 4, false),
+                section(".data", 4, false),
+                section(".bss", 4, true),
+            ],
+            current: Section::TEXT,
+            symbols: SymbolTable::new(),
+            comment: None,
+            scratch: vec!["R4".into()],
+            live: Vec::new(),
+            pic: None,
+            source: None,
+            relocatable: false,
+            vregs: 0,
+            pool: regalloc::default_pool(),
+            functions: Vec::new(),
+            calls: Vec::new(),
+            branches: Vec::new(),
         }
     }
 
-    fn offset(&self) -> u32 {
-        self.memory.len().try_into().unwrap()
+    /// Position independent code. The image is linked at 0, and the header loads the runtime
+    /// base address into base_reg. Symbol loads add base_reg, so the image can be placed anywhere.
+    /// base_reg is reserved for the whole payload.
+    pub fn new_pic(base_reg: Register) -> Result<Self, DynAsmError> {
+        let mut asm = Self::new(0);
+        asm.set_live(base_reg.clone())?;
+        asm.pic = Some(base_reg);
+        Ok(asm)
     }
 
-    fn addr(&self) -> u32 {
-        self.offset() + self.base
+    /// Whether the image gets a relocation table, off by default. Then every absolute address of a non PIC image
+    /// keeps both halves, so it can be patched for another load address. Without, the image only runs at its base,
+    /// it has no relocations and every address load gets the shortest encoding. PIC images can always be moved,
+    /// their absolute data words are in the relocation table either way.
+    pub fn set_relocatable(&mut self, relocatable: bool) {
+        self.relocatable = relocatable;
     }
 
-    fn sym_resolve(&mut self, sym: Sym, addr: u32) -> Result<(), DynAsmError> {
-        let symbol = self.symbols.get(sym)?;
-        let old = core::mem::replace(symbol, Symbol::Resolved(addr));
+    /// Set the registers that helpers are allowed to use as scratch, in order of preference
+    pub fn set_scratch(&mut self, regs: &[Register]) {
+        self.scratch = regs.to_vec();
+    }
 
-        match old {
-            Symbol::Unresolved(refs) => {
-                for sym_ref in refs {
-                    self.memory.sym_ref_resolve(sym_ref, addr)?;
-                }
+    /// Mark register as holding a user value, helpers will refuse to clobber it
+    pub fn set_live(&mut self, reg: Register) -> Result<(), DynAsmError> {
+        if !self.is_live(&reg)? {
+            self.live.push(reg);
+        }
+        Ok(())
+    }
+
+    /// Release a register that was marked live, so helpers may use it again
+    pub fn set_dead(&mut self, reg: Register) -> Result<(), DynAsmError> {
+        let bits = reg.bits()?;
+        self.live.retain(|x| x.bits().ok() != Some(bits));
+        Ok(())
+    }
+
+    fn is_live(&self, reg: &Register) -> Result<bool, DynAsmError> {
+        let bits = reg.bits()?;
+        Ok(self.live.iter().any(|x| x.bits().ok() == Some(bits)))
+    }
+
+    // Pick the first allowed scratch register that isn't live
+    fn scratch(&self) -> Result<Register, DynAsmError> {
+        for reg in &self.scratch {
+            if !self.is_live(reg)? {
+                return Ok(reg.clone());
             }
-            Symbol::Resolved(_) => return Err(DynAsmError::SymbolRedefined),
         }
 
+        match self.scratch.first() {
+            Some(reg) => Err(DynAsmError::ScratchLive(reg.clone())),
+            None => Err(DynAsmError::NoScratchRegister),
+        }
+    }
+
+    // Check a scratch register that was passed explicitly to a helper
+    fn scratch_override(&self, reg: Register) -> Result<Register, DynAsmError> {
+        if self.is_live(&reg)? {
+            return Err(DynAsmError::ScratchLive(reg));
+        }
+        Ok(reg)
+    }
+
+    /// Add a section, it is placed after all existing sections
+    pub fn add_section(&mut self, name: &str, align: u32, nobits: bool) -> Result<Section, DynAsmError> {
+        if self.section_by_name(name).is_some() {
+            return Err(DynAsmError::DuplicateSection(name.to_string()));
+        }
+
+        self.sections.push(SectionData {
+            name: name.to_string(),
+            align: align.max(1),
+            nobits,
+            items: Vec::new(),
+        });
+        Ok(Section(self.sections.len() - 1))
+    }
+
+    /// Look up a section, including the predefined `.text`, `.rodata`, `.data` and `.bss`
+    pub fn section_by_name(&self, name: &str) -> Option<Section> {
+        self.sections.iter().position(|x| x.name == name).map(Section)
+    }
+
+    /// Switch the section that instructions and data are emitted to, each section keeps its own cursor
+    pub fn set_section(&mut self, section: Section) -> Result<(), DynAsmError> {
+        if section.0 >= self.sections.len() {
+            return Err(DynAsmError::InvalidSection);
+        }
+        self.current = section;
         Ok(())
     }
 
-    fn sym_fixup(&mut self, sym: Sym, kind: SymRefKind) -> Result<(), DynAsmError> {
-        let sym_ref = SymRef {
-            offset: self.offset() - 6,
-            kind,
-        };
+    /// Section that is currently emitted to
+    pub fn section(&self) -> Section {
+        self.current
+    }
+
+    // Position of the next item in the current section
+    fn here(&self) -> (Section, usize) {
+        (self.current, self.sections[self.current.0].items.len())
+    }
 
-        match self.symbols.get(sym)? {
-            Symbol::Unresolved(refs) => refs.push(sym_ref),
-            Symbol::Resolved(addr) => self.memory.sym_ref_resolve(sym_ref, *addr)?,
+    #[track_caller]
+    fn push(&mut self, kind: ItemKind, line: LineKind) -> Result<(), DynAsmError> {
+        let section = &mut self.sections[self.current.0];
+        let zeros = match &kind {
+            ItemKind::Bytes(bytes, _) => bytes.iter().all(|x| *x == 0),
+            ItemKind::Align(_) => true,
+            _ => false,
         };
+        if section.nobits && !zeros {
+            return Err(DynAsmError::NoBitsData(section.name.clone()));
+        }
 
+        section.items.push(Item {
+            kind,
+            line,
+            comment: self.comment.take(),
+            source: match self.source {
+                Some(source) => source,
+                None => SourceLoc::caller(),
+            },
+        });
         Ok(())
     }
 
+    #[track_caller]
+    fn emit(&mut self, bytes: &[u8], line: LineKind) -> Result<(), DynAsmError> {
+        self.push(ItemKind::Bytes(bytes.to_vec(), None), line)
+    }
+
+    /// Attach a comment to the next instruction or data, it shows up in the listing
+    pub fn comment(&mut self, text: &str) {
+        self.comment = Some(text.to_string());
+    }
+
+    /// Record this location for the following lines instead of the Rust caller, None goes back to the caller
+    pub fn set_source(&mut self, source: Option<SourceLoc>) {
+        self.source = source;
+    }
+
+    fn sym_resolve(&mut self, sym: Sym, loc: (Section, usize)) -> Result<(), DynAsmError> {
+        let symbol = self.symbols.get(sym)?;
+        if symbol.loc.is_some() {
+            return Err(DynAsmError::SymbolRedefined(self.symbols.name(sym)?));
+        }
+        symbol.loc = Some(loc);
+        Ok(())
+    }
+
+    // Bytes with a reference that is patched when the image is finished
+    #[track_caller]
+    fn emit_fixup(&mut self, bytes: Vec<u8>, line: LineKind, fixup: Fixup) -> Result<(), DynAsmError> {
+        for sym in fixup.expr.syms() {
+            self.symbols.get(sym)?;
+        }
+        self.push(ItemKind::Bytes(bytes, Some(fixup)), line)
+    }
+
+    /// Anonymous symbol without an address yet
     pub fn new_sym(&mut self) -> Sym {
-        let entry = Symbol::Unresolved(Vec::new());
-        self.symbols.push(entry);
-        Sym(self.symbols.len() - 1)
+        let entry = Symbol {
+            name: None,
+            loc: None,
+        };
+        self.symbols.push(entry)
     }
 
+    /// Named symbols show up in dumps, errors and the map file. Names must be unique.
+    pub fn new_named_sym(&mut self, name: &str) -> Result<Sym, DynAsmError> {
+        if self.sym_by_name(name).is_some() {
+            return Err(DynAsmError::DuplicateSymbol(name.to_string()));
+        }
+
+        let sym = self.new_sym();
+        self.symbols.get(sym)?.name = Some(name.to_string());
+        Ok(sym)
+    }
+
+    /// Look up a named symbol
+    pub fn sym_by_name(&self, name: &str) -> Option<Sym> {
+        let index = self
+            .symbols
+            .entries
+            .iter()
+            .position(|x| x.name.as_deref() == Some(name))?;
+        Some(Sym {
+            owner: self.symbols.owner,
+            index,
+        })
+    }
+
+    /// Name of a symbol, anonymous symbols get a generated name
+    pub fn sym_name(&self, sym: Sym) -> Result<String, DynAsmError> {
+        self.symbols.name(sym)
+    }
+
+    /// Symbol at the current location
     pub fn new_sym_here(&mut self) -> Sym {
         let sym = self.new_sym();
-        self.sym_resolve(sym, self.addr()).unwrap();
+        self.sym_resolve(sym, self.here()).unwrap();
         sym
     }
 
-    pub fn sym_addr(&mut self, sym: Sym) -> Result<Option<u32>, DynAsmError> {
-        Ok(match self.symbols.get(sym)? {
-            Symbol::Unresolved(_) => None,
-            Symbol::Resolved(addr) => Some(*addr),
-        })
+    /// Section of the symbol, None if it isn't placed yet. Addresses are only known when the image is finished.
+    pub fn sym_section(&mut self, sym: Sym) -> Result<Option<Section>, DynAsmError> {
+        Ok(self.symbols.get(sym)?.loc.map(|(section, _)| section))
     }
 
+    /// Give a symbol the current location as address, a symbol can only be placed once
     pub fn set_sym_here(&mut self, sym: Sym) -> Result<(), DynAsmError> {
-        self.sym_resolve(sym, self.addr())
+        self.sym_resolve(sym, self.here())
     }
 
+    #[track_caller]
+    /// Encode and emit one instruction. With virtual registers it is encoded once they are allocated.
     pub fn gen(&mut self, instruction: Instruction) -> Result<(), DynAsmError> {
+        if regalloc::has_virtual(&instruction) {
+            regalloc::with_zero_registers(&instruction).encode()?;
+            return self.push(ItemKind::Instr(instruction), LineKind::Instruction);
+        }
         let instr = instruction.encode()?;
-        self.memory.extend_from_slice(instr.as_slice());
-        Ok(())
+        self.emit(instr.as_slice(), LineKind::Instruction)
     }
 
-    pub fn gen_load(&mut self, dst: Register, imm: u32) -> Result<(), DynAsmError> {
-        let low_zero = imm & 0xFFFF == 0;
-        let high_zero = imm & 0xFFFF0000 == 0;
-
-        match (high_zero, low_zero) {
-            (false, false) => {
-                self.gen(Instruction::i_type(
-                    Opcode::ORI,
-                    dst.clone(),
-                    0.into(),
-                    imm as u16,
-                ))?;
-                self.gen(Instruction::i_type(
-                    Opcode::ORIU,
-                    dst.clone(),
-                    dst,
-                    (imm >> 16) as u16,
-                ))?;
-            }
-            (false, true) => self.gen(Instruction::i_type(
-                Opcode::ORIU,
-                dst,
-                0.into(),
-                (imm >> 16) as u16,
-            ))?,
-            (true, _) => self.gen(Instruction::i_type(Opcode::ORI, dst, 0.into(), imm as u16))?,
+    #[track_caller]
+    /// Emit a byte
+    pub fn gen_u8(&mut self, value: u8) -> Result<(), DynAsmError> {
+        self.emit(&[value], LineKind::Data)
+    }
+
+    #[track_caller]
+    /// Emit a little endian 16bit value
+    pub fn gen_u16(&mut self, value: u16) -> Result<(), DynAsmError> {
+        self.emit(&value.to_le_bytes(), LineKind::Data)
+    }
+
+    #[track_caller]
+    /// Emit a little endian 32bit value
+    pub fn gen_u32(&mut self, value: u32) -> Result<(), DynAsmError> {
+        self.emit(&value.to_le_bytes(), LineKind::Data)
+    }
+
+    #[track_caller]
+    /// Emit raw bytes
+    pub fn gen_bytes(&mut self, bytes: &[u8]) -> Result<(), DynAsmError> {
+        self.emit(bytes, LineKind::Data)
+    }
+
+    #[track_caller]
+    /// Emit len zero bytes, this is the only data allowed in a nobits section like `.bss`
+    pub fn gen_zeros(&mut self, len: usize) -> Result<(), DynAsmError> {
+        if len == 0 {
+            return Ok(());
         }
+        self.emit(&vec![0; len], LineKind::Zeros)
+    }
 
+    /// Data word with the value of an address expression, e.g. for jump tables
+    #[track_caller]
+    pub fn gen_u32_expr(&mut self, expr: SymExpr) -> Result<(), DynAsmError> {
+        let fixup = Fixup {
+            expr,
+            kind: SymRefKind::Word,
+            field: BitField::WORD,
+        };
+        self.emit_fixup(0xDEADDEADu32.to_le_bytes().to_vec(), LineKind::Data, fixup)
+    }
+
+    /// Pad with zeros up to a multiple of align, the section start is aligned to at least the same amount
+    #[track_caller]
+    pub fn gen_align(&mut self, align: u32) -> Result<(), DynAsmError> {
+        let align = align.max(1);
+        let section = &mut self.sections[self.current.0];
+        section.align = section.align.max(align);
+        self.push(ItemKind::Align(align), LineKind::Zeros)
+    }
+
+    #[track_caller]
+    /// Load a 32bit constant, with as few instructions as possible
+    pub fn gen_load(&mut self, dst: Register, imm: u32) -> Result<(), DynAsmError> {
+        self.gen_load_near(dst, imm, &[])
+    }
+
+    /// Load a 32bit constant, known lists registers that hold a known value and can be used as starting point
+    #[track_caller]
+    pub fn gen_load_near(&mut self, dst: Register, imm: u32, known: &[(Register, u32)]) -> Result<(), DynAsmError> {
+        for instr in constant::synthesize(&dst, imm, known) {
+            self.gen(instr)?;
+        }
         Ok(())
     }
 
+    #[track_caller]
+    /// Load the address of a symbol
     pub fn gen_load_symbol(&mut self, dst: Register, sym: Sym) -> Result<(), DynAsmError> {
-        self.gen(Instruction::i_type(
-            Opcode::ORI,
-            dst.clone(),
-            0.into(),
-            0xDEAD,
-        ))?;
-        self.sym_fixup(sym, SymRefKind::LowImm)?;
-        self.gen(Instruction::i_type(Opcode::ORIU, dst.clone(), dst, 0xDEAD))?;
-        self.sym_fixup(sym, SymRefKind::HighImm)
+        self.gen_load_expr(dst, sym.into())
+    }
+
+    /// Load the value of an address expression, with ORI and ORIU, or only one of them if the value allows
+    #[track_caller]
+    pub fn gen_load_expr(&mut self, dst: Register, expr: SymExpr) -> Result<(), DynAsmError> {
+        self.gen_load_item(dst, expr, false)
     }
 
+    /// Load the value of an address expression, with ORIU and a sign extending ADDI, or only one of them
+    #[track_caller]
+    pub fn gen_load_expr_addi(&mut self, dst: Register, expr: SymExpr) -> Result<(), DynAsmError> {
+        self.gen_load_item(dst, expr, true)
+    }
+
+    // In PIC mode an address is loaded as offset from the image start, the runtime base is added to it.
+    // Differences between symbols are position independent already.
+    #[track_caller]
+    fn gen_load_item(&mut self, dst: Register, expr: SymExpr, addi: bool) -> Result<(), DynAsmError> {
+        if !matches!(dst, Register::Virtual(_)) {
+            dst.bits()?;
+        }
+        for sym in expr.syms() {
+            self.symbols.get(sym)?;
+        }
+
+        let rebase = match expr.sub {
+            None => self.pic.clone(),
+            Some(_) => None,
+        };
+        let load = Load {
+            dst,
+            expr,
+            addi,
+            rebase,
+            long: false,
+        };
+        self.push(ItemKind::Load(load), LineKind::Instruction)
+    }
+
+    /// Generate I type instruction, with the immediate taken from part of an address expression
+    #[track_caller]
+    pub fn gen_i_type_expr(
+        &mut self,
+        opcode: Opcode,
+        dst: Register,
+        src: Register,
+        expr: SymExpr,
+        kind: SymRefKind,
+    ) -> Result<(), DynAsmError> {
+        let bytes = Instruction::i_type(opcode, dst, src, 0xDEAD).encode()?;
+        let fixup = Fixup {
+            expr,
+            kind,
+            field: BitField::IMM,
+        };
+        self.emit_fixup(bytes, LineKind::Instruction, fixup)
+    }
+
+    /// Emit a raw AIS instruction word, with part of an address expression patched into field.
+    /// This works for any instruction format, the rest of the word is kept as given.
+    #[track_caller]
+    pub fn gen_word_expr(
+        &mut self,
+        word: u32,
+        expr: SymExpr,
+        kind: SymRefKind,
+        field: BitField,
+    ) -> Result<(), DynAsmError> {
+        let mut bytes = vec![0x62, 0x80];
+        bytes.extend_from_slice(&word.to_le_bytes());
+        self.emit_fixup(bytes, LineKind::Instruction, Fixup { expr, kind, field })
+    }
+
+    #[track_caller]
+    /// Jump to a symbol, the address is loaded into a scratch register
     pub fn gen_jump(&mut self, sym: Sym) -> Result<(), DynAsmError> {
-        self.gen_load_symbol("R4".into(), sym)?;
-        self.gen(Instruction::xj("R4".into()))?;
+        let scratch = self.scratch()?;
+        self.gen_jump_via(sym, scratch)
+    }
+
+    /// Same as gen_jump, but use the given register to hold the target address
+    #[track_caller]
+    pub fn gen_jump_via(&mut self, sym: Sym, scratch: Register) -> Result<(), DynAsmError> {
+        let scratch = self.scratch_override(scratch)?;
+        self.gen_load_symbol(scratch.clone(), sym)?;
+        self.gen(Instruction::xj(scratch))?;
         Ok(())
     }
 
-    pub fn gen_header(&mut self) {
-        self.memory.extend_from_slice(HEADER);
+    #[track_caller]
+    /// x86 code that switches to AIS, the entry point of a payload
+    pub fn gen_header(&mut self) -> Result<(), DynAsmError> {
+        self.emit(HEADER, LineKind::Header)?;
+
+        // The header leaves the address of the first AIS instruction in EAX, derive the runtime base from that.
+        // The text section is placed at the image start, so the offset within it is the offset in the image.
+        // The offset is only known after layout.
+        if let Some(base_reg) = self.pic.clone() {
+            if self.current != Section::TEXT {
+                return Err(DynAsmError::HeaderPlacement);
+            }
+            self.push(ItemKind::PicBase(base_reg), LineKind::Instruction)?;
+        }
+
+        // Stack area of the code outside functions, it is released again by the footer
+        self.push(ItemKind::Frame(Frame::new(0, true)), LineKind::Instruction)
     }
 
-    pub fn gen_footer(&mut self) {
-        self.memory.extend_from_slice(FOOTER);
+    #[track_caller]
+    /// x86 return, after the AIS code has jumped to it the payload returns to its caller
+    pub fn gen_footer(&mut self) -> Result<(), DynAsmError> {
+        self.push(ItemKind::Frame(Frame::new(0, false)), LineKind::Instruction)?;
+        self.emit(FOOTER, LineKind::Footer)
     }
 
-    pub fn memory(&self) -> &Vec<u8> {
-        &self.memory
+    // Place the sections in order at the base address, honoring their alignment, with the current load sizes
+    fn layout(&self) -> Layout {
+        let mut addr = self.base;
+        let mut layout = Layout {
+            sections: Vec::new(),
+            offsets: Vec::new(),
+        };
+
+        for section in &self.sections {
+            addr = addr.next_multiple_of(section.align);
+            let mut offsets = Vec::with_capacity(section.items.len() + 1);
+            let mut offset = 0;
+            for item in &section.items {
+                offsets.push(offset);
+                offset += item.size(offset);
+            }
+            offsets.push(offset);
+
+            layout.sections.push(SectionInfo {
+                name: section.name.clone(),
+                addr,
+                size: offset,
+                align: section.align,
+            });
+            layout.offsets.push(offsets);
+            addr += offset;
+        }
+
+        layout
     }
 
-    pub fn dump(&self) {
-        let mut bytes = &self.memory[HEADER.len()..self.memory.len() - FOOTER.len()];
+    // Absolute loads that go in the relocation table need both halves, whatever their value. All others start short.
+    fn init_loads(&mut self) {
+        let keep_long = self.pic.is_none() && self.relocatable;
+        for item in self.sections.iter_mut().flat_map(|x| x.items.iter_mut()) {
+            if let ItemKind::Load(load) = &mut item.kind {
+                load.long = keep_long && load.expr.sub.is_none();
+            }
+        }
+    }
+
+    // Lay out until every load fits its value. Loads start short and only grow, so this ends.
+    fn relax(&mut self) -> Layout {
         loop {
-            if bytes.is_empty() {
-                break;
+            let layout = self.layout();
+
+            let mut grow = Vec::new();
+            for (s, section) in self.sections.iter().enumerate() {
+                for (i, item) in section.items.iter().enumerate() {
+                    if let ItemKind::Load(load) = &item.kind {
+                        let value = self.expr_value(&layout, load.expr);
+                        if !load.long && value.is_some_and(|x| load.needs_long(x)) {
+                            grow.push((s, i));
+                        }
+                    }
+                }
             }
 
-            match Instruction::decode(bytes) {
-                Ok((i, size)) => {
-                    println!("{:?}", i);
-                    bytes = &bytes[size..];
+            if grow.is_empty() {
+                return layout;
+            }
+            for (s, i) in grow {
+                if let ItemKind::Load(load) = &mut self.sections[s].items[i].kind {
+                    load.long = true;
                 }
-                Err(e) => {
-                    println!("{:?}", e);
-                    break;
+            }
+        }
+    }
+
+    // Absolute addresses change with the load address, they go in the relocation table.
+    // Symbol differences never change, and in PIC mode loads are relative to the runtime base already.
+    fn is_absolute(&self, expr: SymExpr, kind: RelocKind) -> bool {
+        match (expr.sub, &self.pic) {
+            (Some(_), _) => false,
+            (None, Some(_)) => kind == RelocKind::Word,
+            (None, None) => self.relocatable,
+        }
+    }
+
+    fn sym_value(&self, layout: &Layout, sym: Sym) -> Option<u32> {
+        let (section, index) = self.symbols.entries[sym.index].loc?;
+        Some(layout.sections[section.0].addr + layout.offsets[section.0][index])
+    }
+
+    // Value of the expression, or None if it contains unresolved symbols
+    fn expr_value(&self, layout: &Layout, expr: SymExpr) -> Option<u32> {
+        let addr = self.sym_value(layout, expr.sym)?;
+        let sub = match expr.sub {
+            Some(sub) => self.sym_value(layout, sub)?,
+            None => 0,
+        };
+        Some(addr.wrapping_sub(sub).wrapping_add(expr.addend as u32))
+    }
+
+    // Named symbols sorted by address. The size of a symbol runs up to the next symbol, or the end of its section.
+    fn symbol_table(&self, layout: &Layout) -> Vec<SymbolInfo> {
+        let mut table: Vec<(usize, SymbolInfo)> = self
+            .symbols
+            .entries
+            .iter()
+            .filter_map(|x| match (&x.name, x.loc) {
+                (Some(name), Some((section, index))) => Some((
+                    section.0,
+                    SymbolInfo {
+                        name: name.clone(),
+                        section: layout.sections[section.0].name.clone(),
+                        addr: layout.sections[section.0].addr + layout.offsets[section.0][index],
+                        size: 0,
+                    },
+                )),
+                _ => None,
+            })
+            .collect();
+        table.sort_by_key(|(_, x)| x.addr);
+
+        for i in 0..table.len() {
+            let (section, ref info) = table[i];
+            let end = layout.sections[section].addr + layout.sections[section].size;
+            let next = table[i + 1..]
+                .iter()
+                .filter(|(x, _)| *x == section)
+                .map(|(_, x)| x.addr)
+                .find(|x| *x > info.addr)
+                .unwrap_or(end);
+            table[i].1.size = next - info.addr;
+        }
+
+        table.into_iter().map(|(_, x)| x).collect()
+    }
+
+    /// Check that every symbol got an address, lay out the image, patch all references and hand out the final payload
+    pub fn finish(mut self) -> Result<Image, DynAsmError> {
+        self.allocate()?;
+        self.init_loads();
+        let layout = self.relax();
+
+        // Collect the references per unresolved symbol
+        let mut refs: BTreeMap<usize, Vec<u32>> = BTreeMap::new();
+        for (s, section) in self.sections.iter().enumerate() {
+            for (i, item) in section.items.iter().enumerate() {
+                for sym in item.expr().iter().flat_map(|x| x.syms()) {
+                    if self.symbols.entries[sym.index].loc.is_none() {
+                        let offset = layout.sections[s].addr - self.base + layout.offsets[s][i];
+                        refs.entry(sym.index).or_default().push(offset);
+                    }
+                }
+            }
+        }
+
+        let unresolved: Vec<UnresolvedSym> = refs
+            .into_iter()
+            .map(|(index, refs)| UnresolvedSym {
+                name: self.symbols.entries[index].display_name(index),
+                refs,
+            })
+            .collect();
+
+        if !unresolved.is_empty() {
+            return Err(DynAsmError::Unresolved(unresolved));
+        }
+
+        // Encode every item at its place, and record every absolute address that is written for the relocation table
+        let mut memory = Vec::new();
+        let mut lines = Vec::new();
+        let mut relocs = Vec::new();
+        for (s, section) in self.sections.iter().enumerate() {
+            let info = &layout.sections[s];
+            memory.resize((info.addr - self.base) as usize, 0);
+
+            for (i, item) in section.items.iter().enumerate() {
+                let mut comment = item.comment.clone();
+                let mut line = |addr: u32, len: usize, kind| Line {
+                    addr,
+                    len: len as u32,
+                    kind,
+                    comment: comment.take(),
+                    source: item.source,
+                };
+                let addr = info.addr + layout.offsets[s][i];
+                let start = memory.len();
+
+                // Relocation for an absolute expression, the target section is the one of its symbol
+                let reloc = |offset: usize, expr: SymExpr, kind, field, value| {
+                    let (target, _) = self.symbols.entries[expr.sym.index].loc.unwrap();
+                    self.is_absolute(expr, kind).then_some(RelocEntry {
+                        offset: offset as u32,
+                        value,
+                        kind,
+                        section: target.0 as u8,
+                        field,
+                    })
+                };
+
+                match &item.kind {
+                    ItemKind::Bytes(bytes, fixup) => {
+                        memory.extend_from_slice(bytes);
+                        if let Some(fixup) = fixup {
+                            let value = self.expr_value(&layout, fixup.expr).unwrap();
+                            let kind = fixup.kind.reloc_kind();
+                            relocs.extend(reloc(start, fixup.expr, kind, fixup.field, value));
+                            reloc::patch(&mut memory, start, kind, fixup.field, value).unwrap();
+                        }
+                        lines.push(line(addr, bytes.len(), item.line));
+                    }
+                    ItemKind::Align(_) => {
+                        let len = (layout.offsets[s][i + 1] - layout.offsets[s][i]) as usize;
+                        if len > 0 {
+                            memory.resize(start + len, 0);
+                            lines.push(line(addr, len, LineKind::Zeros));
+                        }
+                    }
+                    ItemKind::Load(load) => {
+                        let value = self.expr_value(&layout, load.expr).unwrap();
+                        for (instr, kind) in load.instructions(value) {
+                            let offset = memory.len();
+                            if let Some(kind) = kind {
+                                relocs.extend(reloc(offset, load.expr, kind, BitField::IMM, value));
+                            }
+                            memory.extend_from_slice(&instr.encode()?);
+                            lines.push(line(addr + (offset - start) as u32, 6, LineKind::Instruction));
+                        }
+                    }
+                    ItemKind::Instr(instr) => {
+                        memory.extend_from_slice(&instr.encode()?);
+                        lines.push(line(addr, 6, LineKind::Instruction));
+                    }
+                    ItemKind::Frame(frame) => {
+                        for (x, instr) in frame.instructions().iter().enumerate() {
+                            memory.extend_from_slice(&instr.encode()?);
+                            lines.push(line(addr + 6 * x as u32, 6, LineKind::Instruction));
+                        }
+                    }
+                    ItemKind::PicBase(base_reg) => {
+                        let offset = layout.offsets[s][i];
+                        if offset > 0x8000 {
+                            return Err(DynAsmError::HeaderPlacement);
+                        }
+                        let imm = (offset as u16).wrapping_neg();
+                        let instr = Instruction::i_type(Opcode::ADDI, base_reg.clone(), "EAX".into(), imm);
+                        memory.extend_from_slice(&instr.encode()?);
+                        lines.push(line(addr, 6, LineKind::Instruction));
+                    }
                 }
             }
         }
+        relocs.sort_by_key(|x| x.offset);
+
+        // Nobits sections at the end only take memory at runtime, they are left out of the payload
+        let end = self
+            .sections
+            .iter()
+            .zip(&layout.sections)
+            .filter(|(section, _)| !section.nobits)
+            .map(|(_, info)| info.addr + info.size)
+            .max()
+            .unwrap_or(self.base);
+        memory.truncate((end - self.base) as usize);
+
+        Ok(Image {
+            base: self.base,
+            lines,
+            symbols: self.symbol_table(&layout),
+            sections: layout.sections,
+            relocs,
+            memory,
+        })
+    }
+}
+
+/// Final payload, all symbols are resolved
+pub struct Image {
+    base: u32,
+    memory: Vec<u8>,
+    symbols: Vec<SymbolInfo>,
+    sections: Vec<SectionInfo>,
+    relocs: Vec<RelocEntry>,
+    lines: Vec<Line>,
+}
+
+impl Image {
+    /// Load address of the image
+    pub fn base(&self) -> u32 {
+        self.base
+    }
+
+    /// Payload, all sections placed after each other. Nobits sections at the end, like `.bss`, aren't included.
+    pub fn bytes(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// Memory the payload needs when it runs, the zeroed nobits sections follow the bytes
+    pub fn size(&self) -> u32 {
+        self.sections.iter().map(|x| x.addr + x.size).max().unwrap_or(self.base) - self.base
+    }
+
+    /// Named symbols, sorted by address
+    pub fn symbol_table(&self) -> &[SymbolInfo] {
+        &self.symbols
+    }
+
+    /// Placement of every section
+    pub fn sections(&self) -> &[SectionInfo] {
+        &self.sections
+    }
+
+    /// Everything that was emitted, in address order
+    pub fn lines(&self) -> &[Line] {
+        &self.lines
+    }
+
+    /// Line that covers an absolute address, e.g. to find the generator code of a faulting EIP
+    pub fn line_at(&self, addr: u32) -> Option<&Line> {
+        self.lines
+            .iter()
+            .find(|x| x.len > 0 && x.addr <= addr && addr < x.addr + x.len)
+    }
+
+    /// Source location that generated the byte at addr
+    pub fn source_at(&self, addr: u32) -> Option<SourceLoc> {
+        self.line_at(addr).map(|x| x.source)
+    }
+
+    /// Bytes at an absolute address, empty for the part in nobits sections at the end
+    pub fn bytes_at(&self, addr: u32, len: u32) -> &[u8] {
+        let start = ((addr - self.base) as usize).min(self.memory.len());
+        &self.memory[start..(start + len as usize).min(self.memory.len())]
+    }
+
+    /// Every absolute address in the payload, the target section is an index into sections()
+    pub fn relocs(&self) -> &[RelocEntry] {
+        &self.relocs
+    }
+
+    /// Relocation table in the format of the reloc module, so the payload can be rebased at load time
+    pub fn reloc_table(&self) -> Vec<u8> {
+        let mut table = Vec::with_capacity(reloc::HEADER_SIZE + self.relocs.len() * reloc::ENTRY_SIZE);
+        table.extend_from_slice(&reloc::MAGIC);
+        table.extend_from_slice(&self.base.to_le_bytes());
+        table.extend_from_slice(&(self.relocs.len() as u32).to_le_bytes());
+        for entry in &self.relocs {
+            table.extend_from_slice(&reloc::encode_entry(entry));
+        }
+        table
+    }
+
+    /// Write the relocation table, see reloc_table()
+    #[cfg(feature = "std")]
+    pub fn write_relocs<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        w.write_all(&self.reloc_table())
+    }
+
+    /// Write map file with the section layout, and one line per named symbol: address, size, section and name
+    #[cfg(feature = "std")]
+    pub fn write_map<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        writeln!(w, "{:<10} {:<10} Section", "Address", "Size")?;
+        for info in &self.sections {
+            writeln!(w, "0x{:08X} 0x{:08X} {}", info.addr, info.size, info.name)?;
+        }
+        writeln!(w)?;
+
+        writeln!(w, "{:<10} {:<10} {:<10} Name", "Address", "Size", "Section")?;
+        for info in &self.symbols {
+            writeln!(
+                w,
+                "0x{:08X} 0x{:08X} {:<10} {}",
+                info.addr, info.size, info.section, info.name
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Show the generated AIS instructions, with the source location that generated them
+    #[cfg(feature = "std")]
+    pub fn dump(&self) {
+        for line in self.lines.iter().filter(|x| x.kind == LineKind::Instruction) {
+            for info in self.symbols.iter().filter(|x| x.addr == line.addr) {
+                println!("{}:", info.name);
+            }
+
+            let comment = line.comment.as_deref().map(|x| format!(" ; {}", x)).unwrap_or_default();
+            match Instruction::decode(self.bytes_at(line.addr, line.len)) {
+                Ok((i, _)) => println!("{:?} // {}{}", i, line.source, comment),
+                Err(e) => println!("{:?} // {}{}", e, line.source, comment),
+            }
+        }
     }
 }
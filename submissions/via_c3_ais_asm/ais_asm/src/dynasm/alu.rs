@@ -0,0 +1,145 @@
+//! One builder method per XALU operation, e.g. `asm.or(EAX, EAX, EDX)` instead of spelling out
+//! [`Instruction::xalur`]. With the register constants from [`crate::ais`]:
+//!
+//! ```
+//! use ais_asm::ais::{EAX, EDX};
+//! use ais_asm::{DpCntl, DynAsm};
+//!
+//! let mut asm = DynAsm::new(0);
+//! asm.or(EAX, EAX, EDX).unwrap();
+//! asm.shl_imm(EAX, EAX, 4).unwrap();
+//! asm.add_w16(EAX, EAX, EDX).unwrap();
+//! asm.add_dp(DpCntl::HL, EAX, EAX, EDX).unwrap();
+//! asm.inc(EDX, EDX).unwrap();
+//! ```
+//!
+//! The plain methods work on 32 bits, `_w16` on the low 16 bits and `_dp` takes the [`DpCntl`], for the LL, HL, LH
+//! and HH halves. `_imm` takes a constant in -16..=15 in place of b. INC and DEC only have one source, so their
+//! methods don't take a second one.
+//!
+//! IMUL, MUL, IDIV, CTC2, MFLOU and MFLOI have no methods. They don't fit `dst = a op b`: the multiply and divide
+//! results go to registers outside the AIS register file that MFLOU and MFLOI read back, and which operands each one
+//! takes isn't documented. [`DynAsm::gen_xalu`] still encodes them with whatever operands are given.
+//!
+//! The `_imm` methods and [`DynAsm::setcc`] are experimental. They rest on the layout that [`Instruction::xaluir`] and
+//! [`Instruction::setcc`] assume for the constant and the condition, which hasn't been checked on hardware.
+
+use super::{DynAsm, DynAsmError};
+use crate::ais::{Cond, Const, DpCntl, Instruction, Register, SubOpXalu};
+
+// dst = a op b, in register, 16bit, any width and constant form
+macro_rules! binary {
+    ($($op:ident: $word:ident, $w16:ident, $dp:ident, $imm:ident;)*) => {
+        impl DynAsm {
+            $(
+                #[doc = concat!("`dst = a ", stringify!($op), " b`")]
+                #[track_caller]
+                pub fn $word(&mut self, dst: Register, a: Register, b: Register) -> Result<(), DynAsmError> {
+                    self.gen_xalu(SubOpXalu::$op, DpCntl::Word, dst, a, b)
+                }
+
+                #[doc = concat!("`dst = a ", stringify!($op), " b` on the low 16 bits")]
+                #[track_caller]
+                pub fn $w16(&mut self, dst: Register, a: Register, b: Register) -> Result<(), DynAsmError> {
+                    self.gen_xalu(SubOpXalu::$op, DpCntl::Short, dst, a, b)
+                }
+
+                #[doc = concat!("`dst = a ", stringify!($op), " b`, with the width and halves from dpcntl")]
+                #[track_caller]
+                pub fn $dp(
+                    &mut self,
+                    dpcntl: DpCntl,
+                    dst: Register,
+                    a: Register,
+                    b: Register,
+                ) -> Result<(), DynAsmError> {
+                    self.gen_xalu(SubOpXalu::$op, dpcntl, dst, a, b)
+                }
+
+                #[doc = concat!("`dst = a ", stringify!($op), " c`, with a constant in -16..=15. Experimental, see")]
+                #[doc = "[`Instruction::xaluir`]."]
+                #[track_caller]
+                pub fn $imm(&mut self, dst: Register, a: Register, c: i8) -> Result<(), DynAsmError> {
+                    self.gen(Instruction::xaluir(SubOpXalu::$op, DpCntl::Word, dst, a, Const::Number(c)))
+                }
+            )*
+        }
+    };
+}
+
+// dst = op src, the unused operand is R0
+macro_rules! unary {
+    ($($op:ident: $word:ident, $w16:ident, $dp:ident;)*) => {
+        impl DynAsm {
+            $(
+                #[doc = concat!("`dst = ", stringify!($op), " src`")]
+                #[track_caller]
+                pub fn $word(&mut self, dst: Register, src: Register) -> Result<(), DynAsmError> {
+                    self.gen_xalu(SubOpXalu::$op, DpCntl::Word, dst, src, 0.into())
+                }
+
+                #[doc = concat!("`dst = ", stringify!($op), " src` on the low 16 bits")]
+                #[track_caller]
+                pub fn $w16(&mut self, dst: Register, src: Register) -> Result<(), DynAsmError> {
+                    self.gen_xalu(SubOpXalu::$op, DpCntl::Short, dst, src, 0.into())
+                }
+
+                #[doc = concat!("`dst = ", stringify!($op), " src`, with the width and halves from dpcntl")]
+                #[track_caller]
+                pub fn $dp(&mut self, dpcntl: DpCntl, dst: Register, src: Register) -> Result<(), DynAsmError> {
+                    self.gen_xalu(SubOpXalu::$op, dpcntl, dst, src, 0.into())
+                }
+            )*
+        }
+    };
+}
+
+binary! {
+    SHL: shl, shl_w16, shl_dp, shl_imm;
+    SHR: shr, shr_w16, shr_dp, shr_imm;
+    SAR: sar, sar_w16, sar_dp, sar_imm;
+    ROL: rol, rol_w16, rol_dp, rol_imm;
+    ROR: ror, ror_w16, ror_dp, ror_imm;
+    RCL: rcl, rcl_w16, rcl_dp, rcl_imm;
+    RCR: rcr, rcr_w16, rcr_dp, rcr_imm;
+    ADD: add, add_w16, add_dp, add_imm;
+    ADC: adc, adc_w16, adc_dp, adc_imm;
+    SUB: sub, sub_w16, sub_dp, sub_imm;
+    SBB: sbb, sbb_w16, sbb_dp, sbb_imm;
+    AND: and, and_w16, and_dp, and_imm;
+    OR: or, or_w16, or_dp, or_imm;
+    XOR: xor, xor_w16, xor_dp, xor_imm;
+    NOR: nor, nor_w16, nor_dp, nor_imm;
+}
+
+unary! {
+    INC: inc, inc_w16, inc_dp;
+    DEC: dec, dec_w16, dec_dp;
+}
+
+impl DynAsm {
+    /// `dst = a op b`, with the width and halves from dpcntl
+    #[track_caller]
+    pub fn gen_xalu(
+        &mut self,
+        op: SubOpXalu,
+        dpcntl: DpCntl,
+        dst: Register,
+        a: Register,
+        b: Register,
+    ) -> Result<(), DynAsmError> {
+        self.gen(Instruction::xalur(op, dpcntl, dst, a, b))
+    }
+
+    /// Compare a with b like the x86 `cmp`, for a following [`DynAsm::setcc`]
+    #[track_caller]
+    pub fn cmps(&mut self, a: Register, b: Register) -> Result<(), DynAsmError> {
+        self.gen(Instruction::cmps(a, b))
+    }
+
+    /// `dst = 1` when cond holds for the last compare, else 0. Experimental, see [`Instruction::setcc`].
+    #[track_caller]
+    pub fn setcc(&mut self, cond: Cond, dst: Register) -> Result<(), DynAsmError> {
+        self.gen(Instruction::setcc(cond, dst))
+    }
+}
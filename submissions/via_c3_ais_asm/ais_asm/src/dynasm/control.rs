@@ -0,0 +1,184 @@
+//! Conditional control flow for AIS, which only has the unconditional XJ.
+//!
+//! CMPS compares two registers like the x86 `cmp`, and SETCC turns one of the x86 conditions into 0 or 1. A
+//! conditional branch makes a mask out of that, and uses it to pick the target or the next instruction as the
+//! address for XJ:
+//!
+//! ```text
+//!   XALUR.CMPS R0, a, b
+//!   XALUR.SETCC t, R0, cond     t = 1 when cond holds, else 0
+//!   XALUR.SUB t, R0, t          all ones or zero
+//!   load d, target - next       the distance doesn't depend on the load address
+//!   XALUR.AND d, d, t
+//!   load u, next
+//!   XALUR.ADD u, u, d
+//!   XJ u
+//! next:
+//! ```
+//!
+//! The temporaries are virtual registers, so the helpers only clobber the flags. The register allocator gives them
+//! registers that don't hold a live value, see [`DynAsm::allocate`].
+//!
+//! [`Structured`] builds if/else, loops and switches on top of that, and places their labels itself.
+//!
+//! # Experimental
+//!
+//! Everything in here depends on SETCC, and its operands aren't documented. The encoding puts the condition number
+//! in the rt field, see [`Instruction::setcc`], which hasn't been checked on hardware. The tests run the code on a
+//! model that makes the same assumption, so they only show that the branches are consistent with it.
+
+use super::{DynAsm, DynAsmError, FunctionBuilder, Section, Sym};
+use crate::ais::{Cond, DpCntl, Instruction, Opcode, Register, Size, SubOpXalu};
+
+use alloc::vec::Vec;
+
+fn alu(op: SubOpXalu, dst: &Register, a: Register, b: &Register) -> Instruction {
+    Instruction::xalur(op, DpCntl::Word, dst.clone(), a, b.clone())
+}
+
+impl DynAsm {
+    /// New virtual register that is 1 when cond holds for a compared with b, else 0. Clobbers the flags.
+    /// Experimental, the SETCC layout is unverified, see the module documentation.
+    #[track_caller]
+    pub fn gen_set_if(&mut self, cond: Cond, a: Register, b: Register) -> Result<Register, DynAsmError> {
+        let result = self.new_vreg();
+        self.gen(Instruction::cmps(a, b))?;
+        self.gen(Instruction::setcc(cond, result.clone()))?;
+        Ok(result)
+    }
+
+    /// Jump to target when cond holds for a compared with b, e.g. `Cond::L` jumps when a < b as signed numbers.
+    /// Clobbers the flags, see the module documentation. Experimental, like [`DynAsm::gen_set_if`].
+    #[track_caller]
+    pub fn gen_branch_if(&mut self, cond: Cond, a: Register, b: Register, target: Sym) -> Result<(), DynAsmError> {
+        let mask = self.gen_set_if(cond, a, b)?;
+        self.gen(alu(SubOpXalu::SUB, &mask, 0.into(), &mask))?;
+
+        let next = self.new_sym();
+        let (distance, address) = (self.new_vreg(), self.new_vreg());
+        self.gen_load_expr(distance.clone(), target - next)?;
+        self.gen(alu(SubOpXalu::AND, &distance, distance.clone(), &mask))?;
+        self.gen_load_symbol(address.clone(), next)?;
+        self.gen(alu(SubOpXalu::ADD, &address, address.clone(), &distance))?;
+        self.gen(Instruction::xj(address))?;
+
+        self.branches.push((next, target));
+        self.set_sym_here(next)
+    }
+}
+
+/// Structured control flow for [`DynAsm`], and for function bodies so they can [`FunctionBuilder::ret`] from inside
+/// a block. Blocks are closures that emit their code to the same builder. The conditions are the ones of
+/// [`DynAsm::gen_branch_if`], only the flags and the scratch register of [`DynAsm::gen_jump`] are clobbered.
+/// Experimental, like the branches it is built on.
+pub trait Structured: Sized {
+    /// Assembler that the code goes to
+    fn asm(&mut self) -> &mut DynAsm;
+
+    /// Run then when cond holds for a compared with b, otherwise run otherwise
+    #[track_caller]
+    fn if_else<T, E>(&mut self, cond: Cond, a: Register, b: Register, then: T, otherwise: E) -> Result<(), DynAsmError>
+    where
+        T: FnOnce(&mut Self) -> Result<(), DynAsmError>,
+        E: FnOnce(&mut Self) -> Result<(), DynAsmError>,
+    {
+        let (other, end) = (self.asm().new_sym(), self.asm().new_sym());
+        self.asm().gen_branch_if(!cond, a, b, other)?;
+        then(self)?;
+        if self.asm().falls_through() {
+            self.asm().gen_jump(end)?;
+        }
+        self.asm().set_sym_here(other)?;
+        otherwise(self)?;
+        self.asm().set_sym_here(end)
+    }
+
+    /// Run body as long as cond holds for a compared with b, the registers are compared again before every round.
+    /// The test is at the bottom, so a round costs one branch.
+    #[track_caller]
+    fn while_loop<B>(&mut self, cond: Cond, a: Register, b: Register, body: B) -> Result<(), DynAsmError>
+    where
+        B: FnOnce(&mut Self) -> Result<(), DynAsmError>,
+    {
+        let (top, test) = (self.asm().new_sym(), self.asm().new_sym());
+        self.asm().gen_jump(test)?;
+        self.asm().set_sym_here(top)?;
+        body(self)?;
+        self.asm().set_sym_here(test)?;
+        self.asm().gen_branch_if(cond, a, b, top)
+    }
+
+    /// Run body with a new virtual register counting from start up to, but not including, end. The comparison is
+    /// unsigned, and the body must not write the counter.
+    #[track_caller]
+    fn for_loop<B>(&mut self, start: Register, end: Register, body: B) -> Result<(), DynAsmError>
+    where
+        B: FnOnce(&mut Self, Register) -> Result<(), DynAsmError>,
+    {
+        let index = self.asm().new_vreg();
+        self.asm().gen(Instruction::i_type(Opcode::ORI, index.clone(), start, 0))?;
+        let counter = index.clone();
+        self.while_loop(Cond::B, index.clone(), end, |s| {
+            body(s, counter.clone())?;
+            s.asm().gen(Instruction::i_type(Opcode::ADDI, counter.clone(), counter, 1))
+        })
+    }
+
+    /// Jump through a table to case `Some(index)` when index < cases, else to `None`. body emits the code of each
+    /// case, it is called once per case in order and then for `None`. Cases don't fall through into the next one.
+    /// The table in `.rodata` holds offsets from its own start, so it is position independent and needs no
+    /// relocations.
+    #[track_caller]
+    fn switch<B>(&mut self, index: Register, cases: usize, mut body: B) -> Result<(), DynAsmError>
+    where
+        B: FnMut(&mut Self, Option<usize>) -> Result<(), DynAsmError>,
+    {
+        let asm = self.asm();
+        let labels: Vec<Sym> = (0..cases).map(|_| asm.new_sym()).collect();
+        let (table, default, end) = (asm.new_sym(), asm.new_sym(), asm.new_sym());
+
+        let section = asm.section();
+        asm.set_section(Section::RODATA)?;
+        asm.gen_align(4)?;
+        asm.set_sym_here(table)?;
+        for label in &labels {
+            asm.gen_u32_expr(*label - table)?;
+        }
+        asm.set_section(section)?;
+
+        let count = asm.new_vreg();
+        asm.gen_load(count.clone(), cases as u32)?;
+        asm.gen_branch_if(Cond::AE, index.clone(), count, default)?;
+        let (offset, base, target) = (asm.new_vreg(), asm.new_vreg(), asm.new_vreg());
+        asm.gen_load(offset.clone(), 2)?;
+        asm.gen(alu(SubOpXalu::SHL, &offset, index, &offset))?;
+        asm.gen_load_symbol(base.clone(), table)?;
+        asm.gen(alu(SubOpXalu::ADD, &offset, offset.clone(), &base))?;
+        asm.gen(Instruction::xl(Size::Bits32, offset, target.clone()))?;
+        asm.gen(alu(SubOpXalu::ADD, &target, target.clone(), &base))?;
+        asm.gen(Instruction::xj(target))?;
+
+        for (case, label) in labels.into_iter().enumerate() {
+            self.asm().set_sym_here(label)?;
+            body(self, Some(case))?;
+            if self.asm().falls_through() {
+                self.asm().gen_jump(end)?;
+            }
+        }
+        self.asm().set_sym_here(default)?;
+        body(self, None)?;
+        self.asm().set_sym_here(end)
+    }
+}
+
+impl Structured for DynAsm {
+    fn asm(&mut self) -> &mut DynAsm {
+        self
+    }
+}
+
+impl Structured for FunctionBuilder<'_> {
+    fn asm(&mut self) -> &mut DynAsm {
+        self
+    }
+}
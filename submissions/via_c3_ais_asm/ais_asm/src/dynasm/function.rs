@@ -0,0 +1,190 @@
+//! Functions with a stack based calling convention.
+//!
+//! [`DynAsm::function`] generates a subroutine that any code can call with [`DynAsm::gen_call`], including the
+//! function itself. The convention, for AIS code that runs on the stack of its x86 caller:
+//! - ESP is the stack pointer, the stack grows down in 4 byte slots.
+//! - A call stores the return address in the slot below ESP and jumps to the function. The function returns with
+//!   ESP back where it was before the call.
+//! - Up to four arguments go in R1, R2, R3 and R6, the result comes back in EAX. R4 is the default scratch register
+//!   and R5 is a common PIC base, so they are left out.
+//! - EBX, ESP, EBP, ESI and EDI survive a call, like on x86. Every other register may be clobbered.
+//!
+//! The frame of a function holds, from high to low addresses: the return address, the callee saved registers that
+//! the function writes or that the register allocator gave to values live across a call, and the slots of
+//! spilled values. So values that live across calls should be virtual registers, see [`DynAsm::new_vreg`].
+
+use super::{DynAsm, DynAsmError, Frame, ItemKind, LineKind, Sym};
+use crate::ais::{Instruction, Opcode, Register, Size};
+
+use alloc::vec::Vec;
+use core::ops::{Deref, DerefMut};
+
+// Argument registers in order
+pub(super) const ARGS: [u32; 4] = [1, 2, 3, 6];
+
+fn mov(dst: Register, src: Register) -> Instruction {
+    Instruction::i_type(Opcode::ORI, dst, src, 0)
+}
+
+fn esp() -> Register {
+    "ESP".into()
+}
+
+/// Body of a function that is being generated, see [`DynAsm::function`]. Code is emitted through the DynAsm it
+/// dereferences to.
+pub struct FunctionBuilder<'a> {
+    asm: &'a mut DynAsm,
+    sym: Sym,
+    exit: Sym,
+}
+
+impl Deref for FunctionBuilder<'_> {
+    type Target = DynAsm;
+    fn deref(&self) -> &DynAsm {
+        self.asm
+    }
+}
+
+impl DerefMut for FunctionBuilder<'_> {
+    fn deref_mut(&mut self) -> &mut DynAsm {
+        self.asm
+    }
+}
+
+impl FunctionBuilder<'_> {
+    /// Entry of the function, for recursive calls
+    pub fn sym(&self) -> Sym {
+        self.sym
+    }
+
+    /// Copy an argument into a new virtual register. Take the arguments before any other code, it may clobber them.
+    #[track_caller]
+    pub fn arg(&mut self, index: usize) -> Result<Register, DynAsmError> {
+        let Some(reg) = ARGS.get(index) else {
+            return Err(DynAsmError::TooManyArguments(index + 1));
+        };
+        let value = self.asm.new_vreg();
+        self.asm.gen(mov(value.clone(), Register::Index(*reg as u8)))?;
+        Ok(value)
+    }
+
+    /// Return from the function, with value as result
+    #[track_caller]
+    pub fn ret(&mut self, value: Option<Register>) -> Result<(), DynAsmError> {
+        if let Some(value) = value {
+            self.asm.gen(mov("EAX".into(), value))?;
+        }
+        let exit = self.exit;
+        self.asm.gen_jump(exit)
+    }
+}
+
+impl DynAsm {
+    // Whether execution can continue past the last item of the current section
+    pub(super) fn falls_through(&self) -> bool {
+        let Some(item) = self.sections[self.current.0].items.last() else {
+            return true;
+        };
+        let decoded;
+        let instr = match &item.kind {
+            ItemKind::Instr(instr) => Some(instr),
+            ItemKind::Bytes(bytes, _) if item.line == LineKind::Instruction => {
+                decoded = Instruction::decode(bytes).ok().map(|x| x.0);
+                decoded.as_ref()
+            }
+            _ => None,
+        };
+        item.line != LineKind::Footer && instr.is_none_or(|x| x.opcode != Opcode::XJ)
+    }
+
+    /// Generate a function named name, body emits its code. The function returns when body calls
+    /// [`FunctionBuilder::ret`] or when its code ends. It is placed in the current section, with a jump around it
+    /// when the code before could run into it. A symbol with the name that isn't placed yet becomes the entry, so
+    /// functions can call each other before they are generated. See the module documentation for the convention.
+    #[track_caller]
+    pub fn function<F>(&mut self, name: &str, body: F) -> Result<Sym, DynAsmError>
+    where
+        F: FnOnce(&mut FunctionBuilder) -> Result<(), DynAsmError>,
+    {
+        let sym = match self.sym_by_name(name) {
+            Some(sym) => sym,
+            None => self.new_named_sym(name)?,
+        };
+        if self.symbols.get(sym)?.loc.is_some() {
+            return Err(DynAsmError::SymbolRedefined(name.into()));
+        }
+        let scratch = self.scratch()?;
+
+        let skip = match self.falls_through() {
+            true => {
+                let skip = self.new_sym();
+                self.gen_jump(skip)?;
+                Some(skip)
+            }
+            false => None,
+        };
+
+        let end = self.new_sym();
+        self.functions.push((sym, end));
+        let region = self.functions.len();
+        self.set_sym_here(sym)?;
+        self.push(ItemKind::Frame(Frame::new(region, true)), LineKind::Instruction)?;
+
+        let exit = self.new_sym();
+        body(&mut FunctionBuilder { asm: self, sym, exit })?;
+
+        // Release the frame, then pop the return address and go back
+        self.set_sym_here(exit)?;
+        self.push(ItemKind::Frame(Frame::new(region, false)), LineKind::Instruction)?;
+        self.gen(Instruction::xl(Size::Bits32, esp(), scratch.clone()))?;
+        self.gen(Instruction::i_type(Opcode::ADDI, esp(), esp(), 4))?;
+        self.gen(Instruction::xj(scratch))?;
+        self.set_sym_here(end)?;
+
+        if let Some(skip) = skip {
+            self.set_sym_here(skip)?;
+        }
+        Ok(sym)
+    }
+
+    /// Call a function with up to four arguments, see the module documentation for the convention. The result is
+    /// copied into a new virtual register.
+    #[track_caller]
+    pub fn gen_call(&mut self, function: Sym, args: &[Register]) -> Result<Register, DynAsmError> {
+        if args.len() > ARGS.len() {
+            return Err(DynAsmError::TooManyArguments(args.len()));
+        }
+
+        // An argument in the register of an earlier one would be overwritten before it is read, copy it first
+        let mut values = Vec::with_capacity(args.len());
+        for (i, arg) in args.iter().enumerate() {
+            let value = match arg.bits() {
+                Ok(bits) if ARGS[..i].contains(&bits) => {
+                    let value = self.new_vreg();
+                    self.gen(mov(value.clone(), arg.clone()))?;
+                    value
+                }
+                _ => arg.clone(),
+            };
+            values.push(value);
+        }
+        for (reg, value) in ARGS.iter().zip(values) {
+            if value.bits().ok() != Some(*reg) {
+                self.gen(mov(Register::Index(*reg as u8), value))?;
+            }
+        }
+
+        let scratch = self.scratch()?;
+        let ret = self.new_sym();
+        self.calls.push(ret);
+        self.gen_load_symbol(scratch.clone(), ret)?;
+        self.gen(Instruction::i_type(Opcode::ADDI, esp(), esp(), 4u16.wrapping_neg()))?;
+        self.gen(Instruction::xs(Size::Bits32, esp(), scratch.clone()))?;
+        self.gen_jump_via(function, scratch)?;
+        self.set_sym_here(ret)?;
+
+        let result = self.new_vreg();
+        self.gen(mov(result.clone(), "EAX".into()))?;
+        Ok(result)
+    }
+}
@@ -0,0 +1,460 @@
+//! Peephole optimiser over the pending items of a DynAsm.
+//!
+//! Register values are tracked through the code, as constants or as the address a load produced. An XJ to an
+//! address that isn't known can go to any symbol, so the state at a symbol is what those XJ instructions, the XJ
+//! instructions to that symbol and the code that falls through agree on. With that:
+//! - loads of a value that the register already holds are removed
+//! - runs of I type instructions that compute a constant are replaced by the shortest sequence for it
+//! - an XJ to the symbol right after it is removed
+//! - I type instructions and loads that write a register nobody reads before the next write are removed
+//!
+//! Data, x86 code and instructions that aren't understood are barriers, every register may be read or written there.
+//! Code with virtual registers is a barrier as well, call [`DynAsm::allocate`] first to optimise it.
+//! Jumps are assumed to go to symbols, or to addresses loaded as symbol plus offset. Code that computes a jump
+//! target in another way must not be optimised.
+
+use super::{DynAsm, Item, ItemKind, LineKind, SymExpr};
+use crate::ais::{Instruction, Opcode, Register};
+use crate::constant;
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// What the optimiser removed, and the size of the code and data before and after. Alignment padding doesn't count,
+/// it only changes because of what was removed, so the image can shrink by less than the bytes saved.
+#[derive(Debug, Clone, Default)]
+pub struct OptReport {
+    pub reloads: usize,
+    pub folded: usize,
+    pub jumps: usize,
+    pub dead: usize,
+    pub bytes_before: u32,
+    pub bytes_after: u32,
+}
+
+impl fmt::Display for OptReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "removed {} reloads, {} jumps to the next instruction and {} dead writes, folded {} constants: {} -> {} bytes, {} saved",
+            self.reloads,
+            self.jumps,
+            self.dead,
+            self.folded,
+            self.bytes_before,
+            self.bytes_after,
+            self.bytes_before - self.bytes_after
+        )
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Value {
+    Const(u32),
+    // Whatever a load of the expression produced, including the PIC base
+    Addr(SymExpr),
+}
+
+// Known value of every register, by number
+type State = [Option<Value>; 32];
+
+const UNKNOWN: State = {
+    let mut state = [None; 32];
+    state[0] = Some(Value::Const(0));
+    state
+};
+
+// What an item does to the registers, as far as the optimiser cares
+enum Effect {
+    // I type instruction without a fixup, dst = opcode(src, imm)
+    IType { opcode: Opcode, dst: u32, src: u32, imm: u16 },
+    Load { dst: u32, expr: SymExpr, base: Option<u32> },
+    // XJ, control doesn't fall through
+    Jump(u32),
+    // Any of the registers may be read or written
+    Other(Vec<u32>),
+    // Every register may be read or written
+    Barrier,
+    // Control leaves the AIS code, like the footer
+    Exit,
+}
+
+fn bits(reg: &Option<Register>) -> Option<u32> {
+    reg.as_ref().and_then(|x| x.bits().ok())
+}
+
+fn effect(item: &Item) -> Effect {
+    match (&item.kind, item.line) {
+        // A virtual dst isn't allocated yet
+        (ItemKind::Load(load), _) => match load.dst.bits() {
+            Ok(dst) => Effect::Load {
+                dst,
+                expr: load.expr,
+                base: load.rebase.as_ref().map(|x| x.bits().unwrap()),
+            },
+            Err(_) => Effect::Barrier,
+        },
+        (ItemKind::PicBase(reg), _) => Effect::Other(vec![reg.bits().unwrap(), 16]),
+        // Pushes and pops of the callee saved registers
+        (ItemKind::Frame(frame), _) => Effect::Other(frame.saved.iter().copied().chain([20]).collect()),
+        (ItemKind::Bytes(_, _), LineKind::Footer) => Effect::Exit,
+        (ItemKind::Bytes(bytes, fixup), LineKind::Instruction) => {
+            let Ok((instr, _)) = Instruction::decode(bytes) else {
+                return Effect::Barrier;
+            };
+            let (dst, src) = (bits(&instr.rt), bits(&instr.rs));
+            match (instr.opcode, fixup, dst, src, instr.imm) {
+                (Opcode::XJ, _, Some(reg), _, _) => Effect::Jump(reg),
+                (opcode, None, Some(dst), Some(src), Some(imm)) if constant::i_type_result(opcode, 0, 0).is_some() => {
+                    Effect::IType { opcode, dst, src, imm }
+                }
+                _ => Effect::Other([&instr.rs, &instr.rt, &instr.rd].into_iter().filter_map(bits).collect()),
+            }
+        }
+        _ => Effect::Barrier,
+    }
+}
+
+// State where two paths come together, None is a path that nothing reaches
+fn meet(a: Option<State>, b: Option<State>) -> Option<State> {
+    match (a, b) {
+        (Some(mut state), Some(b)) => {
+            for (x, y) in state.iter_mut().zip(&b) {
+                if x != y {
+                    *x = None;
+                }
+            }
+            Some(state)
+        }
+        (a, b) => a.or(b),
+    }
+}
+
+// States that XJ instructions carry to symbols: to any symbol when the target isn't known, or to one position
+#[derive(PartialEq, Default)]
+struct Jumps {
+    any: Option<State>,
+    to: BTreeMap<(usize, usize), State>,
+}
+
+impl Jumps {
+    fn add(&mut self, target: Option<(usize, usize)>, state: State) {
+        match target {
+            Some(target) => {
+                let state = meet(self.to.get(&target).copied(), Some(state)).unwrap();
+                self.to.insert(target, state);
+            }
+            None => self.any = meet(self.any, Some(state)),
+        }
+    }
+
+    fn to(&self, target: (usize, usize)) -> Option<State> {
+        meet(self.any, self.to.get(&target).copied())
+    }
+}
+
+impl DynAsm {
+    /// Run the peephole optimiser over everything generated so far, see the module documentation
+    pub fn optimize(&mut self) -> OptReport {
+        let mut report = OptReport {
+            bytes_before: self.code_size(),
+            ..Default::default()
+        };
+
+        loop {
+            let before = (report.reloads, report.folded, report.jumps, report.dead);
+            self.fold_values(&mut report);
+            self.remove_dead(&mut report);
+            if before == (report.reloads, report.folded, report.jumps, report.dead) {
+                break;
+            }
+        }
+
+        report.bytes_after = self.code_size();
+        report
+    }
+
+    // Bytes of every item with the loads relaxed, without the padding of .align and between sections
+    fn code_size(&mut self) -> u32 {
+        self.init_loads();
+        let layout = self.relax();
+        let mut size = 0;
+        for (section, offsets) in self.sections.iter().zip(&layout.offsets) {
+            for (i, item) in section.items.iter().enumerate() {
+                if !matches!(item.kind, ItemKind::Align(_)) {
+                    size += offsets[i + 1] - offsets[i];
+                }
+            }
+        }
+        size
+    }
+
+    // Places an XJ can go to: symbols, and anywhere in a section that is referenced with an offset from a symbol
+    pub(super) fn labels(&self) -> BTreeSet<(usize, usize)> {
+        let mut labels: BTreeSet<(usize, usize)> = self
+            .symbols
+            .entries
+            .iter()
+            .filter_map(|x| x.loc)
+            .map(|(section, index)| (section.0, index))
+            .collect();
+
+        for item in self.sections.iter().flat_map(|x| &x.items) {
+            let Some(expr) = item.expr().filter(|x| x.sub.is_none() && x.addend != 0) else {
+                continue;
+            };
+            if let Some((section, _)) = self.symbols.entries[expr.sym.index].loc {
+                labels.extend((0..=self.sections[section.0].items.len()).map(|x| (section.0, x)));
+            }
+        }
+        labels
+    }
+
+    // Position that a jump to value goes to, if it is the address of a symbol
+    fn jump_target(&self, value: Option<Value>) -> Option<(usize, usize)> {
+        match value {
+            Some(Value::Addr(expr)) if expr.sub.is_none() && expr.addend == 0 => {
+                self.symbols.entries[expr.sym.index].loc.map(|(section, index)| (section.0, index))
+            }
+            _ => None,
+        }
+    }
+
+    // Write to a register, the address of a load is only known while the PIC base stays the same
+    fn clobber(&self, state: &mut State, reg: u32) {
+        if reg == 0 {
+            return;
+        }
+        state[reg as usize] = None;
+        if self.pic.as_ref().and_then(|x| x.bits().ok()) == Some(reg) {
+            for x in state.iter_mut() {
+                if let Some(Value::Addr(_)) = x {
+                    *x = None;
+                }
+            }
+        }
+    }
+
+    // Register state before every item. It starts from nothing reaching the symbols, and is repeated until the state
+    // at the XJ instructions is stable. None is code that nothing reaches, it is treated as unknown.
+    fn analyse(&self, labels: &BTreeSet<(usize, usize)>) -> Vec<Vec<State>> {
+        let mut jumps = Jumps::default();
+        loop {
+            let mut states = Vec::new();
+            let mut next_jumps = Jumps::default();
+
+            for (s, section) in self.sections.iter().enumerate() {
+                let mut section_states = Vec::with_capacity(section.items.len());
+                let mut state = Some(UNKNOWN);
+
+                for (i, item) in section.items.iter().enumerate() {
+                    if labels.contains(&(s, i)) {
+                        state = meet(state, jumps.to((s, i)));
+                    }
+                    section_states.push(state.unwrap_or(UNKNOWN));
+
+                    let Some(current) = &mut state else {
+                        continue;
+                    };
+                    match effect(item) {
+                        Effect::IType { opcode, dst, src, imm } => {
+                            let value = match current[src as usize] {
+                                Some(Value::Const(x)) => constant::i_type_result(opcode, x, imm).map(Value::Const),
+                                _ => None,
+                            };
+                            self.clobber(current, dst);
+                            if dst != 0 {
+                                current[dst as usize] = value;
+                            }
+                        }
+                        Effect::Load { dst, expr, .. } => {
+                            self.clobber(current, dst);
+                            current[dst as usize] = Some(Value::Addr(expr));
+                        }
+                        Effect::Jump(reg) => {
+                            next_jumps.add(self.jump_target(current[reg as usize]), *current);
+                            state = None;
+                        }
+                        Effect::Other(regs) => {
+                            for reg in regs {
+                                self.clobber(current, reg);
+                            }
+                        }
+                        Effect::Barrier => *current = UNKNOWN,
+                        Effect::Exit => state = None,
+                    }
+                }
+                states.push(section_states);
+            }
+
+            if next_jumps == jumps {
+                return states;
+            }
+            jumps = next_jumps;
+        }
+    }
+
+    // Remove reloads and jumps to the next instruction, shorten constant computations
+    fn fold_values(&mut self, report: &mut OptReport) {
+        let labels = self.labels();
+        let states = self.analyse(&labels);
+
+        let mut rewritten = Vec::new();
+        for (s, section) in self.sections.iter().enumerate() {
+            let items = &section.items;
+            let mut new_items = Vec::new();
+            let mut map = Vec::with_capacity(items.len() + 1);
+
+            let mut i = 0;
+            while i < items.len() {
+                map.push(new_items.len());
+                let state = &states[s][i];
+
+                match effect(&items[i]) {
+                    Effect::Load { dst, expr, .. } if state[dst as usize] == Some(Value::Addr(expr)) => {
+                        report.reloads += 1;
+                        i += 1;
+                        continue;
+                    }
+                    Effect::Jump(reg) if i + 1 < items.len() && self.jump_target(state[reg as usize]) == Some((s, i + 1)) => {
+                        report.jumps += 1;
+                        i += 1;
+                        continue;
+                    }
+                    Effect::IType { dst, .. } if dst != 0 => {
+                        // Run of instructions that all compute a constant into dst, without a symbol in between
+                        let mut end = i;
+                        let mut value = None;
+                        while end < items.len() && (end == i || !labels.contains(&(s, end))) {
+                            match effect(&items[end]) {
+                                Effect::IType { opcode, dst: x, src, imm } if x == dst => {
+                                    match states[s][end][src as usize] {
+                                        Some(Value::Const(rs)) => value = constant::i_type_result(opcode, rs, imm),
+                                        _ => break,
+                                    }
+                                }
+                                _ => break,
+                            }
+                            end += 1;
+                        }
+
+                        if let Some(value) = value {
+                            let known: Vec<(Register, u32)> = (1..32)
+                                .filter_map(|x| match state[x] {
+                                    Some(Value::Const(c)) => Some((Register::Index(x as u8), c)),
+                                    _ => None,
+                                })
+                                .collect();
+                            let code = constant::synthesize(&Register::Index(dst as u8), value, &known);
+
+                            if code.len() < end - i {
+                                match code.len() {
+                                    0 => report.reloads += 1,
+                                    _ => report.folded += 1,
+                                }
+                                let first = &items[i];
+                                for (n, instr) in code.into_iter().enumerate() {
+                                    new_items.push(Item {
+                                        kind: ItemKind::Bytes(instr.encode().unwrap(), None),
+                                        line: LineKind::Instruction,
+                                        comment: if n == 0 { first.comment.clone() } else { None },
+                                        source: first.source,
+                                    });
+                                }
+                                map.extend((i + 1..end).map(|_| new_items.len()));
+                                i = end;
+                                continue;
+                            }
+                        }
+                    }
+                    _ => (),
+                }
+
+                new_items.push(items[i].clone());
+                i += 1;
+            }
+            map.push(new_items.len());
+            rewritten.push((new_items, map));
+        }
+
+        self.apply(rewritten);
+    }
+
+    // Remove I type instructions and loads whose register is written again before anything reads it.
+    // Every register is live at symbols, jumps, exits and barriers.
+    fn remove_dead(&mut self, report: &mut OptReport) {
+        let labels = self.labels();
+
+        let mut rewritten = Vec::new();
+        for (s, section) in self.sections.iter().enumerate() {
+            let items = &section.items;
+            let mut keep = vec![true; items.len()];
+            let mut live = [true; 32];
+
+            for i in (0..items.len()).rev() {
+                if labels.contains(&(s, i + 1)) {
+                    live = [true; 32];
+                }
+
+                match effect(&items[i]) {
+                    Effect::IType { dst, src, .. } => {
+                        if dst != 0 && !live[dst as usize] {
+                            keep[i] = false;
+                            report.dead += 1;
+                            continue;
+                        }
+                        live[dst as usize] = false;
+                        live[src as usize] = true;
+                    }
+                    Effect::Load { dst, base, .. } => {
+                        if !live[dst as usize] {
+                            keep[i] = false;
+                            report.dead += 1;
+                            continue;
+                        }
+                        live[dst as usize] = false;
+                        if let Some(base) = base {
+                            live[base as usize] = true;
+                        }
+                    }
+                    Effect::Other(regs) => {
+                        for reg in regs {
+                            live[reg as usize] = true;
+                        }
+                    }
+                    Effect::Jump(_) | Effect::Barrier | Effect::Exit => live = [true; 32],
+                }
+            }
+
+            let mut new_items = Vec::new();
+            let mut map = Vec::with_capacity(items.len() + 1);
+            for (item, keep) in items.iter().zip(keep) {
+                map.push(new_items.len());
+                if keep {
+                    new_items.push(item.clone());
+                }
+            }
+            map.push(new_items.len());
+            rewritten.push((new_items, map));
+        }
+
+        self.apply(rewritten);
+    }
+
+    // Replace the items of every section, map gives the new index of every old index for the symbols
+    pub(super) fn apply(&mut self, rewritten: Vec<(Vec<Item>, Vec<usize>)>) {
+        for (s, (items, map)) in rewritten.into_iter().enumerate() {
+            self.sections[s].items = items;
+
+            for symbol in &mut self.symbols.entries {
+                if let Some((section, index)) = &mut symbol.loc {
+                    if section.0 == s {
+                        *index = map[*index];
+                    }
+                }
+            }
+        }
+    }
+}
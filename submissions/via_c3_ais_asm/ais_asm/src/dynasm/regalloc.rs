@@ -0,0 +1,604 @@
+//! Register allocator for the virtual registers of a DynAsm.
+//!
+//! [`DynAsm::new_vreg`] hands out virtual registers, instructions and loads take them like any other register.
+//! [`DynAsm::allocate`] maps them onto physical registers with a linear scan over their live ranges. A range runs
+//! from the first to the last place its value is live, where an XJ continues at the symbol that was loaded right
+//! before it, or at any symbol when the target isn't known. Registers come from the pool, minus the scratch and
+//! live registers and every register that the code names itself. The x86 callee saved registers EBX, ESP, EBP, ESI
+//! and EDI are never in the pool.
+//!
+//! A call made with [`DynAsm::gen_call`] continues after itself and clobbers everything but the callee saved
+//! registers, so a value that is live across a call gets one of EBX, EBP, ESI and EDI instead. The frame of the
+//! function, or the one between header and footer for code outside functions, saves it and restores it on the way
+//! out, so the kernel still gets them back unchanged.
+//!
+//! When the registers run out, the value that lives longest goes to the stack. The last two pool registers are then
+//! kept free to reload and store spilled values around every instruction that uses them, with XL and XS. The slots
+//! are in the frame of the code that uses the value, code that moves ESP itself must not spill. Without a header the
+//! frame is set up at the start of `.text`.
+
+use super::{DynAsm, DynAsmError, Frame, Item, ItemKind, LineKind, Sym};
+use crate::ais::{Function, Instruction, Opcode, Register, Size, SubOpXalu};
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// What the register allocator did
+#[derive(Debug, Clone, Default)]
+pub struct AllocReport {
+    pub vregs: usize,
+    // Distinct physical registers that were handed out, including the ones for spill code
+    pub registers: usize,
+    pub spilled: usize,
+    // Bytes reserved on the stack for spilled values
+    pub frame: u32,
+    // Callee saved registers that frames save, for values live across calls and the ones functions write
+    pub saved: usize,
+}
+
+impl fmt::Display for AllocReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} virtual registers in {} physical registers, {} spilled to a {} byte stack area",
+            self.vregs, self.registers, self.spilled, self.frame
+        )?;
+        if self.saved > 0 {
+            write!(f, ", {} callee saved registers saved", self.saved)?;
+        }
+        Ok(())
+    }
+}
+
+const EAX: u32 = 16;
+const ESP: u32 = 20;
+
+// x86 registers that the caller of the payload expects back unchanged
+const CALLEE_SAVED: [u32; 5] = [19, 20, 21, 22, 23];
+
+// The AIS only registers first, then the x86 registers that the caller doesn't expect to survive.
+// EAX is left out, the header writes it and it holds the result of the payload.
+pub(super) fn default_pool() -> Vec<Register> {
+    (1..16).chain([17, 18]).map(Register::Index).collect()
+}
+
+pub(super) fn has_virtual(instr: &Instruction) -> bool {
+    [&instr.rs, &instr.rt, &instr.rd]
+        .into_iter()
+        .flatten()
+        .any(|x| matches!(x, Register::Virtual(_)))
+}
+
+// Copy with every virtual register replaced by R0, to check the encoding before allocation
+pub(super) fn with_zero_registers(instr: &Instruction) -> Instruction {
+    map_virtual(instr, |_| 0)
+}
+
+fn map_virtual(instr: &Instruction, f: impl Fn(u32) -> u32) -> Instruction {
+    let mut instr = instr.clone();
+    for reg in [&mut instr.rs, &mut instr.rt, &mut instr.rd].into_iter().flatten() {
+        if let Register::Virtual(x) = reg {
+            *reg = Register::Index(f(*x) as u8);
+        }
+    }
+    instr
+}
+
+// Registers that an instruction reads, and the one it writes
+fn operands(instr: &Instruction) -> (Vec<&Register>, Option<&Register>) {
+    let (uses, def) = match instr.opcode {
+        Opcode::XJ => (vec![&instr.rt], None),
+        // The rt field holds the condition
+        Opcode::XALU | Opcode::XALUR if matches!(instr.function, Some(Function::Xalu(SubOpXalu::SETCC, _))) => {
+            (vec![&instr.rs], Some(&instr.rd))
+        }
+        Opcode::XALU | Opcode::XALUR => (vec![&instr.rs, &instr.rt], Some(&instr.rd)),
+        Opcode::XALUI | Opcode::XALUIR => (vec![&instr.rs], Some(&instr.rd)),
+        Opcode::XL | Opcode::XIOR => (vec![&instr.rt], Some(&instr.rs)),
+        Opcode::XS | Opcode::XIOW => (vec![&instr.rs, &instr.rt], None),
+        // I type, nothing else can be encoded
+        _ => (vec![&instr.rs], Some(&instr.rt)),
+    };
+    (uses.into_iter().flatten().collect(), def.and_then(|x| x.as_ref()))
+}
+
+fn virtual_of(reg: &Register) -> Option<u32> {
+    match reg {
+        Register::Virtual(x) => Some(*x),
+        _ => None,
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Flow {
+    Next,
+    // XJ, to a known position or to any symbol
+    Jump(Option<(usize, usize)>),
+    // XJ of a call, it comes back to the next item with the caller saved registers clobbered
+    Call,
+    // XJ of a conditional branch, to the position or to the next item
+    Branch(Option<(usize, usize)>),
+    Exit,
+}
+
+// What an item does with the virtual registers, and which physical registers it names
+struct Access {
+    uses: Vec<u32>,
+    def: Option<u32>,
+    physical: Vec<u32>,
+    written: Option<u32>,
+    flow: Flow,
+}
+
+impl Access {
+    fn new(regs: &[&Register], def: Option<&Register>) -> Self {
+        Self {
+            uses: regs.iter().filter_map(|x| virtual_of(x)).collect(),
+            def: def.and_then(virtual_of),
+            physical: regs.iter().copied().chain(def).filter_map(|x| x.bits().ok()).collect(),
+            written: def.and_then(|x| x.bits().ok()),
+            flow: Flow::Next,
+        }
+    }
+}
+
+// Where a virtual register ended up
+#[derive(Copy, Clone, PartialEq)]
+enum Home {
+    Reg(u32),
+    // Offset from ESP
+    Stack(u32),
+}
+
+// Linear scan over the live ranges in order of their start. Values in across take a register from saved, the others
+// one from pool. Returns the register of every virtual register, None for the ones that don't get one.
+fn linear_scan(
+    ranges: &BTreeMap<u32, (usize, usize)>,
+    pool: &[u32],
+    saved: &[u32],
+    across: &BTreeSet<u32>,
+) -> BTreeMap<u32, Option<u32>> {
+    let mut order: Vec<(usize, usize, u32)> = ranges.iter().map(|(v, (start, end))| (*start, *end, *v)).collect();
+    order.sort();
+
+    let preference: Vec<u32> = pool.iter().chain(saved).copied().collect();
+    let mut free = preference.clone();
+    // End, virtual register and register of the ranges that hold a register
+    let mut active: Vec<(usize, u32, u32)> = Vec::new();
+    let mut result = BTreeMap::new();
+
+    for (start, end, v) in order {
+        active.retain(|(e, _, reg)| {
+            if *e < start {
+                free.push(*reg);
+            }
+            *e >= start
+        });
+        free.sort_by_key(|x| preference.iter().position(|y| y == x));
+
+        let allowed = if across.contains(&v) { saved } else { pool };
+        if let Some(x) = free.iter().position(|x| allowed.contains(x)) {
+            let reg = free.remove(x);
+            active.push((end, v, reg));
+            result.insert(v, Some(reg));
+            continue;
+        }
+
+        // Out of registers, the range that ends last goes to the stack
+        let candidates = active.iter().enumerate().filter(|(_, x)| allowed.contains(&x.2));
+        match candidates.max_by_key(|(_, x)| x.0) {
+            Some((i, &(e, spilled, reg))) if e > end => {
+                result.insert(spilled, None);
+                active[i] = (end, v, reg);
+                result.insert(v, Some(reg));
+            }
+            _ => {
+                result.insert(v, None);
+            }
+        }
+    }
+
+    result
+}
+
+impl DynAsm {
+    /// New virtual register, it gets a physical register or a stack slot when the code is allocated
+    pub fn new_vreg(&mut self) -> Register {
+        self.vregs += 1;
+        Register::Virtual(self.vregs - 1)
+    }
+
+    /// Set the registers that virtual registers may get, in order of preference. R0 and the x86 callee saved
+    /// registers can't be used. Scratch and live registers are skipped when the code is allocated.
+    pub fn set_alloc_pool(&mut self, regs: &[Register]) -> Result<(), DynAsmError> {
+        for reg in regs {
+            let bits = reg.bits()?;
+            if bits == 0 || CALLEE_SAVED.contains(&bits) {
+                return Err(DynAsmError::NotAllocatable(reg.clone()));
+            }
+        }
+        self.pool = regs.to_vec();
+        Ok(())
+    }
+
+    /// Replace the virtual registers of all code generated so far and fill in the frames, see the module
+    /// documentation. finish does this as well, call it earlier to run the peephole optimiser over the result.
+    pub fn allocate(&mut self) -> Result<AllocReport, DynAsmError> {
+        let mut accesses = Vec::new();
+        let mut regions = Vec::new();
+        for (s, section) in self.sections.iter().enumerate() {
+            let mut list = Vec::with_capacity(section.items.len());
+            for i in 0..section.items.len() {
+                list.push(self.access(s, i));
+                regions.push(self.region((s, i)));
+            }
+            accesses.push(list);
+        }
+        let (ranges, across) = self.live_ranges(&accesses);
+
+        // Registers that the code holds values in already
+        let mut taken: BTreeSet<u32> = accesses.iter().flatten().flat_map(|x| x.physical.iter().copied()).collect();
+        for reg in self.scratch.iter().chain(&self.live).chain(&self.pic) {
+            taken.insert(reg.bits()?);
+        }
+        let mut pool = Vec::new();
+        for reg in &self.pool {
+            let bits = reg.bits()?;
+            if !taken.contains(&bits) && !pool.contains(&bits) {
+                pool.push(bits);
+            }
+        }
+        let saved: Vec<u32> = CALLEE_SAVED.into_iter().filter(|x| *x != ESP && !taken.contains(x)).collect();
+
+        let mut assigned = linear_scan(&ranges, &pool, &saved, &across);
+        let mut temps = None;
+        if assigned.values().any(Option::is_none) {
+            let Some(split) = pool.len().checked_sub(2) else {
+                return Err(DynAsmError::OutOfRegisters);
+            };
+            assigned = linear_scan(&ranges, &pool[..split], &saved, &across);
+            temps = Some([pool[split], pool[split + 1]]);
+        }
+
+        // Frames as earlier allocations left them. A value belongs to the frame of the code that first uses it.
+        let mut frames: BTreeMap<usize, Frame> = BTreeMap::new();
+        for item in self.sections.iter().flat_map(|x| &x.items) {
+            if let ItemKind::Frame(frame) = &item.kind {
+                frames.entry(frame.region).or_insert_with(|| frame.clone());
+            }
+        }
+        let mut region_of = BTreeMap::new();
+        for (p, access) in accesses.iter().flatten().enumerate() {
+            for v in access.uses.iter().chain(&access.def) {
+                region_of.entry(*v).or_insert(regions[p]);
+            }
+        }
+
+        // Stack slots follow the ones of earlier allocations
+        let mut homes = BTreeMap::new();
+        let mut report = AllocReport::default();
+        let mut save = |frames: &mut BTreeMap<usize, Frame>, region: usize, reg: u32| {
+            let frame = frames.entry(region).or_insert_with(|| Frame::new(region, true));
+            if !frame.saved.contains(&reg) {
+                frame.saved.push(reg);
+                report.saved += 1;
+            }
+        };
+        for (v, reg) in &assigned {
+            let region = region_of[v];
+            let home = match reg {
+                Some(reg) => {
+                    if CALLEE_SAVED.contains(reg) {
+                        save(&mut frames, region, *reg);
+                    }
+                    Home::Reg(*reg)
+                }
+                None => {
+                    let frame = frames.entry(region).or_insert_with(|| Frame::new(region, true));
+                    frame.slots += 1;
+                    Home::Stack(4 * (frame.slots - 1))
+                }
+            };
+            homes.insert(*v, home);
+        }
+
+        // Functions save the callee saved registers they write themselves
+        for (p, access) in accesses.iter().flatten().enumerate() {
+            match access.written {
+                Some(reg) if regions[p] > 0 && reg != ESP && CALLEE_SAVED.contains(&reg) => {
+                    save(&mut frames, regions[p], reg)
+                }
+                _ => (),
+            }
+        }
+
+        let mut registers: BTreeSet<u32> = assigned.values().flatten().copied().collect();
+        registers.extend(temps.iter().flatten());
+        report.vregs = ranges.len();
+        report.registers = registers.len();
+        report.spilled = assigned.values().filter(|x| x.is_none()).count();
+        report.frame = 4 * report.spilled as u32;
+
+        // Code outside functions without a header sets up its frame at the start
+        let mut insert = None;
+        let mut has_enter = false;
+        for item in self.sections.iter_mut().flat_map(|x| &mut x.items) {
+            if let ItemKind::Frame(frame) = &mut item.kind {
+                let filled = &frames[&frame.region];
+                frame.saved = filled.saved.clone();
+                frame.slots = filled.slots;
+                has_enter |= frame.region == 0 && frame.enter;
+            }
+        }
+        if let Some(frame) = frames.get(&0).filter(|x| !has_enter && (x.slots > 0 || !x.saved.is_empty())) {
+            insert = Some(Frame {
+                enter: true,
+                ..frame.clone()
+            });
+        }
+
+        if report.vregs > 0 || insert.is_some() {
+            self.rewrite(&homes, temps.unwrap_or_default(), insert)?;
+        }
+        Ok(report)
+    }
+
+    // Position of a placed symbol
+    fn placed(&self, sym: Sym) -> Option<(usize, usize)> {
+        self.symbols.entries[sym.index].loc.map(|(section, index)| (section.0, index))
+    }
+
+    // Frame that the item at a position belongs to, the one of the innermost function around it
+    fn region(&self, (s, i): (usize, usize)) -> usize {
+        let mut region = 0;
+        for (x, (entry, end)) in self.functions.iter().enumerate() {
+            if let (Some(entry), Some(end)) = (self.placed(*entry), self.placed(*end)) {
+                if entry.0 == s && end.0 == s && entry.1 <= i && i < end.1 {
+                    region = x + 1;
+                }
+            }
+        }
+        region
+    }
+
+    // Access of an item, the ones before it tell the target of an XJ
+    fn access(&self, s: usize, i: usize) -> Access {
+        let items = &self.sections[s].items[..=i];
+        let item = &items[i];
+        let decoded;
+        let instr = match (&item.kind, item.line) {
+            (ItemKind::Instr(instr), _) => Some(instr),
+            (ItemKind::Bytes(bytes, _), LineKind::Instruction) => {
+                decoded = Instruction::decode(bytes).ok().map(|x| x.0);
+                decoded.as_ref()
+            }
+            _ => None,
+        };
+
+        let eax = Register::Index(EAX as u8);
+        let mut access = match (&item.kind, item.line, instr) {
+            (_, _, Some(instr)) => {
+                let (uses, def) = operands(instr);
+                Access::new(&uses, def)
+            }
+            (ItemKind::Load(load), _, _) => Access::new(&load.rebase.iter().collect::<Vec<_>>(), Some(&load.dst)),
+            (ItemKind::PicBase(reg), _, _) => Access::new(&[reg, &eax], None),
+            (_, LineKind::Header, _) => Access::new(&[&eax], None),
+            (_, LineKind::Footer, _) => {
+                let mut access = Access::new(&[], None);
+                access.flow = Flow::Exit;
+                access
+            }
+            _ => Access::new(&[], None),
+        };
+
+        let Some(target) = instr.filter(|x| x.opcode == Opcode::XJ).and_then(|x| x.rt.as_ref()) else {
+            return access;
+        };
+
+        // Calls come back right after themselves, and function returns leave the function
+        let next = Some((s, i + 1));
+        if self.calls.iter().any(|x| self.placed(*x) == next) {
+            access.flow = Flow::Call;
+            return access;
+        }
+        if self.functions.iter().any(|x| self.placed(x.1) == next) {
+            access.flow = Flow::Exit;
+            return access;
+        }
+        if let Some((_, target)) = self.branches.iter().find(|x| self.placed(x.0) == next) {
+            access.flow = Flow::Branch(self.placed(*target));
+            return access;
+        }
+
+        // A jump right after a load of a symbol into the same register goes there
+        let same = |a: &Register, b: &Register| a == b || a.bits().is_ok() && a.bits().ok() == b.bits().ok();
+        let position = match items.len().checked_sub(2).map(|x| &items[x].kind) {
+            Some(ItemKind::Load(load)) if same(&load.dst, target) && load.expr.sub.is_none() && load.expr.addend == 0 => {
+                let loc = self.symbols.entries[load.expr.sym.index].loc;
+                loc.map(|(section, index)| (section.0, index))
+            }
+            _ => None,
+        };
+        access.flow = Flow::Jump(position);
+        access
+    }
+
+    // Live range of every virtual register, over points 2p for the reads and 2p+1 for the write of item p, with
+    // the items of all sections numbered in order. Also returns the virtual registers that are live across a call.
+    fn live_ranges(&self, accesses: &[Vec<Access>]) -> (BTreeMap<u32, (usize, usize)>, BTreeSet<u32>) {
+        let mut start = vec![0];
+        for section in accesses {
+            start.push(start.last().unwrap() + section.len());
+        }
+        let count = *start.last().unwrap();
+        let position = |(s, i): (usize, usize)| (i < accesses[s].len()).then_some(start[s] + i);
+        let targets: Vec<usize> = self.labels().into_iter().filter_map(position).collect();
+
+        let mut live_in = vec![BTreeSet::new(); count];
+        let mut live_out = vec![BTreeSet::new(); count];
+        loop {
+            let mut changed = false;
+            for (s, section) in accesses.iter().enumerate().rev() {
+                for (i, access) in section.iter().enumerate().rev() {
+                    let p = start[s] + i;
+                    let next: Vec<usize> = match access.flow {
+                        Flow::Next | Flow::Call => position((s, i + 1)).into_iter().collect(),
+                        Flow::Jump(Some(target)) => position(target).into_iter().collect(),
+                        Flow::Branch(Some(target)) => [target, (s, i + 1)].into_iter().filter_map(position).collect(),
+                        Flow::Branch(None) => targets.iter().copied().chain(position((s, i + 1))).collect(),
+                        Flow::Jump(None) => targets.clone(),
+                        Flow::Exit => Vec::new(),
+                    };
+
+                    let mut out = BTreeSet::new();
+                    for x in next {
+                        out.extend(live_in[x].iter().copied());
+                    }
+                    let mut live = out.clone();
+                    if let Some(def) = access.def {
+                        live.remove(&def);
+                    }
+                    live.extend(access.uses.iter().copied());
+
+                    live_out[p] = out;
+                    if live != live_in[p] {
+                        live_in[p] = live;
+                        changed = true;
+                    }
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        let mut across = BTreeSet::new();
+        let mut ranges: BTreeMap<u32, (usize, usize)> = BTreeMap::new();
+        let mut extend = |v: u32, point: usize| {
+            let range = ranges.entry(v).or_insert((point, point));
+            range.0 = range.0.min(point);
+            range.1 = range.1.max(point);
+        };
+        for (p, access) in accesses.iter().flatten().enumerate() {
+            for v in access.uses.iter().chain(&live_in[p]) {
+                extend(*v, 2 * p);
+            }
+            for v in access.def.iter().chain(&live_out[p]) {
+                extend(*v, 2 * p + 1);
+            }
+            if access.flow == Flow::Call {
+                across.extend(live_out[p].iter().copied());
+            }
+        }
+        (ranges, across)
+    }
+
+    // Put the physical registers in, with reloads and stores around the uses of spilled values. frame is inserted at
+    // the start of .text.
+    fn rewrite(&mut self, homes: &BTreeMap<u32, Home>, temps: [u32; 2], frame: Option<Frame>) -> Result<(), DynAsmError> {
+        let reg = |x: u32| Register::Index(x as u8);
+        let addi = |dst: u32, src: u32, imm: u32| Instruction::i_type(Opcode::ADDI, reg(dst), reg(src), imm as u16);
+        // Address of the slot into address, then the access
+        let spill_code = |offset: u32, value: u32, address: u32, load: bool| {
+            let access = match load {
+                true => Instruction::xl(Size::Bits32, reg(address), reg(value)),
+                false => Instruction::xs(Size::Bits32, reg(address), reg(value)),
+            };
+            [addi(address, ESP, offset), access]
+        };
+
+        let mut rewritten = Vec::new();
+        for (s, section) in self.sections.iter().enumerate() {
+            let items = &section.items;
+            let mut new_items = Vec::new();
+            let mut map = Vec::with_capacity(items.len() + 1);
+
+            for (i, item) in items.iter().enumerate() {
+                map.push(new_items.len());
+                let mut kind = item.kind.clone();
+                let mut before = Vec::new();
+                let mut after = Vec::new();
+
+                if let Some(frame) = frame.as_ref().filter(|_| s == 0 && i == 0) {
+                    new_items.push(Item {
+                        kind: ItemKind::Frame(frame.clone()),
+                        line: LineKind::Instruction,
+                        comment: None,
+                        source: item.source,
+                    });
+                }
+
+                match &item.kind {
+                    ItemKind::Instr(instr) => {
+                        // Every spilled value that is read gets a temporary. A spilled result takes its own or the
+                        // first one, the reads are done by then.
+                        let (uses, def) = operands(instr);
+                        let mut temp_of: Vec<(u32, u32)> = Vec::new();
+                        for v in uses.into_iter().filter_map(virtual_of) {
+                            if let Home::Stack(offset) = homes[&v] {
+                                if temp_of.iter().all(|x| x.0 != v) {
+                                    let temp = temps[temp_of.len()];
+                                    temp_of.push((v, temp));
+                                    before.extend(spill_code(offset, temp, temp, true));
+                                }
+                            }
+                        }
+                        let def = def.and_then(virtual_of).filter(|x| matches!(homes[x], Home::Stack(_)));
+                        if let Some(v) = def.filter(|v| temp_of.iter().all(|x| x.0 != *v)) {
+                            temp_of.push((v, temps[0]));
+                        }
+                        let temp = |v: u32| temp_of.iter().find(|x| x.0 == v).unwrap().1;
+
+                        let instr = map_virtual(instr, |v| match homes[&v] {
+                            Home::Reg(x) => x,
+                            Home::Stack(_) => temp(v),
+                        });
+                        kind = ItemKind::Bytes(instr.encode()?, None);
+                        if let Some((v, Home::Stack(offset))) = def.map(|v| (v, homes[&v])) {
+                            let address = if temp(v) == temps[0] { temps[1] } else { temps[0] };
+                            after.extend(spill_code(offset, temp(v), address, false));
+                        }
+                    }
+                    ItemKind::Load(load) => {
+                        if let Some(v) = virtual_of(&load.dst) {
+                            let mut load = load.clone();
+                            load.dst = reg(match homes[&v] {
+                                Home::Reg(x) => x,
+                                Home::Stack(offset) => {
+                                    after.extend(spill_code(offset, temps[0], temps[1], false));
+                                    temps[0]
+                                }
+                            });
+                            kind = ItemKind::Load(load);
+                        }
+                    }
+                    _ => (),
+                }
+
+                // The first item in place of the old one gets its comment
+                let mut comment = item.comment.clone();
+                let mut derived = |kind, line| Item {
+                    kind,
+                    line,
+                    comment: comment.take(),
+                    source: item.source,
+                };
+                for instr in before {
+                    new_items.push(derived(ItemKind::Bytes(instr.encode()?, None), LineKind::Instruction));
+                }
+                new_items.push(derived(kind, item.line));
+                for instr in after {
+                    new_items.push(derived(ItemKind::Bytes(instr.encode()?, None), LineKind::Instruction));
+                }
+            }
+            map.push(new_items.len());
+            rewritten.push((new_items, map));
+        }
+
+        self.apply(rewritten);
+        Ok(())
+    }
+}
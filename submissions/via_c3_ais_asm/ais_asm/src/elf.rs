@@ -0,0 +1,292 @@
+//! Write an image as ELF32 i386 relocatable object, so it can be linked into the kernel with rust-lld.
+//!
+//! The whole image is placed in a single .text.ais section, so offsets between the DynAsm sections stay
+//! the same. Named symbols become global symbols, absolute data words get R_386_32 relocations.
+//! i386 has no relocation type for the 16bit halves of an address, so address loads must be position
+//! independent, use DynAsm::new_pic for payloads that go into an object file.
+
+use crate::dynasm::Image;
+use crate::reloc::{BitField, RelocEntry, RelocKind};
+
+use std::io::Write;
+
+/// Error while writing an object file
+#[derive(Debug)]
+pub enum ElfError {
+    IoError(std::io::Error),
+    UnsupportedReloc(RelocEntry),
+}
+
+impl std::fmt::Display for ElfError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ElfError::IoError(_) => write!(f, "failed to write object file"),
+            ElfError::UnsupportedReloc(x) => write!(
+                f,
+                "{:?} relocation at offset 0x{:X} has no i386 equivalent, generate position independent code",
+                x.kind, x.offset
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ElfError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ElfError::IoError(x) => Some(x),
+            ElfError::UnsupportedReloc(_) => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ElfError {
+    fn from(x: std::io::Error) -> Self {
+        Self::IoError(x)
+    }
+}
+
+const EHDR_SIZE: u32 = 52;
+const SHDR_SIZE: u32 = 40;
+const SYM_SIZE: u32 = 16;
+const REL_SIZE: u32 = 8;
+
+const ET_REL: u16 = 1;
+const EM_386: u16 = 3;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_REL: u32 = 9;
+
+const SHF_WRITE: u32 = 0x1;
+const SHF_ALLOC: u32 = 0x2;
+const SHF_EXECINSTR: u32 = 0x4;
+const SHF_INFO_LINK: u32 = 0x40;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+
+const R_386_32: u32 = 1;
+
+// Section header indices, .rel.text.ais is at 2
+const TEXT: u16 = 1;
+const SYMTAB: u32 = 3;
+const STRTAB: u32 = 4;
+const SHSTRTAB: u16 = 5;
+
+// Symbol index of the .text.ais section symbol, relocations are relative to it
+const SECTION_SYM: u32 = 1;
+
+struct StrTab(Vec<u8>);
+
+impl StrTab {
+    fn new() -> Self {
+        Self(vec![0])
+    }
+
+    fn add(&mut self, name: &str) -> u32 {
+        let offset = self.0.len() as u32;
+        self.0.extend_from_slice(name.as_bytes());
+        self.0.push(0);
+        offset
+    }
+}
+
+#[derive(Default)]
+struct SectionHeader {
+    name: u32,
+    kind: u32,
+    flags: u32,
+    offset: u32,
+    size: u32,
+    link: u32,
+    info: u32,
+    align: u32,
+    entsize: u32,
+}
+
+impl SectionHeader {
+    fn write<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        let addr = 0u32;
+        for x in [
+            self.name,
+            self.kind,
+            self.flags,
+            addr,
+            self.offset,
+            self.size,
+            self.link,
+            self.info,
+            self.align,
+            self.entsize,
+        ] {
+            w.write_all(&x.to_le_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+fn symbol(name: u32, value: u32, size: u32, bind: u8, kind: u8, shndx: u16) -> Vec<u8> {
+    let mut sym = Vec::new();
+    sym.extend_from_slice(&name.to_le_bytes());
+    sym.extend_from_slice(&value.to_le_bytes());
+    sym.extend_from_slice(&size.to_le_bytes());
+    sym.push(bind << 4 | kind);
+    sym.push(0); // st_other, default visibility
+    sym.extend_from_slice(&shndx.to_le_bytes());
+    sym
+}
+
+fn pad<W: Write>(w: &mut W, pos: &mut u32, align: u32) -> std::io::Result<()> {
+    let next = pos.next_multiple_of(align);
+    w.write_all(&vec![0; (next - *pos) as usize])?;
+    *pos = next;
+    Ok(())
+}
+
+/// Write image as ELF32 i386 relocatable object
+pub fn write_elf<W: Write>(image: &Image, w: &mut W) -> Result<(), ElfError> {
+    let base = image.base();
+
+    // Section contents. REL relocations store the addend in place, relative to the section start.
+    // The nobits sections become zeros, so their symbols stay inside the section.
+    let mut text = image.bytes().to_vec();
+    text.resize(image.size() as usize, 0);
+    let mut rel = Vec::new();
+    for entry in image.relocs() {
+        if entry.kind != RelocKind::Word || entry.field != BitField::WORD {
+            return Err(ElfError::UnsupportedReloc(*entry));
+        }
+
+        let at = entry.offset as usize;
+        let addend = entry.value.wrapping_sub(base);
+        text[at..at + 4].copy_from_slice(&addend.to_le_bytes());
+        rel.extend_from_slice(&entry.offset.to_le_bytes());
+        rel.extend_from_slice(&(SECTION_SYM << 8 | R_386_32).to_le_bytes());
+    }
+
+    let mut strtab = StrTab::new();
+    let mut symtab = symbol(0, 0, 0, STB_LOCAL, 0, 0);
+    symtab.extend(symbol(0, 0, 0, STB_LOCAL, STT_SECTION, TEXT));
+    let first_global = 2;
+    for info in image.symbol_table() {
+        let kind = if info.section == ".text" { STT_FUNC } else { STT_OBJECT };
+        let name = strtab.add(&info.name);
+        symtab.extend(symbol(name, info.addr - base, info.size, STB_GLOBAL, kind, TEXT));
+    }
+
+    let mut shstrtab = StrTab::new();
+    let text_name = shstrtab.add(".text.ais");
+    let rel_name = shstrtab.add(".rel.text.ais");
+    let symtab_name = shstrtab.add(".symtab");
+    let strtab_name = shstrtab.add(".strtab");
+    let shstrtab_name = shstrtab.add(".shstrtab");
+
+    // Lay out the file: header, section contents, section header table
+    let text_align = image.sections().iter().map(|x| x.align).max().unwrap_or(1).max(4);
+    let contents: [(&[u8], u32); 5] = [
+        (&text, text_align),
+        (&rel, 4),
+        (&symtab, 4),
+        (&strtab.0, 1),
+        (&shstrtab.0, 1),
+    ];
+
+    let mut offsets = Vec::new();
+    let mut pos = EHDR_SIZE;
+    for (data, align) in contents {
+        pos = pos.next_multiple_of(align);
+        offsets.push(pos);
+        pos += data.len() as u32;
+    }
+    let shoff = pos.next_multiple_of(4);
+
+    let headers = [
+        SectionHeader::default(),
+        SectionHeader {
+            name: text_name,
+            kind: SHT_PROGBITS,
+            flags: SHF_ALLOC | SHF_WRITE | SHF_EXECINSTR,
+            offset: offsets[0],
+            size: text.len() as u32,
+            align: text_align,
+            ..Default::default()
+        },
+        SectionHeader {
+            name: rel_name,
+            kind: SHT_REL,
+            flags: SHF_INFO_LINK,
+            offset: offsets[1],
+            size: rel.len() as u32,
+            link: SYMTAB,
+            info: TEXT.into(),
+            align: 4,
+            entsize: REL_SIZE,
+        },
+        SectionHeader {
+            name: symtab_name,
+            kind: SHT_SYMTAB,
+            offset: offsets[2],
+            size: symtab.len() as u32,
+            link: STRTAB,
+            info: first_global,
+            align: 4,
+            entsize: SYM_SIZE,
+            ..Default::default()
+        },
+        SectionHeader {
+            name: strtab_name,
+            kind: SHT_STRTAB,
+            offset: offsets[3],
+            size: strtab.0.len() as u32,
+            align: 1,
+            ..Default::default()
+        },
+        SectionHeader {
+            name: shstrtab_name,
+            kind: SHT_STRTAB,
+            offset: offsets[4],
+            size: shstrtab.0.len() as u32,
+            align: 1,
+            ..Default::default()
+        },
+    ];
+
+    // ELF header
+    let mut ident = [0u8; 16];
+    ident[0..4].copy_from_slice(b"\x7FELF");
+    ident[4] = 1; // ELFCLASS32
+    ident[5] = 1; // ELFDATA2LSB
+    ident[6] = 1; // EV_CURRENT
+    w.write_all(&ident)?;
+    w.write_all(&ET_REL.to_le_bytes())?;
+    w.write_all(&EM_386.to_le_bytes())?;
+    w.write_all(&1u32.to_le_bytes())?; // e_version
+    w.write_all(&0u32.to_le_bytes())?; // e_entry
+    w.write_all(&0u32.to_le_bytes())?; // e_phoff
+    w.write_all(&shoff.to_le_bytes())?;
+    w.write_all(&0u32.to_le_bytes())?; // e_flags
+    w.write_all(&(EHDR_SIZE as u16).to_le_bytes())?;
+    w.write_all(&0u16.to_le_bytes())?; // e_phentsize
+    w.write_all(&0u16.to_le_bytes())?; // e_phnum
+    w.write_all(&(SHDR_SIZE as u16).to_le_bytes())?;
+    w.write_all(&(headers.len() as u16).to_le_bytes())?;
+    w.write_all(&SHSTRTAB.to_le_bytes())?;
+
+    let mut pos = EHDR_SIZE;
+    for (data, align) in contents {
+        pad(w, &mut pos, align)?;
+        w.write_all(data)?;
+        pos += data.len() as u32;
+    }
+    pad(w, &mut pos, 4)?;
+
+    for header in &headers {
+        header.write(w)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,47 @@
+//! Intel HEX output, for EPROM programmers and boot loaders that take it.
+//! Data records hold 16 bytes, an extended linear address record is written whenever the upper 16 bits change.
+
+use std::io::Write;
+
+const BYTES_PER_RECORD: usize = 16;
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+const START_LINEAR_ADDRESS: u8 = 0x05;
+
+fn write_record<W: Write>(w: &mut W, kind: u8, addr: u16, data: &[u8]) -> std::io::Result<()> {
+    let mut record = vec![data.len() as u8];
+    record.extend_from_slice(&addr.to_be_bytes());
+    record.push(kind);
+    record.extend_from_slice(data);
+
+    let checksum = record.iter().fold(0u8, |acc, x| acc.wrapping_add(*x)).wrapping_neg();
+    record.push(checksum);
+
+    let text: String = record.iter().map(|x| format!("{:02X}", x)).collect();
+    writeln!(w, ":{}", text)
+}
+
+/// Write bytes loaded at addr, with entry as start address
+pub fn write_hex<W: Write>(bytes: &[u8], addr: u32, entry: u32, w: &mut W) -> std::io::Result<()> {
+    let mut upper = None;
+    let mut offset = 0;
+
+    while offset < bytes.len() {
+        let at = addr.wrapping_add(offset as u32);
+        if upper != Some(at >> 16) {
+            upper = Some(at >> 16);
+            write_record(w, EXTENDED_LINEAR_ADDRESS, 0, &((at >> 16) as u16).to_be_bytes())?;
+        }
+
+        // Records don't cross a 64KiB boundary
+        let to_boundary = 0x10000 - (at & 0xFFFF) as usize;
+        let len = BYTES_PER_RECORD.min(bytes.len() - offset).min(to_boundary);
+        write_record(w, DATA, at as u16, &bytes[offset..offset + len])?;
+        offset += len;
+    }
+
+    write_record(w, START_LINEAR_ADDRESS, 0, &entry.to_be_bytes())?;
+    write_record(w, END_OF_FILE, 0, &[])
+}
@@ -0,0 +1,55 @@
+//! Assembler for the VIA C3 Alternative Instruction Set (AIS).
+//!
+//! [`ais`](mod@ais) encodes and decodes single instructions, [`constant`] finds the shortest way to load a value.
+//! [`dynasm`] generates whole payloads from Rust code, with symbols, sections and position independent code.
+//! The [`ais!`] macro from [`dsl`] writes the assembly for it inline.
+//! The other modules write the resulting [`Image`] in different formats, or parse assembly source text into a
+//! [`DynAsm`].
+//!
+//! Without the default `std` feature the crate is `no_std`. The `alloc` feature keeps the encoder,
+//! DynAsm and the parser, only the file writers and the command line tool need `std`. The reloc module
+//! only depends on `core` and is always available.
+//!
+//! ```no_run
+//! use ais_asm::{DynAsm, Instruction, Opcode};
+//!
+//! let mut asm = DynAsm::new(0x480000);
+//! asm.gen_header()?;
+//! asm.gen(Instruction::i_type(Opcode::ORI, "EAX".into(), "R0".into(), 0x1234))?;
+//! asm.gen_footer()?;
+//! let image = asm.finish()?;
+//! # Ok::<(), ais_asm::DynAsmError>(())
+//! ```
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+#[cfg(feature = "alloc")]
+pub mod ais;
+#[cfg(feature = "alloc")]
+pub mod constant;
+#[cfg(feature = "alloc")]
+pub mod dsl;
+#[cfg(feature = "alloc")]
+pub mod dynasm;
+#[cfg(feature = "std")]
+pub mod elf;
+#[cfg(feature = "std")]
+pub mod hex;
+#[cfg(feature = "std")]
+pub mod listing;
+#[cfg(feature = "alloc")]
+pub mod parse;
+pub mod reloc;
+
+#[cfg(feature = "alloc")]
+pub use crate::ais::{AisError, Cond, DpCntl, Instruction, Opcode, Recovery, Register, SubOpXalu, SubOpXio, SubOpXls};
+#[cfg(feature = "alloc")]
+pub use crate::dynasm::{DynAsm, DynAsmError, Image, Structured, Sym, SymExpr};
+#[cfg(feature = "std")]
+pub use crate::elf::ElfError;
+#[cfg(feature = "alloc")]
+pub use crate::parse::ParseError;
+pub use crate::reloc::RelocError;
@@ -0,0 +1,247 @@
+//! Annotated listing of an image, one row per instruction:
+//! address, raw bytes, AIS view, literal x86 view, label that starts there, generator source location and comment.
+
+use crate::ais::{Function, Instruction, Recovery};
+use crate::dynasm::{Image, LineKind, FOOTER, FOOTER_X86, HEADER, HEADER_X86};
+
+use std::io::Write;
+
+// Data is split over multiple rows, with the same width as an AIS instruction
+const BYTES_PER_ROW: usize = 6;
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02x}", x)).collect::<Vec<_>>().join(" ")
+}
+
+// The way a x86 CPU sees the AIS wrapper, like objdump shows it
+fn x86_view(bytes: &[u8]) -> String {
+    match bytes {
+        [0x62, 0x80, d0, d1, d2, d3] => {
+            let disp = i32::from_le_bytes([*d0, *d1, *d2, *d3]);
+            let sign = if disp < 0 { '-' } else { '+' };
+            format!("bound eax,QWORD PTR [eax{}0x{:x}]", sign, disp.unsigned_abs())
+        }
+        _ => "(bad)".to_string(),
+    }
+}
+
+fn ais_view(bytes: &[u8]) -> String {
+    match Instruction::decode(bytes) {
+        Ok((instr, _)) => instr.to_string(),
+        Err(_) => "(bad)".to_string(),
+    }
+}
+
+fn data_view(bytes: &[u8]) -> String {
+    format!(
+        ".byte {}",
+        bytes.iter().map(|x| format!("0x{:02X}", x)).collect::<Vec<_>>().join(", ")
+    )
+}
+
+struct Row<'a> {
+    addr: u32,
+    bytes: &'a [u8],
+    ais: String,
+    x86: String,
+}
+
+/// Write the annotated listing of an image
+pub fn write_listing<W: Write>(image: &Image, w: &mut W) -> std::io::Result<()> {
+    writeln!(
+        w,
+        "{:<8}  {:<17}  {:<32}  {:<40}  {:<16}  {:<20}  Comment",
+        "Address", "Bytes", "AIS", "x86", "Label", "Source"
+    )?;
+
+    for line in image.lines() {
+        let bytes = image.bytes_at(line.addr, line.len);
+
+        // Split x86 parts in their instructions
+        let split = |parts: &[(usize, &str)]| {
+            let mut offset = 0;
+            parts
+                .iter()
+                .map(|(len, x86)| {
+                    let row = Row {
+                        addr: line.addr + offset as u32,
+                        bytes: &bytes[offset..offset + len],
+                        ais: String::new(),
+                        x86: x86.to_string(),
+                    };
+                    offset += len;
+                    row
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let rows = match line.kind {
+            LineKind::Header => split(HEADER_X86),
+            LineKind::Footer => split(FOOTER_X86),
+            LineKind::Instruction => vec![Row {
+                addr: line.addr,
+                bytes,
+                ais: ais_view(bytes),
+                x86: x86_view(bytes),
+            }],
+            LineKind::Data => bytes
+                .chunks(BYTES_PER_ROW)
+                .enumerate()
+                .map(|(i, x)| Row {
+                    addr: line.addr + (i * BYTES_PER_ROW) as u32,
+                    bytes: x,
+                    ais: data_view(x),
+                    x86: String::new(),
+                })
+                .collect(),
+            LineKind::Zeros => vec![Row {
+                addr: line.addr,
+                bytes: &[],
+                ais: format!(".zero {}", line.len),
+                x86: String::new(),
+            }],
+        };
+
+        for (i, row) in rows.iter().enumerate() {
+            let labels: Vec<&str> = image
+                .symbol_table()
+                .iter()
+                .filter(|x| x.addr == row.addr && i == 0)
+                .map(|x| x.name.as_str())
+                .collect();
+            let comment = match (&line.comment, i) {
+                (Some(comment), 0) => format!("; {}", comment),
+                _ => String::new(),
+            };
+            let source = if i == 0 { line.source.to_string() } else { String::new() };
+
+            let text = format!(
+                "{:08X}  {:<17}  {:<32}  {:<40}  {:<16}  {:<20}  {}",
+                row.addr,
+                hex_bytes(row.bytes),
+                row.ais,
+                row.x86,
+                labels.join(","),
+                source,
+                comment
+            );
+            writeln!(w, "{}", text.trim_end())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Disassemble a raw payload without any symbol information, for payloads that were not generated in this process.
+/// Known x86 header and footer sequences are shown as x86, every AIS wrapper as AIS and everything else as data.
+pub fn write_disasm<W: Write>(bytes: &[u8], base: u32, w: &mut W) -> std::io::Result<()> {
+    writeln!(w, "{:<8}  {:<17}  {:<32}  x86", "Address", "Bytes", "AIS")?;
+
+    // The instruction iterator finds the AIS instructions, everything in between is a gap
+    let mut rows = Vec::new();
+    let mut gap_start = 0;
+    for (offset, result) in Instruction::iter(bytes, Recovery::SkipToWrapper) {
+        if let Ok(instr) = result {
+            gap_rows(&bytes[gap_start..offset], base.wrapping_add(gap_start as u32), &mut rows);
+            let instr_bytes = &bytes[offset..offset + 6];
+            rows.push(Row {
+                addr: base.wrapping_add(offset as u32),
+                bytes: instr_bytes,
+                ais: instr.to_string(),
+                x86: x86_view(instr_bytes),
+            });
+            gap_start = offset + 6;
+        }
+    }
+    gap_rows(&bytes[gap_start..], base.wrapping_add(gap_start as u32), &mut rows);
+
+    for row in rows {
+        let text = format!("{:08X}  {:<17}  {:<32}  {}", row.addr, hex_bytes(row.bytes), row.ais, row.x86);
+        writeln!(w, "{}", text.trim_end())?;
+    }
+
+    Ok(())
+}
+
+// Rows for bytes that are not a valid AIS instruction: the known x86 header and footer, wrappers that
+// don't decode, and data.
+fn gap_rows<'a>(bytes: &'a [u8], base: u32, rows: &mut Vec<Row<'a>>) {
+    let is_wrapper = |x: &[u8]| x.len() >= 6 && x[0..2] == [0x62, 0x80];
+    let is_known = |x: &[u8]| x.starts_with(HEADER) || x.starts_with(FOOTER) || is_wrapper(x);
+
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let rest = &bytes[offset..];
+        let addr = base.wrapping_add(offset as u32);
+        let count = rows.len();
+
+        for (sequence, parts) in [(HEADER, HEADER_X86), (FOOTER, FOOTER_X86)] {
+            if rows.len() == count && rest.starts_with(sequence) {
+                let mut at = 0;
+                for (len, x86) in parts {
+                    rows.push(Row {
+                        addr: addr + at as u32,
+                        bytes: &rest[at..at + len],
+                        ais: String::new(),
+                        x86: x86.to_string(),
+                    });
+                    at += len;
+                }
+            }
+        }
+
+        if rows.len() == count && is_wrapper(rest) {
+            rows.push(Row {
+                addr,
+                bytes: &rest[..6],
+                ais: ais_view(&rest[..6]),
+                x86: x86_view(&rest[..6]),
+            });
+        }
+
+        // Group unknown bytes, up to the next thing that is recognized
+        if rows.len() == count {
+            let len = (1..BYTES_PER_ROW.min(rest.len()))
+                .find(|x| is_known(&rest[*x..]))
+                .unwrap_or(BYTES_PER_ROW.min(rest.len()));
+            rows.push(Row {
+                addr,
+                bytes: &rest[..len],
+                ais: data_view(&rest[..len]),
+                x86: String::new(),
+            });
+        }
+
+        offset += rows[count..].iter().map(|x| x.bytes.len()).sum::<usize>();
+    }
+}
+
+/// Field by field breakdown of one decoded AIS instruction
+pub fn write_explain<W: Write>(instr: &Instruction, bytes: &[u8], w: &mut W) -> std::io::Result<()> {
+    let word = u32::from_le_bytes([bytes[2], bytes[3], bytes[4], bytes[5]]);
+
+    writeln!(w, "{}  {}", hex_bytes(bytes), instr)?;
+    writeln!(w, "  {:<9} {}", "x86", x86_view(bytes))?;
+    writeln!(w, "  {:<9} 0x{:08X}", "word", word)?;
+    writeln!(w, "  {:<9} 0o{:02o} {:?}", "opcode", word >> 26, instr.opcode)?;
+
+    let fields = [("rs", &instr.rs, 21), ("rt", &instr.rt, 16), ("rd", &instr.rd, 11)];
+    for (name, reg, shift) in fields {
+        if let Some(reg) = reg {
+            writeln!(w, "  {:<9} {:<2} {}", name, (word >> shift) & 0x1F, reg)?;
+        }
+    }
+    if let Some(imm) = instr.imm {
+        writeln!(w, "  {:<9} 0x{:04X} {}", "imm", imm, imm as i16)?;
+    }
+    match instr.function {
+        Some(Function::Xalu(sub_op, dp_cntl)) => {
+            writeln!(w, "  {:<9} 0o{:02o} {:?}", "sub op", word & 0x1F, sub_op)?;
+            writeln!(w, "  {:<9} {:?}", "dp cntl", dp_cntl)?;
+        }
+        Some(function) => writeln!(w, "  {:<9} {:?}", "function", function)?,
+        None => (),
+    }
+
+    Ok(())
+}
@@ -1,17 +1,95 @@
-mod ais;
-mod dynasm;
+//! Command line interface on top of the ais_asm library
 
-use crate::ais::{DpCntl, Instruction, SubOpXalu};
-use crate::dynasm::{DynAsm, DynAsmError, Sym};
+use ais_asm::{elf, hex, listing, parse};
+use ais_asm::{AisError, DynAsm, DynAsmError, ElfError, Image, Instruction, ParseError, Recovery, Register};
 
 use std::fs::File;
 use std::io::Write;
-use std::process::Command;
+use std::process::ExitCode;
 
+const USAGE: &str = "\
+Usage: ais_asm <command> [options]
+
+Commands:
+  assemble <src> [-o <out>] [--base <addr> | --pic <reg>] [--format bin|elf|hex]
+           [--map <file>] [--rel <file>] [--listing <file>] [-O]
+                       Assemble a source file, the output defaults to <src> with the extension of the format.
+                       --format elf needs --pic. -O runs the peephole optimiser and reports what it saved
+  disasm <bin> [--base <addr>]
+                       Disassemble a raw payload
+  dump <src> [--base <addr> | --pic <reg>] [-O]
+                       Assemble a source file and show the annotated listing
+  explain <bytes>...   Decode AIS instructions given as hex bytes, e.g. `62 80 0b 00 12 34`
+
+Exit codes: 0 success, 1 invalid input, 2 invalid usage, 3 file error";
+
+const EXIT_INPUT: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+const EXIT_FILE: u8 = 3;
+
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug)]
 enum TopError {
+    UsageError(String),
+    ParseError(String, Box<ParseError>),
     DynAsmError(DynAsmError),
+    // Offset of the instruction that failed to decode
+    DecodeError(usize, AisError),
+    ElfError(ElfError),
     IoError(std::io::Error),
+    FileError(String, std::io::Error),
+}
+
+impl TopError {
+    fn exit_code(&self) -> u8 {
+        match self {
+            Self::UsageError(_) => EXIT_USAGE,
+            Self::ParseError(..) | Self::DynAsmError(_) | Self::DecodeError(..) | Self::ElfError(_) => EXIT_INPUT,
+            Self::IoError(_) | Self::FileError(..) => EXIT_FILE,
+        }
+    }
+
+    // Message with the chain of causes, usage errors also show the usage
+    fn report(&self) {
+        eprintln!("error: {}", self);
+
+        let mut source = std::error::Error::source(self);
+        while let Some(x) = source {
+            eprintln!("  caused by: {}", x);
+            source = x.source();
+        }
+
+        if let Self::UsageError(_) = self {
+            eprintln!("\n{}", USAGE);
+        }
+    }
+}
+
+impl std::fmt::Display for TopError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::UsageError(x) => write!(f, "{}", x),
+            Self::ParseError(path, x) => write!(f, "{}:{}: {}", path, x.line, x.kind),
+            Self::DynAsmError(x) => write!(f, "{}", x),
+            Self::DecodeError(offset, _) => write!(f, "can't decode instruction at offset {}", offset),
+            Self::ElfError(x) => write!(f, "{}", x),
+            Self::IoError(x) => write!(f, "{}", x),
+            Self::FileError(path, x) => write!(f, "{}: {}", path, x),
+        }
+    }
+}
+
+impl std::error::Error for TopError {
+    // Wrapped errors are part of the message, their causes are not
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::ParseError(_, x) => x.source(),
+            Self::DynAsmError(x) => x.source(),
+            Self::DecodeError(_, x) => Some(x),
+            Self::ElfError(x) => x.source(),
+            Self::UsageError(_) | Self::IoError(_) | Self::FileError(..) => None,
+        }
+    }
 }
 
 impl From<DynAsmError> for TopError {
@@ -20,97 +98,270 @@ impl From<DynAsmError> for TopError {
     }
 }
 
+impl From<ElfError> for TopError {
+    fn from(x: ElfError) -> Self {
+        Self::ElfError(x)
+    }
+}
+
 impl From<std::io::Error> for TopError {
     fn from(x: std::io::Error) -> Self {
         Self::IoError(x)
     }
 }
 
-fn main() -> Result<(), TopError> {
-    // Gen some code, at location 0x480000, this is where our kernel will place the payload
-    let mut asm = DynAsm::new(0x480000);
+fn usage(message: &str) -> TopError {
+    TopError::UsageError(message.to_string())
+}
 
-    // Add x86 to AIS transition header
-    asm.gen_header();
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Format {
+    Bin,
+    Elf,
+    Hex,
+}
 
-    // Clear result register
-    asm.gen_load("EAX".into(), 0x0)?;
+impl Format {
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Bin => "bin",
+            Format::Elf => "o",
+            Format::Hex => "hex",
+        }
+    }
+}
 
-    // Define pseudo call and return. Return value is place in a register instead of the stack
-    fn pseudo_call(asm: &mut DynAsm, function: Sym) -> Result<(), TopError> {
-        // forward declare return label
-        let ret = asm.new_sym();
-        // Load return register
-        asm.gen_load_symbol("EBX".into(), ret)?;
-        // Jump to the function
-        asm.gen_jump(function)?;
-        // Resolve retunr label to be just after the jump
-        asm.set_sym_here(ret)?;
-        Ok(())
+#[derive(Default)]
+struct Options {
+    inputs: Vec<String>,
+    output: Option<String>,
+    base: Option<u32>,
+    pic: Option<Register>,
+    format: Option<Format>,
+    map: Option<String>,
+    rel: Option<String>,
+    listing: Option<String>,
+    optimize: bool,
+}
+
+// Split arguments in positional inputs and options, only the given options are accepted. -O is the only flag.
+fn parse_options(args: &[String], allowed: &[&str]) -> Result<Options, TopError> {
+    let mut options = Options::default();
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        if !arg.starts_with('-') || arg == "-" {
+            options.inputs.push(arg.clone());
+            continue;
+        }
+        if !allowed.contains(&arg.as_str()) {
+            return Err(usage(&format!("unknown option `{}`", arg)));
+        }
+        if arg == "-O" {
+            options.optimize = true;
+            continue;
+        }
+
+        let value = args
+            .next()
+            .ok_or_else(|| usage(&format!("option `{}` needs a value", arg)))?
+            .clone();
+        match arg.as_str() {
+            "-o" => options.output = Some(value),
+            "--base" => {
+                let base = parse::parse_number(&value)
+                    .and_then(|x| u32::try_from(x).ok())
+                    .ok_or_else(|| usage(&format!("invalid base address `{}`", value)))?;
+                options.base = Some(base);
+            }
+            "--pic" => {
+                let reg = Register::from(value.to_ascii_uppercase().as_str());
+                reg.bits().map_err(|_| usage(&format!("invalid register `{}`", value)))?;
+                options.pic = Some(reg);
+            }
+            "--format" => {
+                let format = match value.as_str() {
+                    "bin" => Format::Bin,
+                    "elf" => Format::Elf,
+                    "hex" => Format::Hex,
+                    _ => return Err(usage(&format!("unknown format `{}`", value))),
+                };
+                options.format = Some(format);
+            }
+            "--map" => options.map = Some(value),
+            "--rel" => options.rel = Some(value),
+            "--listing" => options.listing = Some(value),
+            _ => unreachable!(),
+        }
+    }
+
+    Ok(options)
+}
+
+fn single_input(options: &Options) -> Result<&str, TopError> {
+    match options.inputs.as_slice() {
+        [x] => Ok(x),
+        [] => Err(usage("missing input file")),
+        _ => Err(usage("too many input files")),
+    }
+}
+
+fn create(path: &str) -> Result<File, TopError> {
+    File::create(path).map_err(|x| TopError::FileError(path.to_string(), x))
+}
+
+// Map write errors to the file that is written, and don't leave a partial file behind
+fn write_file<F>(path: &str, f: F) -> Result<(), TopError>
+where
+    F: FnOnce(&mut File) -> Result<(), TopError>,
+{
+    let mut file = create(path)?;
+    f(&mut file).map_err(|x| {
+        let _ = std::fs::remove_file(path);
+        match x {
+            TopError::IoError(x) => TopError::FileError(path.to_string(), x),
+            x => x,
+        }
+    })
+}
+
+// Read and assemble a source file
+fn assemble_file(path: &str, options: &Options) -> Result<Image, TopError> {
+    let source = std::fs::read_to_string(path).map_err(|x| TopError::FileError(path.to_string(), x))?;
+
+    let mut asm = match (&options.pic, options.base) {
+        (Some(_), Some(_)) => return Err(usage("--base and --pic can't be combined")),
+        (Some(reg), None) => DynAsm::new_pic(reg.clone())?,
+        (None, base) => DynAsm::new(base.unwrap_or(0)),
+    };
+    // Only keep absolute addresses at full size when something can still move the image
+    asm.set_relocatable(options.rel.is_some() || options.format == Some(Format::Elf));
+
+    // The file name is kept by every line of the image, for the rest of the process
+    let file: &'static str = Box::leak(path.to_string().into_boxed_str());
+    parse::parse_into(&mut asm, &source, file).map_err(|x| TopError::ParseError(path.to_string(), Box::new(x)))?;
+    if options.optimize {
+        eprintln!("{}: {}", path, asm.optimize());
+    }
+    Ok(asm.finish()?)
+}
+
+fn cmd_assemble(args: &[String]) -> Result<(), TopError> {
+    let options = parse_options(
+        args,
+        &["-o", "--base", "--pic", "--format", "--map", "--rel", "--listing", "-O"],
+    )?;
+    let input = single_input(&options)?;
+    let format = options.format.unwrap_or(Format::Bin);
+    // i386 has no relocation for the halves of an address load, only position independent code fits in an object
+    if format == Format::Elf && options.pic.is_none() {
+        return Err(usage("--format elf needs --pic"));
     }
+    let image = assemble_file(input, &options)?;
 
-    fn pseudo_ret(asm: &mut DynAsm) -> Result<(), TopError> {
-        // Jump to the return register
-        asm.gen(Instruction::xj("EBX".into()))?;
+    let output = match &options.output {
+        Some(x) => x.clone(),
+        None => std::path::Path::new(input)
+            .with_extension(format.extension())
+            .to_string_lossy()
+            .into_owned(),
+    };
+    if output == input {
+        return Err(usage("output would overwrite the input, use -o"));
+    }
+
+    write_file(&output, |w| {
+        match format {
+            Format::Bin => w.write_all(image.bytes())?,
+            Format::Elf => elf::write_elf(&image, w)?,
+            Format::Hex => hex::write_hex(image.bytes(), image.base(), image.base(), w)?,
+        }
         Ok(())
+    })?;
+
+    if let Some(path) = &options.map {
+        write_file(path, |w| Ok(image.write_map(w)?))?;
+    }
+    if let Some(path) = &options.rel {
+        write_file(path, |w| Ok(image.write_relocs(w)?))?;
+    }
+    if let Some(path) = &options.listing {
+        write_file(path, |w| Ok(listing::write_listing(&image, w)?))?;
+    }
+
+    Ok(())
+}
+
+fn cmd_disasm(args: &[String]) -> Result<(), TopError> {
+    let options = parse_options(args, &["--base"])?;
+    let input = single_input(&options)?;
+    let bytes = std::fs::read(input).map_err(|x| TopError::FileError(input.to_string(), x))?;
+
+    listing::write_disasm(&bytes, options.base.unwrap_or(0), &mut std::io::stdout().lock())?;
+    Ok(())
+}
+
+fn cmd_dump(args: &[String]) -> Result<(), TopError> {
+    let options = parse_options(args, &["--base", "--pic", "-O"])?;
+    let input = single_input(&options)?;
+    let image = assemble_file(input, &options)?;
+
+    listing::write_listing(&image, &mut std::io::stdout().lock())?;
+    Ok(())
+}
+
+fn cmd_explain(args: &[String]) -> Result<(), TopError> {
+    if args.is_empty() {
+        return Err(usage("missing bytes"));
     }
 
-    // Forward declare push function
-    let push = asm.new_sym();
-
-    // Push some bytes
-    asm.gen_load("EDX".into(), 0xB)?;
-    pseudo_call(&mut asm, push)?;
-    asm.gen_load("EDX".into(), 0xA)?;
-    pseudo_call(&mut asm, push)?;
-    asm.gen_load("EDX".into(), 0xD)?;
-    pseudo_call(&mut asm, push)?;
-    asm.gen_load("EDX".into(), 0xC)?;
-    pseudo_call(&mut asm, push)?;
-    asm.gen_load("EDX".into(), 0x0)?;
-    pseudo_call(&mut asm, push)?;
-    asm.gen_load("EDX".into(), 0xD)?;
-    pseudo_call(&mut asm, push)?;
-    asm.gen_load("EDX".into(), 0xE)?;
-    pseudo_call(&mut asm, push)?;
-
-    // Done jump to the end
-    let end = asm.new_sym();
-    asm.gen_jump(end)?;
-
-    // Function that will push a byte in the result
-    // EAX = EAX << 8 | EDX
-    asm.set_sym_here(push)?;
-    asm.gen_load("R4".into(), 4)?;
-    asm.gen(Instruction::xalur(SubOpXalu::SHL, DpCntl::Word, "EAX".into(), "EAX".into(), "R4".into()))?;
-    asm.gen(Instruction::xalur(
-        SubOpXalu::OR,
-        DpCntl::Word,
-        "EAX".into(),
-        "EAX".into(),
-        "EDX".into(),
-    ))?;
-    pseudo_ret(&mut asm)?;
-
-    // The end is here
-    asm.set_sym_here(end)?;
-
-    // Append footer and we are done. This is just a return, so it will return from the payload back into the kernel
-    asm.gen_footer();
-
-    // Show dynamic assembled instructions
-    asm.dump();
-
-    // Write payload to out.bin, the kernel will included this as the payload
-    let mut output = File::create("out.bin")?;
-    output.by_ref().write_all(asm.memory())?;
-    output.flush()?;
-
-    // Show generated disassembly in regular x86 instructions.
-    let output = Command::new("objdump")
-        .args(["-D", "-bbinary", "-mi386", "-Mintel", "out.bin"])
-        .output()?;
-    println!("{}", std::str::from_utf8(&output.stdout).unwrap());
+    // Accept `62 80 ..`, `628000..`, `0x62,0x80` and mixes of those
+    let text: String = args
+        .join(" ")
+        .split([' ', ','])
+        .map(|x| x.trim_start_matches("0x").trim_start_matches("0X"))
+        .collect();
+    if !text.len().is_multiple_of(2) || !text.chars().all(|x| x.is_ascii_hexdigit()) {
+        return Err(usage("bytes must be given as pairs of hex digits"));
+    }
+    let bytes: Vec<u8> = (0..text.len())
+        .step_by(2)
+        .map(|x| u8::from_str_radix(&text[x..x + 2], 16).unwrap())
+        .collect();
+
+    let mut stdout = std::io::stdout().lock();
+    for (offset, result) in Instruction::iter(&bytes, Recovery::Stop) {
+        let instr = result.map_err(|x| TopError::DecodeError(offset, x))?;
+        listing::write_explain(&instr, &bytes[offset..offset + 6], &mut stdout)?;
+    }
 
     Ok(())
 }
+
+fn run(args: &[String]) -> Result<(), TopError> {
+    let (command, args) = args.split_first().ok_or_else(|| usage("missing command"))?;
+    match command.as_str() {
+        "assemble" => cmd_assemble(args),
+        "disasm" => cmd_disasm(args),
+        "dump" => cmd_dump(args),
+        "explain" => cmd_explain(args),
+        "help" | "-h" | "--help" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        x => Err(usage(&format!("unknown command `{}`", x))),
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        // Output was piped into something like head, that is fine
+        Err(TopError::IoError(e)) if e.kind() == std::io::ErrorKind::BrokenPipe => ExitCode::SUCCESS,
+        Err(e) => {
+            e.report();
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
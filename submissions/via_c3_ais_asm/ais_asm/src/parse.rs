@@ -0,0 +1,478 @@
+//! Text front end for DynAsm, one statement per line:
+//!
+//! ```text
+//!   label:                      define label at the current location
+//!   ORI EAX, R0, 0x000B         AIS instruction, same syntax as the listing
+//!   ORIU EAX, EAX, %hi(table)   immediate from part of an address, also %lo() and %hiadj()
+//!   XALUR.SHL EAX, EAX, ECX     XALU instructions take the sub operation and optional DpCntl as suffix
+//!   XALUR.SETCC EAX, R0, NE     SETCC takes an x86 condition instead of the last register
+//!   XIOW.Bits8 EAX, [EDX+0]     port IO, value then port
+//!   XL.Bits32 EAX, [ESP+0]      memory load and XS store, value then address
+//!   load EAX, table+4           load a 32bit value or address expression
+//!   jump label                  jump via a scratch register
+//!   .header / .footer           x86 to AIS transition and return to x86
+//!   .text .rodata .data .bss    switch to a standard section, or `.section name` for any other
+//!   .byte .short .long          data, .long also takes address expressions
+//!   .zero n / .align n          zero fill
+//!   .scratch R4, R6             scratch registers for helpers, `.live` and `.dead` mark user registers
+//! ```
+//!
+//! Everything after a `;` is a comment, and is attached to the generated line.
+
+use crate::ais::{Cond, Const, DpCntl, Instruction, Opcode, Register, Size, SubOpXalu};
+use crate::dynasm::{DynAsm, DynAsmError, SourceLoc, Sym, SymExpr, SymRefKind};
+
+use num_traits::FromPrimitive;
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+
+/// What went wrong on a source line
+#[derive(Debug)]
+pub enum ParseErrorKind {
+    UnknownMnemonic(String),
+    UnknownDirective(String),
+    Unsupported(String),
+    InvalidOperand(String),
+    OperandCount(usize),
+    OutOfRange(i64),
+    DynAsmError(DynAsmError),
+}
+
+/// Error in a source file, with the line it was found on
+#[derive(Debug)]
+pub struct ParseError {
+    // 1 based line number in the source
+    pub line: usize,
+    pub kind: ParseErrorKind,
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseErrorKind::UnknownMnemonic(x) => write!(f, "unknown mnemonic `{}`", x),
+            ParseErrorKind::UnknownDirective(x) => write!(f, "unknown directive `{}`", x),
+            ParseErrorKind::Unsupported(x) => write!(f, "`{}` is not supported", x),
+            ParseErrorKind::InvalidOperand(x) => write!(f, "invalid operand `{}`", x),
+            ParseErrorKind::OperandCount(x) => write!(f, "expected {} operands", x),
+            ParseErrorKind::OutOfRange(x) => write!(f, "value {} is out of range", x),
+            ParseErrorKind::DynAsmError(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.kind)
+    }
+}
+
+impl core::error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        // The DynAsm error is part of the message already, continue with its cause
+        match &self.kind {
+            ParseErrorKind::DynAsmError(x) => core::error::Error::source(x),
+            _ => None,
+        }
+    }
+}
+
+impl From<DynAsmError> for ParseErrorKind {
+    fn from(x: DynAsmError) -> Self {
+        Self::DynAsmError(x)
+    }
+}
+
+// Value of an operand, either known now or an address expression that is fixed up later
+enum Value {
+    Number(i64),
+    Expr(SymExpr),
+}
+
+/// Assemble source text into asm, call DynAsm::finish() afterwards to get the image.
+/// Generated lines record their location in file, instead of the location in this parser.
+pub fn parse_into(asm: &mut DynAsm, source: &str, file: &'static str) -> Result<(), ParseError> {
+    let mut result = Ok(());
+    for (i, text) in source.lines().enumerate() {
+        asm.set_source(Some(SourceLoc { file, line: i as u32 + 1 }));
+        result = parse_line(asm, text).map_err(|kind| ParseError { line: i + 1, kind });
+        if result.is_err() {
+            break;
+        }
+    }
+    asm.set_source(None);
+    result
+}
+
+fn parse_line(asm: &mut DynAsm, text: &str) -> Result<(), ParseErrorKind> {
+    let (code, comment) = match text.split_once(';') {
+        Some((code, comment)) => (code, Some(comment.trim())),
+        None => (text, None),
+    };
+
+    // Labels, possibly followed by a statement on the same line
+    let mut code = code.trim();
+    while let Some((label, rest)) = code.split_once(':') {
+        let label = label.trim();
+        if !is_ident(label) {
+            break;
+        }
+        let sym = sym_by_name(asm, label)?;
+        asm.set_sym_here(sym)?;
+        code = rest.trim();
+    }
+
+    if code.is_empty() {
+        return Ok(());
+    }
+
+    if let Some(comment) = comment.filter(|x| !x.is_empty()) {
+        asm.comment(comment);
+    }
+
+    let (mnemonic, operands) = match code.split_once(char::is_whitespace) {
+        Some((mnemonic, operands)) => (mnemonic, split_operands(operands)),
+        None => (code, Vec::new()),
+    };
+
+    if mnemonic.starts_with('.') {
+        parse_directive(asm, mnemonic, &operands)
+    } else {
+        parse_instruction(asm, mnemonic, &operands)
+    }
+}
+
+fn split_operands(text: &str) -> Vec<&str> {
+    text.split(',').map(|x| x.trim()).collect()
+}
+
+fn is_ident(text: &str) -> bool {
+    let mut chars = text.chars();
+    matches!(chars.next(), Some(x) if x.is_ascii_alphabetic() || x == '_' || x == '.')
+        && chars.all(|x| x.is_ascii_alphanumeric() || x == '_' || x == '.')
+}
+
+fn expect_count(operands: &[&str], count: usize) -> Result<(), ParseErrorKind> {
+    if operands.len() != count {
+        return Err(ParseErrorKind::OperandCount(count));
+    }
+    Ok(())
+}
+
+fn sym_by_name(asm: &mut DynAsm, name: &str) -> Result<Sym, ParseErrorKind> {
+    match asm.sym_by_name(name) {
+        Some(sym) => Ok(sym),
+        None => Ok(asm.new_named_sym(name)?),
+    }
+}
+
+/// Number in decimal, or hex and binary with 0x and 0b prefix, optionally negative
+pub fn parse_number(text: &str) -> Option<i64> {
+    let (negative, text) = match text.strip_prefix('-') {
+        Some(x) => (true, x),
+        None => (false, text),
+    };
+
+    let value = if let Some(x) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        i64::from_str_radix(x, 16).ok()?
+    } else if let Some(x) = text.strip_prefix("0b").or_else(|| text.strip_prefix("0B")) {
+        i64::from_str_radix(x, 2).ok()?
+    } else {
+        text.parse().ok()?
+    };
+
+    Some(if negative { -value } else { value })
+}
+
+// Sum of numbers and symbols, with at most one added and one subtracted symbol, e.g. `end - start + 4`
+fn parse_value(asm: &mut DynAsm, text: &str) -> Result<Value, ParseErrorKind> {
+    let invalid = || ParseErrorKind::InvalidOperand(text.to_string());
+
+    let mut number = 0i64;
+    let mut add = None;
+    let mut sub = None;
+
+    let mut rest = text.trim();
+    let mut negative = false;
+    if let Some(x) = rest.strip_prefix('-') {
+        negative = true;
+        rest = x.trim_start();
+    }
+
+    loop {
+        let end = rest.find(['+', '-']).unwrap_or(rest.len());
+        let term = rest[..end].trim();
+
+        if let Some(x) = parse_number(term) {
+            let x = if negative { x.checked_neg() } else { Some(x) };
+            number = x.and_then(|x| number.checked_add(x)).ok_or(ParseErrorKind::OutOfRange(number))?;
+        } else if is_ident(term) {
+            let slot = if negative { &mut sub } else { &mut add };
+            if slot.is_some() {
+                return Err(invalid());
+            }
+            *slot = Some(sym_by_name(asm, term)?);
+        } else {
+            return Err(invalid());
+        }
+
+        if end == rest.len() {
+            break;
+        }
+        negative = rest[end..].starts_with('-');
+        rest = rest[end + 1..].trim_start();
+    }
+
+    let addend = i32::try_from(number).map_err(|_| ParseErrorKind::OutOfRange(number))?;
+    match (add, sub) {
+        (None, None) => Ok(Value::Number(number)),
+        (Some(add), None) => Ok(Value::Expr(SymExpr::from(add) + addend)),
+        (Some(add), Some(sub)) => Ok(Value::Expr((add - sub) + addend)),
+        (None, Some(_)) => Err(invalid()),
+    }
+}
+
+fn parse_register(text: &str) -> Result<Register, ParseErrorKind> {
+    let reg: Register = text.to_ascii_uppercase().as_str().into();
+    reg.bits().map_err(|_| ParseErrorKind::InvalidOperand(text.to_string()))?;
+    Ok(reg)
+}
+
+// Signed and unsigned 32bit numbers are accepted, both wrap to the same bits
+fn number_u32(x: i64) -> Result<u32, ParseErrorKind> {
+    if !(-0x8000_0000..=0xFFFF_FFFF).contains(&x) {
+        return Err(ParseErrorKind::OutOfRange(x));
+    }
+    Ok(x as u32)
+}
+
+fn parse_u32(asm: &mut DynAsm, text: &str) -> Result<u32, ParseErrorKind> {
+    match parse_value(asm, text)? {
+        Value::Number(x) => number_u32(x),
+        Value::Expr(_) => Err(ParseErrorKind::InvalidOperand(text.to_string())),
+    }
+}
+
+// Number for .byte and .short, signed or unsigned like number_u32 but only as wide as the directive
+fn parse_data(asm: &mut DynAsm, text: &str, bits: u32) -> Result<u32, ParseErrorKind> {
+    match parse_value(asm, text)? {
+        Value::Number(x) if (-(1 << (bits - 1))..1 << bits).contains(&x) => Ok(x as u32),
+        Value::Number(x) => Err(ParseErrorKind::OutOfRange(x)),
+        Value::Expr(_) => Err(ParseErrorKind::InvalidOperand(text.to_string())),
+    }
+}
+
+// Name lookup through the Debug names, the same names the listing shows
+fn by_name<T: FromPrimitive + core::fmt::Debug>(name: &str, count: u32) -> Option<T> {
+    (0..count)
+        .filter_map(T::from_u32)
+        .find(|x| format!("{:?}", x).eq_ignore_ascii_case(name))
+}
+
+fn parse_directive(asm: &mut DynAsm, directive: &str, operands: &[&str]) -> Result<(), ParseErrorKind> {
+    match directive {
+        ".header" => asm.gen_header()?,
+        ".footer" => asm.gen_footer()?,
+        ".text" | ".rodata" | ".data" | ".bss" => {
+            let section = asm.section_by_name(directive).ok_or_else(|| ParseErrorKind::Unsupported(directive.to_string()))?;
+            asm.set_section(section)?;
+        }
+        ".section" => {
+            expect_count(operands, 1)?;
+            let section = match asm.section_by_name(operands[0]) {
+                Some(section) => section,
+                None => asm.add_section(operands[0], 1, false)?,
+            };
+            asm.set_section(section)?;
+        }
+        ".byte" => {
+            for x in operands {
+                let value = parse_data(asm, x, 8)?;
+                asm.gen_u8(value as u8)?;
+            }
+        }
+        ".short" => {
+            for x in operands {
+                let value = parse_data(asm, x, 16)?;
+                asm.gen_u16(value as u16)?;
+            }
+        }
+        ".long" => {
+            for x in operands {
+                match parse_value(asm, x)? {
+                    Value::Expr(expr) => asm.gen_u32_expr(expr)?,
+                    Value::Number(x) => asm.gen_u32(number_u32(x)?)?,
+                }
+            }
+        }
+        ".zero" => {
+            expect_count(operands, 1)?;
+            let len = parse_u32(asm, operands[0])?;
+            asm.gen_zeros(len as usize)?;
+        }
+        ".align" => {
+            expect_count(operands, 1)?;
+            let align = parse_u32(asm, operands[0])?;
+            asm.gen_align(align)?;
+        }
+        ".scratch" => {
+            let regs = operands.iter().map(|x| parse_register(x)).collect::<Result<Vec<_>, _>>()?;
+            asm.set_scratch(&regs);
+        }
+        ".live" | ".dead" => {
+            for x in operands {
+                let reg = parse_register(x)?;
+                if directive == ".live" {
+                    asm.set_live(reg)?;
+                } else {
+                    asm.set_dead(reg)?;
+                }
+            }
+        }
+        _ => return Err(ParseErrorKind::UnknownDirective(directive.to_string())),
+    }
+
+    Ok(())
+}
+
+fn parse_instruction(asm: &mut DynAsm, mnemonic: &str, operands: &[&str]) -> Result<(), ParseErrorKind> {
+    // Pseudo instructions, these map to DynAsm helpers
+    if mnemonic.eq_ignore_ascii_case("load") {
+        expect_count(operands, 2)?;
+        let dst = parse_register(operands[0])?;
+        return match parse_value(asm, operands[1])? {
+            Value::Expr(expr) => Ok(asm.gen_load_expr(dst, expr)?),
+            Value::Number(x) => Ok(asm.gen_load(dst, number_u32(x)?)?),
+        };
+    }
+    if mnemonic.eq_ignore_ascii_case("jump") {
+        expect_count(operands, 1)?;
+        if !is_ident(operands[0]) {
+            return Err(ParseErrorKind::InvalidOperand(operands[0].to_string()));
+        }
+        let sym = sym_by_name(asm, operands[0])?;
+        return Ok(asm.gen_jump(sym)?);
+    }
+
+    let unknown = || ParseErrorKind::UnknownMnemonic(mnemonic.to_string());
+    let mut parts = mnemonic.split('.');
+    let opcode: Opcode = parts.next().and_then(|x| by_name(x, 64)).ok_or_else(unknown)?;
+    let suffixes: Vec<&str> = parts.collect();
+
+    let instruction = match opcode {
+        Opcode::ORIU
+        | Opcode::ADDI
+        | Opcode::ANDIU
+        | Opcode::ANDIL
+        | Opcode::ANDI
+        | Opcode::ORI
+        | Opcode::XORI
+        | Opcode::XORIU => {
+            expect_count(operands, 3)?;
+            if !suffixes.is_empty() {
+                return Err(unknown());
+            }
+            let dst = parse_register(operands[0])?;
+            let src = parse_register(operands[1])?;
+            return parse_immediate(asm, opcode, dst, src, operands[2]);
+        }
+        Opcode::XALU | Opcode::XALUR | Opcode::XALUI | Opcode::XALUIR => {
+            expect_count(operands, 3)?;
+            let sub_op: SubOpXalu = suffixes.first().and_then(|x| by_name(x, 32)).ok_or_else(unknown)?;
+            let dp_cntl = match suffixes.get(1) {
+                Some(x) => by_name(x, 8).ok_or_else(unknown)?,
+                None => DpCntl::Word,
+            };
+            if suffixes.len() > 2 {
+                return Err(unknown());
+            }
+
+            let dst = parse_register(operands[0])?;
+            let src = parse_register(operands[1])?;
+            let mut instruction = if matches!(opcode, Opcode::XALU | Opcode::XALUR) {
+                let extra = match (sub_op, by_name::<Cond>(operands[2], 16)) {
+                    (SubOpXalu::SETCC, Some(cond)) => Register::Index(cond as u8),
+                    _ => parse_register(operands[2])?,
+                };
+                Instruction::xalur(sub_op, dp_cntl, dst, src, extra)
+            } else {
+                let c = parse_u32(asm, operands[2])? as i32;
+                let range = |x: &i8| (-16..=15).contains(x);
+                let c = i8::try_from(c).ok().filter(range).ok_or(ParseErrorKind::OutOfRange(c.into()))?;
+                Instruction::xaluir(sub_op, dp_cntl, dst, src, Const::Number(c))
+            };
+            instruction.opcode = opcode;
+            instruction
+        }
+        Opcode::XJ => {
+            expect_count(operands, 1)?;
+            Instruction::xj(parse_register(operands[0])?)
+        }
+        Opcode::XIOR | Opcode::XIOW | Opcode::XL | Opcode::XS => {
+            expect_count(operands, 2)?;
+            let size: Size = match suffixes.as_slice() {
+                [x] => by_name(x, 8).ok_or_else(unknown)?,
+                _ => return Err(unknown()),
+            };
+            let value = parse_register(operands[0])?;
+            let base = parse_address(operands[1])?;
+            match opcode {
+                Opcode::XIOR => Instruction::xior(size, base, value),
+                Opcode::XIOW => Instruction::xiow(size, base, value),
+                Opcode::XL => Instruction::xl(size, base, value),
+                _ => Instruction::xs(size, base, value),
+            }
+        }
+        _ => return Err(ParseErrorKind::Unsupported(mnemonic.to_string())),
+    };
+
+    Ok(asm.gen(instruction)?)
+}
+
+// Address operand of port IO and memory access, `[EDX]` or `[EDX+0]`. Only a zero offset can be encoded.
+fn parse_address(text: &str) -> Result<Register, ParseErrorKind> {
+    let invalid = || ParseErrorKind::InvalidOperand(text.to_string());
+    let inner = text.strip_prefix('[').and_then(|x| x.strip_suffix(']')).ok_or_else(invalid)?;
+    let (base, offset) = match inner.find(['+', '-']) {
+        Some(at) => (&inner[..at], Some(inner[at..].trim_start_matches('+').trim())),
+        None => (inner, None),
+    };
+    if offset.is_some_and(|x| parse_number(x) != Some(0)) {
+        return Err(ParseErrorKind::Unsupported(text.to_string()));
+    }
+    parse_register(base.trim())
+}
+
+// Immediate of an I type instruction, a 16bit number or part of an address expression
+fn parse_immediate(
+    asm: &mut DynAsm,
+    opcode: Opcode,
+    dst: Register,
+    src: Register,
+    text: &str,
+) -> Result<(), ParseErrorKind> {
+    let parts = [
+        ("%hiadj(", SymRefKind::HighImmAdj),
+        ("%hi(", SymRefKind::HighImm),
+        ("%lo(", SymRefKind::LowImm),
+    ];
+    for (prefix, kind) in parts {
+        if let Some(inner) = text.strip_prefix(prefix).and_then(|x| x.strip_suffix(')')) {
+            let expr = match parse_value(asm, inner)? {
+                Value::Expr(expr) => expr,
+                Value::Number(_) => return Err(ParseErrorKind::InvalidOperand(text.to_string())),
+            };
+            return Ok(asm.gen_i_type_expr(opcode, dst, src, expr, kind)?);
+        }
+    }
+
+    match parse_value(asm, text)? {
+        Value::Number(x) if (-0x8000..=0xFFFF).contains(&x) => {
+            Ok(asm.gen(Instruction::i_type(opcode, dst, src, x as u16))?)
+        }
+        Value::Number(x) => Err(ParseErrorKind::OutOfRange(x)),
+        Value::Expr(_) => Err(ParseErrorKind::InvalidOperand(text.to_string())),
+    }
+}
@@ -0,0 +1,209 @@
+//! Relocation table that goes along with a payload, so the payload can be copied to any address and patched.
+//! This module only depends on core, so the kernel can use it to relocate a payload before calling it.
+//!
+//! Table layout, all fields little endian:
+//! ```text
+//!   header: magic "AISR", u32 link base, u32 entry count
+//!   entry:  u32 payload offset, u32 link time value, u8 kind, u8 target section, u8 field shift, u8 field width
+//! ```
+//!
+//! The full link time value is stored for every entry, so a half can be recomputed without its partner.
+//! Only the bits of the field are written, a width of 0 means the whole field of the kind.
+
+pub const MAGIC: [u8; 4] = *b"AISR";
+pub const HEADER_SIZE: usize = 12;
+pub const ENTRY_SIZE: usize = 12;
+
+/// Where and how a relocated value is written
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RelocKind {
+    // Immediate of an AIS instruction, bits 31..16 of the value
+    HighImm = 0,
+    // Immediate of an AIS instruction, bits 15..0 of the value
+    LowImm = 1,
+    // Immediate of an AIS instruction, bits 31..16 of the value, adjusted for a sign extended low half
+    HighImmAdj = 2,
+    // Little endian 32bit data word
+    Word = 3,
+}
+
+impl RelocKind {
+    /// Part of the value that is written
+    pub fn select(self, value: u32) -> u32 {
+        match self {
+            RelocKind::HighImm => value >> 16,
+            RelocKind::LowImm => value & 0xFFFF,
+            // The sign extended low half subtracts 0x10000 when bit 15 is set, compensate for that
+            RelocKind::HighImmAdj => value.wrapping_add(0x8000) >> 16,
+            RelocKind::Word => value,
+        }
+    }
+
+    /// Offset of the patched word from the reference, the AIS word is just after the 0x62 0x80 wrapper
+    pub fn word_offset(self) -> usize {
+        match self {
+            RelocKind::Word => 0,
+            _ => 2,
+        }
+    }
+
+    /// Field that is patched when no other field is given, the immediate or the whole data word
+    pub fn default_field(self) -> BitField {
+        match self {
+            RelocKind::Word => BitField::WORD,
+            _ => BitField::IMM,
+        }
+    }
+
+    fn from_u8(x: u8) -> Option<Self> {
+        match x {
+            0 => Some(Self::HighImm),
+            1 => Some(Self::LowImm),
+            2 => Some(Self::HighImmAdj),
+            3 => Some(Self::Word),
+            _ => None,
+        }
+    }
+}
+
+/// Bits of a little endian 32bit word that receive a value, the rest of the word is kept
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct BitField {
+    pub shift: u8,
+    pub width: u8,
+}
+
+impl BitField {
+    /// Immediate of an I type AIS instruction
+    pub const IMM: BitField = BitField { shift: 0, width: 16 };
+    /// Whole word
+    pub const WORD: BitField = BitField { shift: 0, width: 32 };
+
+    /// Field of width bits starting at bit shift, None if it doesn't fit in a word
+    pub const fn new(shift: u8, width: u8) -> Option<Self> {
+        if width == 0 || shift as u32 + width as u32 > 32 {
+            return None;
+        }
+        Some(Self { shift, width })
+    }
+
+    fn mask(self) -> u32 {
+        (u32::MAX >> (32 - self.width as u32)) << self.shift
+    }
+
+    /// Word with the field replaced by the low bits of value
+    pub fn insert(self, word: u32, value: u32) -> u32 {
+        (word & !self.mask()) | (value << self.shift & self.mask())
+    }
+}
+
+/// Error while parsing or applying a relocation table
+#[derive(Debug)]
+pub enum RelocError {
+    BadMagic,
+    Truncated,
+    UnknownKind(u8),
+    BadField(u8, u8),
+    OutOfBounds(u32),
+}
+
+impl core::fmt::Display for RelocError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            RelocError::BadMagic => write!(f, "relocation table doesn't start with AISR"),
+            RelocError::Truncated => write!(f, "relocation table is truncated"),
+            RelocError::UnknownKind(x) => write!(f, "unknown relocation kind {}", x),
+            RelocError::BadField(shift, width) => {
+                write!(f, "relocation field of {} bits at bit {} doesn't fit in a word", width, shift)
+            }
+            RelocError::OutOfBounds(x) => write!(f, "relocation at offset 0x{:X} is outside the payload", x),
+        }
+    }
+}
+
+impl core::error::Error for RelocError {}
+
+/// One absolute address in a payload
+#[derive(Debug, Copy, Clone)]
+pub struct RelocEntry {
+    pub offset: u32,
+    pub value: u32,
+    pub kind: RelocKind,
+    pub section: u8,
+    pub field: BitField,
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+/// Link base and entries of a relocation table
+pub fn parse(table: &[u8]) -> Result<(u32, impl Iterator<Item = Result<RelocEntry, RelocError>> + '_), RelocError> {
+    if table.len() < HEADER_SIZE {
+        return Err(RelocError::Truncated);
+    }
+    if table[0..4] != MAGIC {
+        return Err(RelocError::BadMagic);
+    }
+
+    let base = read_u32(table, 4);
+    let count = read_u32(table, 8) as usize;
+    let entries = &table[HEADER_SIZE..];
+    if entries.len() < count * ENTRY_SIZE {
+        return Err(RelocError::Truncated);
+    }
+
+    let iter = entries.chunks_exact(ENTRY_SIZE).take(count).map(|x| {
+        let kind = RelocKind::from_u8(x[8]).ok_or(RelocError::UnknownKind(x[8]))?;
+        let field = match x[11] {
+            0 => kind.default_field(),
+            width => BitField::new(x[10], width).ok_or(RelocError::BadField(x[10], width))?,
+        };
+        Ok(RelocEntry {
+            offset: read_u32(x, 0),
+            value: read_u32(x, 4),
+            kind,
+            section: x[9],
+            field,
+        })
+    });
+
+    Ok((base, iter))
+}
+
+/// Entry in the table format
+pub fn encode_entry(entry: &RelocEntry) -> [u8; ENTRY_SIZE] {
+    let mut bytes = [0; ENTRY_SIZE];
+    bytes[0..4].copy_from_slice(&entry.offset.to_le_bytes());
+    bytes[4..8].copy_from_slice(&entry.value.to_le_bytes());
+    bytes[8] = entry.kind as u8;
+    bytes[9] = entry.section;
+    bytes[10] = entry.field.shift;
+    bytes[11] = entry.field.width;
+    bytes
+}
+
+/// Patch a payload that was assembled for the link base in the table, so it runs at load_addr
+pub fn relocate(payload: &mut [u8], table: &[u8], load_addr: u32) -> Result<(), RelocError> {
+    let (base, entries) = parse(table)?;
+    let delta = load_addr.wrapping_sub(base);
+
+    for entry in entries {
+        let entry = entry?;
+        let value = entry.value.wrapping_add(delta);
+        patch(payload, entry.offset as usize, entry.kind, entry.field, value)
+            .ok_or(RelocError::OutOfBounds(entry.offset))?;
+    }
+
+    Ok(())
+}
+
+/// Write the part of value selected by kind into the field of the word at a reference.
+/// None if the word is outside bytes.
+pub fn patch(bytes: &mut [u8], offset: usize, kind: RelocKind, field: BitField, value: u32) -> Option<()> {
+    let at = offset.checked_add(kind.word_offset())?;
+    let word = bytes.get_mut(at..at.checked_add(4)?)?;
+    let old = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+    word.copy_from_slice(&field.insert(old, kind.select(value)).to_le_bytes());
+    Some(())
+}
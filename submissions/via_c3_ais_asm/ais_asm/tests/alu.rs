@@ -0,0 +1,110 @@
+// XALU builder methods: same encoding as the constructors, and a program written with them runs on the model
+
+mod common;
+
+use ais_asm::ais::{AisError, Const, EAX, ECX, EDX, R0};
+use ais_asm::parse::parse_into;
+use ais_asm::{Cond, DpCntl, DynAsm, DynAsmError, Instruction, Opcode, Register, SubOpXalu};
+
+type Binary = fn(&mut DynAsm, Register, Register, Register) -> Result<(), DynAsmError>;
+type Dp = fn(&mut DynAsm, DpCntl, Register, Register, Register) -> Result<(), DynAsmError>;
+type Imm = fn(&mut DynAsm, Register, Register, i8) -> Result<(), DynAsmError>;
+
+const DPCNTLS: [DpCntl; 6] = [DpCntl::Word, DpCntl::Short, DpCntl::LL, DpCntl::HL, DpCntl::LH, DpCntl::HH];
+
+// IMUL, MUL, IDIV, CTC2, MFLOU and MFLOI don't take dst = a op b, they have no methods
+const BINARY: [(SubOpXalu, Binary, Binary, Dp, Imm); 15] = [
+    (SubOpXalu::SHL, DynAsm::shl, DynAsm::shl_w16, DynAsm::shl_dp, DynAsm::shl_imm),
+    (SubOpXalu::SHR, DynAsm::shr, DynAsm::shr_w16, DynAsm::shr_dp, DynAsm::shr_imm),
+    (SubOpXalu::SAR, DynAsm::sar, DynAsm::sar_w16, DynAsm::sar_dp, DynAsm::sar_imm),
+    (SubOpXalu::ROL, DynAsm::rol, DynAsm::rol_w16, DynAsm::rol_dp, DynAsm::rol_imm),
+    (SubOpXalu::ROR, DynAsm::ror, DynAsm::ror_w16, DynAsm::ror_dp, DynAsm::ror_imm),
+    (SubOpXalu::RCL, DynAsm::rcl, DynAsm::rcl_w16, DynAsm::rcl_dp, DynAsm::rcl_imm),
+    (SubOpXalu::RCR, DynAsm::rcr, DynAsm::rcr_w16, DynAsm::rcr_dp, DynAsm::rcr_imm),
+    (SubOpXalu::ADD, DynAsm::add, DynAsm::add_w16, DynAsm::add_dp, DynAsm::add_imm),
+    (SubOpXalu::ADC, DynAsm::adc, DynAsm::adc_w16, DynAsm::adc_dp, DynAsm::adc_imm),
+    (SubOpXalu::SUB, DynAsm::sub, DynAsm::sub_w16, DynAsm::sub_dp, DynAsm::sub_imm),
+    (SubOpXalu::SBB, DynAsm::sbb, DynAsm::sbb_w16, DynAsm::sbb_dp, DynAsm::sbb_imm),
+    (SubOpXalu::AND, DynAsm::and, DynAsm::and_w16, DynAsm::and_dp, DynAsm::and_imm),
+    (SubOpXalu::OR, DynAsm::or, DynAsm::or_w16, DynAsm::or_dp, DynAsm::or_imm),
+    (SubOpXalu::XOR, DynAsm::xor, DynAsm::xor_w16, DynAsm::xor_dp, DynAsm::xor_imm),
+    (SubOpXalu::NOR, DynAsm::nor, DynAsm::nor_w16, DynAsm::nor_dp, DynAsm::nor_imm),
+];
+
+fn bytes(f: impl FnOnce(&mut DynAsm) -> Result<(), DynAsmError>) -> Vec<u8> {
+    let mut asm = DynAsm::new(0);
+    f(&mut asm).unwrap();
+    // The instruction, without the padding of the section
+    asm.finish().unwrap().bytes()[..6].to_vec()
+}
+
+fn encode(instr: Instruction) -> Vec<u8> {
+    instr.encode().unwrap()
+}
+
+#[test]
+fn methods_encode_like_the_constructors() {
+    for (op, word, w16, dp, imm) in BINARY {
+        let xalur = |dpcntl| Instruction::xalur(op, dpcntl, EAX, ECX, EDX);
+        assert_eq!(bytes(|asm| word(asm, EAX, ECX, EDX)), encode(xalur(DpCntl::Word)), "{:?}", op);
+        assert_eq!(bytes(|asm| w16(asm, EAX, ECX, EDX)), encode(xalur(DpCntl::Short)), "{:?}", op);
+        for dpcntl in DPCNTLS {
+            assert_eq!(bytes(|asm| dp(asm, dpcntl, EAX, ECX, EDX)), encode(xalur(dpcntl)), "{:?} {:?}", op, dpcntl);
+        }
+        let xaluir = Instruction::xaluir(op, DpCntl::Word, EAX, ECX, Const::Number(-3));
+        assert_eq!(bytes(|asm| imm(asm, EAX, ECX, -3)), encode(xaluir), "{:?}", op);
+    }
+
+    let unary = |op| Instruction::xalur(op, DpCntl::Word, EDX, EDX, R0);
+    assert_eq!(bytes(|asm| asm.inc(EDX, EDX)), encode(unary(SubOpXalu::INC)));
+    assert_eq!(bytes(|asm| asm.dec(EDX, EDX)), encode(unary(SubOpXalu::DEC)));
+    let inc = Instruction::xalur(SubOpXalu::INC, DpCntl::LH, EDX, EDX, R0);
+    assert_eq!(bytes(|asm| asm.inc_dp(DpCntl::LH, EDX, EDX)), encode(inc));
+    // Operations without methods still go through gen_xalu
+    let mul = Instruction::xalur(SubOpXalu::MUL, DpCntl::Word, R0, EAX, EDX);
+    assert_eq!(bytes(|asm| asm.gen_xalu(SubOpXalu::MUL, DpCntl::Word, R0, EAX, EDX)), encode(mul));
+    let hl = Instruction::xalur(SubOpXalu::ADD, DpCntl::HL, EAX, EAX, EDX);
+    assert_eq!(bytes(|asm| asm.gen_xalu(SubOpXalu::ADD, DpCntl::HL, EAX, EAX, EDX)), encode(hl));
+    assert_eq!(bytes(|asm| asm.cmps(EAX, EDX)), encode(Instruction::cmps(EAX, EDX)));
+    assert_eq!(bytes(|asm| asm.setcc(Cond::NE, EAX)), encode(Instruction::setcc(Cond::NE, EAX)));
+}
+
+#[test]
+fn program_with_builder_methods() {
+    // ((7 << 4 | 5) - 3 + 1) ^ !(0 | 0) >> 1, in virtual registers
+    let mut asm = DynAsm::new(0x1000);
+    asm.gen_header().unwrap();
+    let (x, y) = (asm.new_vreg(), asm.new_vreg());
+    asm.gen_load(x.clone(), 7).unwrap();
+    asm.gen_load(y.clone(), 5).unwrap();
+    asm.shl_imm(x.clone(), x.clone(), 4).unwrap();
+    asm.or(x.clone(), x.clone(), y.clone()).unwrap();
+    asm.add_imm(x.clone(), x.clone(), -3).unwrap();
+    asm.inc(x.clone(), x.clone()).unwrap();
+    asm.nor(y.clone(), R0, R0).unwrap();
+    asm.shr_imm(y.clone(), y.clone(), 1).unwrap();
+    asm.xor(EDX, x, y).unwrap();
+    asm.gen_footer().unwrap();
+
+    asm.allocate().unwrap();
+    let image = asm.finish().unwrap();
+    assert_eq!(common::run(image.bytes(), image.base()).edx(), (0x75 - 3 + 1) ^ (u32::MAX >> 1));
+}
+
+#[test]
+fn constants_fit_in_five_bits() {
+    for c in -16..=15 {
+        let instr = Instruction::xaluir(SubOpXalu::ADD, DpCntl::Word, EAX, EAX, Const::Number(c));
+        let (decoded, _) = Instruction::decode(&instr.encode().unwrap()).unwrap();
+        assert!(matches!(decoded.constant, Some(Const::Number(x)) if x == c));
+        assert_eq!(decoded.opcode, Opcode::XALUIR);
+    }
+    for c in [16, -17, i8::MAX] {
+        let instr = Instruction::xaluir(SubOpXalu::ADD, DpCntl::Word, EAX, EAX, Const::Number(c));
+        assert!(matches!(instr.encode(), Err(AisError::ConstantRange(x)) if x == c));
+    }
+
+    let mut asm = DynAsm::new(0);
+    assert!(parse_into(&mut asm, "XALUIR.SHL EAX, EAX, 15", "test.s").is_ok());
+    assert!(parse_into(&mut asm, "XALUIR.SHL EAX, EAX, 16", "test.s").is_err());
+}
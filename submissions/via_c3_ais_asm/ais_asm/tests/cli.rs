@@ -0,0 +1,120 @@
+// Command line tool: the disasm, explain and hex output against the library, and the exit codes
+
+use ais_asm::ais::{EAX, R0};
+use ais_asm::hex::write_hex;
+use ais_asm::listing::{write_disasm, write_explain};
+use ais_asm::{Instruction, Opcode};
+
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+fn run(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_ais_asm")).args(args).output().unwrap()
+}
+
+// A fresh directory for one test
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("ais_asm_cli_{}_{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn ori() -> Vec<u8> {
+    Instruction::i_type(Opcode::ORI, EAX, R0, 0x000B).encode().unwrap()
+}
+
+#[test]
+fn disasm_prints_the_payload() {
+    let dir = temp_dir("disasm");
+    let payload = dir.join("payload.bin");
+    let bytes = [ori(), vec![0x90]].concat();
+    std::fs::write(&payload, &bytes).unwrap();
+
+    let output = run(&["disasm", payload.to_str().unwrap(), "--base", "0x1000"]);
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let mut expected = Vec::new();
+    write_disasm(&bytes, 0x1000, &mut expected).unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), String::from_utf8(expected).unwrap());
+}
+
+#[test]
+fn explain_takes_the_usual_ways_to_write_bytes() {
+    let bytes = ori();
+    let mut expected = Vec::new();
+    let (instr, _) = Instruction::decode(&bytes).unwrap();
+    write_explain(&instr, &bytes, &mut expected).unwrap();
+    let expected = String::from_utf8(expected).unwrap();
+
+    let spaced: Vec<String> = bytes.iter().map(|x| format!("{:02x}", x)).collect();
+    let joined: String = spaced.concat();
+    let prefixed = spaced.iter().map(|x| format!("0x{}", x)).collect::<Vec<_>>().join(",");
+    for args in [spaced.clone(), vec![joined], vec![prefixed]] {
+        let args: Vec<&str> = ["explain"].into_iter().chain(args.iter().map(|x| x.as_str())).collect();
+        let output = run(&args);
+        assert!(output.status.success(), "{:?}: {}", args, String::from_utf8_lossy(&output.stderr));
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+    }
+
+    // Bytes that don't decode are invalid input, bytes that aren't hex are invalid usage
+    assert_eq!(run(&["explain", "90", "90", "90", "90", "90", "90"]).status.code(), Some(1));
+    assert_eq!(run(&["explain", "628"]).status.code(), Some(2));
+    assert_eq!(run(&["explain", "zz"]).status.code(), Some(2));
+}
+
+#[test]
+fn assemble_writes_intel_hex() {
+    let dir = temp_dir("hex");
+    let source = dir.join("payload.s");
+    std::fs::write(&source, ".header\nORI EAX, R0, 0xB\n.footer\n").unwrap();
+
+    let output = run(&["assemble", source.to_str().unwrap(), "--base", "0x1000", "--format", "hex"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let binary = dir.join("payload.bin");
+    let output = run(&["assemble", source.to_str().unwrap(), "--base", "0x1000", "-o", binary.to_str().unwrap()]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let mut expected = Vec::new();
+    write_hex(&std::fs::read(&binary).unwrap(), 0x1000, 0x1000, &mut expected).unwrap();
+    let hex = std::fs::read_to_string(dir.join("payload.hex")).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(hex, String::from_utf8(expected).unwrap());
+    assert!(hex.starts_with(":020000040000FA\n"));
+    assert!(hex.ends_with(":00000001FF\n"));
+}
+
+#[test]
+fn usage_errors_exit_with_2() {
+    let commands = [&[][..], &["frobnicate"], &["assemble"], &["assemble", "a.s", "b.s"]];
+    for args in commands.into_iter().chain([&["disasm", "a.bin", "--pic", "R5"][..]]) {
+        let output = run(args);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(output.status.code(), Some(2), "{:?}: {}", args, stderr);
+        assert!(stderr.contains("Usage: ais_asm"), "{:?}: {}", args, stderr);
+    }
+    assert_eq!(run(&["assemble", "a.s", "--format", "coff"]).status.code(), Some(2));
+    assert_eq!(run(&["assemble", "a.s", "--base"]).status.code(), Some(2));
+
+    let help = run(&["--help"]);
+    assert!(help.status.success());
+    assert!(String::from_utf8(help.stdout).unwrap().starts_with("Usage: ais_asm"));
+}
+
+#[test]
+fn file_errors_exit_with_3() {
+    let dir = temp_dir("file");
+    let missing = dir.join("missing.s");
+    for command in ["assemble", "dump", "disasm"] {
+        let output = run(&[command, missing.to_str().unwrap()]);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(output.status.code(), Some(3), "{}: {}", command, stderr);
+        assert!(stderr.contains("missing.s"), "{}: {}", command, stderr);
+    }
+
+    // An output that can't be created, the directory is in the way
+    let source = dir.join("payload.s");
+    std::fs::write(&source, ".header\n.footer\n").unwrap();
+    let output = run(&["assemble", source.to_str().unwrap(), "-o", dir.to_str().unwrap()]);
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(output.status.code(), Some(3), "{}", String::from_utf8_lossy(&output.stderr));
+}
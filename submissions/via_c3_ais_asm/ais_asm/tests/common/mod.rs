@@ -0,0 +1,137 @@
+// Model of the AIS instructions that the tests generate, enough to run whole payloads
+#![allow(dead_code)]
+
+use ais_asm::ais::{Const, Function};
+use ais_asm::constant::i_type_result;
+use ais_asm::{DpCntl, Instruction, Opcode, Register, SubOpXalu};
+
+use std::collections::BTreeMap;
+
+const HEADER_LEN: u32 = 11;
+
+/// ESP when the payload starts
+pub const STACK: u32 = 0x8000;
+
+/// Values of EBX, EBP, ESI and EDI when the payload starts, the caller expects them back
+pub const SAVED: [(usize, u32); 4] = [(19, 0xB0B0), (21, 0xB9B9), (22, 0x5151), (23, 0xD1D1)];
+
+pub struct Machine {
+    pub regs: [u32; 32],
+    pub memory: BTreeMap<u32, u32>,
+    // Operands of the last CMPS, the flags are derived from them
+    pub compared: (u32, u32),
+}
+
+impl Machine {
+    pub fn eax(&self) -> u32 {
+        self.regs[16]
+    }
+
+    pub fn edx(&self) -> u32 {
+        self.regs[18]
+    }
+
+    pub fn callee_saved_kept(&self) -> bool {
+        SAVED.iter().all(|(reg, value)| self.regs[*reg] == *value) && self.regs[20] == STACK
+    }
+}
+
+/// Whether x86 condition code cc holds after `cmp a, b`
+pub fn condition(cc: u32, a: u32, b: u32) -> bool {
+    let result = a.wrapping_sub(b);
+    let (cf, zf, sf) = (a < b, result == 0, (result as i32) < 0);
+    let of = ((a ^ b) & (a ^ result)) >> 31 == 1;
+    let pf = (result as u8).count_ones().is_multiple_of(2);
+    let holds = match cc >> 1 {
+        0 => of,
+        1 => cf,
+        2 => zf,
+        3 => cf || zf,
+        4 => sf,
+        5 => pf,
+        6 => sf != of,
+        _ => zf || sf != of,
+    };
+    holds != (cc & 1 == 1)
+}
+
+// Run a payload linked at base until its footer
+pub fn run(bytes: &[u8], base: u32) -> Machine {
+    let mut m = Machine {
+        regs: [0; 32],
+        memory: BTreeMap::new(),
+        compared: (0, 0),
+    };
+    m.regs[20] = STACK;
+    for (reg, value) in SAVED {
+        m.regs[reg] = value;
+    }
+
+    let mut pc = base;
+    for _ in 0..100000 {
+        let at = (pc - base) as usize;
+        match bytes[at] {
+            0xC3 => return m,
+            0xE8 => {
+                m.regs[16] = pc + HEADER_LEN;
+                pc += HEADER_LEN;
+                continue;
+            }
+            _ => (),
+        }
+
+        let (instr, len) = Instruction::decode(&bytes[at..]).unwrap();
+        let reg = |x: &Option<Register>| x.as_ref().unwrap().bits().unwrap() as usize;
+        pc += len as u32;
+        match (instr.opcode, instr.function) {
+            (Opcode::XJ, _) => pc = m.regs[reg(&instr.rt)],
+            (Opcode::XL, _) => {
+                // Stack first, then the payload itself
+                let addr = m.regs[reg(&instr.rt)];
+                let at = addr.wrapping_sub(base) as usize;
+                let word = || u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+                m.regs[reg(&instr.rs)] = m.memory.get(&addr).copied().unwrap_or_else(word);
+            }
+            (Opcode::XS, _) => {
+                m.memory.insert(m.regs[reg(&instr.rt)], m.regs[reg(&instr.rs)]);
+            }
+            (Opcode::XALUR, Some(Function::Xalu(SubOpXalu::CMPS, _))) => {
+                m.compared = (m.regs[reg(&instr.rs)], m.regs[reg(&instr.rt)]);
+            }
+            // Condition in rt, like the encoder assumes. Not checked on hardware.
+            (Opcode::XALUR, Some(Function::Xalu(SubOpXalu::SETCC, _))) => {
+                let (a, b) = m.compared;
+                m.regs[reg(&instr.rd)] = condition(reg(&instr.rt) as u32, a, b) as u32;
+            }
+            (Opcode::XALUR | Opcode::XALUIR, Some(Function::Xalu(sub_op, dpcntl))) => {
+                assert!(matches!(dpcntl, DpCntl::Word), "no model for {:?}", dpcntl);
+                let a = m.regs[reg(&instr.rs)];
+                // The XALUI constant as the encoder assumes it, a signed number in rt
+                let b = match instr.constant {
+                    Some(Const::Number(c)) => c as u32,
+                    None => m.regs[reg(&instr.rt)],
+                };
+                m.regs[reg(&instr.rd)] = match sub_op {
+                    SubOpXalu::ADD => a.wrapping_add(b),
+                    SubOpXalu::SUB => a.wrapping_sub(b),
+                    SubOpXalu::AND => a & b,
+                    SubOpXalu::OR => a | b,
+                    SubOpXalu::XOR => a ^ b,
+                    SubOpXalu::SHL => a << (b & 31),
+                    SubOpXalu::SHR => a >> (b & 31),
+                    SubOpXalu::SAR => ((a as i32) >> (b & 31)) as u32,
+                    SubOpXalu::INC => a.wrapping_add(1),
+                    SubOpXalu::DEC => a.wrapping_sub(1),
+                    SubOpXalu::NOR => !(a | b),
+                    x => panic!("no model for {:?}", x),
+                };
+            }
+            (opcode, _) => {
+                let value = i_type_result(opcode, m.regs[reg(&instr.rs)], instr.imm.unwrap()).unwrap();
+                m.regs[reg(&instr.rt)] = value;
+            }
+        }
+        m.regs[0] = 0;
+    }
+    panic!("payload doesn't end");
+}
@@ -0,0 +1,111 @@
+// Constant synthesiser over interesting bit patterns, every sequence is run on a model of the I type instructions
+
+use ais_asm::constant::{i_type_result, synthesize, I_TYPE};
+use ais_asm::{DynAsm, Instruction, Register};
+
+fn patterns() -> Vec<u32> {
+    let mut values = vec![0, 1, 0x7FFF, 0x8000, 0xFFFF, 0x10000, 0x17FFF, 0x18000, 0xDEADBEEF, 0x12345678];
+    values.extend([0xAAAAAAAA, 0x55555555, 0x0000FFFF, 0xFFFF0000, 0x00FF00FF, 0xFF00FF00]);
+    for bit in 0..32 {
+        values.push(1 << bit);
+        values.push(!(1 << bit));
+        values.push((1u32 << bit) - 1);
+        values.push(!((1u32 << bit) - 1));
+    }
+    for x in [1, 2, 0x7FFF, 0x8000, 0x8001, 0xFFFF] {
+        values.push((x as u32).wrapping_neg());
+    }
+    let halves = [0, 1, 0x7FFF, 0x8000, 0xFFFF, 0x1234];
+    for high in halves {
+        for low in halves {
+            values.push(high << 16 | low);
+        }
+    }
+    values
+}
+
+// Run the sequence, with R0 and the known registers as starting state
+fn run(code: &[Instruction], known: &[(Register, u32)]) -> [u32; 32] {
+    let mut regs = [0; 32];
+    for (reg, value) in known {
+        regs[reg.bits().unwrap() as usize] = *value;
+    }
+
+    for instr in code {
+        let rs = instr.rs.as_ref().unwrap().bits().unwrap() as usize;
+        let rt = instr.rt.as_ref().unwrap().bits().unwrap() as usize;
+        regs[rt] = i_type_result(instr.opcode, regs[rs], instr.imm.unwrap()).unwrap();
+        regs[0] = 0;
+    }
+    regs
+}
+
+// Whether any single I type instruction loads value from the zero register
+fn single_exists(value: u32) -> bool {
+    I_TYPE
+        .iter()
+        .any(|opcode| (0..=0xFFFF).any(|imm| i_type_result(*opcode, 0, imm) == Some(value)))
+}
+
+#[test]
+fn every_pattern_loads_with_the_shortest_sequence() {
+    let dst: Register = "EAX".into();
+    for value in patterns() {
+        let code = synthesize(&dst, value, &[]);
+        assert_eq!(run(&code, &[])[16], value, "{:#010X}: {:?}", value, code);
+        assert!(!code.is_empty() && code.len() <= 2, "{:#010X}: {:?}", value, code);
+        if code.len() == 2 {
+            assert!(!single_exists(value), "{:#010X} fits one instruction: {:?}", value, code);
+        }
+    }
+}
+
+#[test]
+fn single_instruction_values() {
+    let dst: Register = "EAX".into();
+    for value in [0, 0x1234, 0x12340000, 0xFFFFFFFF, 0xFFFF8000, 0xFFFFFFFE] {
+        assert_eq!(synthesize(&dst, value, &[]).len(), 1, "{:#010X}", value);
+    }
+    assert_eq!(synthesize(&dst, 0x12345678, &[]).len(), 2);
+}
+
+#[test]
+fn known_registers_are_reused() {
+    let eax: Register = "EAX".into();
+    let ecx: Register = "ECX".into();
+
+    for value in patterns() {
+        for delta in [0, 1, 0x7FFF, 0xFFFF8000] {
+            let known = [(ecx.clone(), value.wrapping_sub(delta))];
+            let code = synthesize(&eax, value, &known);
+            assert_eq!(code.len(), 1, "{:#010X} from {:#010X}: {:?}", value, known[0].1, code);
+            assert_eq!(run(&code, &known)[16], value);
+        }
+
+        // Nothing to do when the register holds the value already
+        assert!(synthesize(&eax, value, &[(eax.clone(), value)]).is_empty());
+
+        let known = [(eax.clone(), value ^ 0x12340000)];
+        let code = synthesize(&eax, value, &known);
+        assert_eq!(code.len(), 1, "{:#010X}: {:?}", value, code);
+        assert_eq!(run(&code, &known)[16], value);
+    }
+}
+
+#[test]
+fn dynasm_uses_the_synthesiser() {
+    let mut asm = DynAsm::new(0);
+    asm.gen_load("EAX".into(), 0x12345678).unwrap();
+    asm.gen_load_near("EDX".into(), 0x12345679, &[("EAX".into(), 0x12345678)]).unwrap();
+    asm.gen_load("ECX".into(), 0xFFFFFFFF).unwrap();
+    let image = asm.finish().unwrap();
+
+    let code: Vec<Instruction> = Instruction::iter(image.bytes(), ais_asm::Recovery::Stop)
+        .map(|(_, x)| x.unwrap())
+        .collect();
+    assert_eq!(code.len(), 4);
+    let regs = run(&code, &[]);
+    assert_eq!(regs[16], 0x12345678);
+    assert_eq!(regs[17], 0xFFFFFFFF);
+    assert_eq!(regs[18], 0x12345679);
+}
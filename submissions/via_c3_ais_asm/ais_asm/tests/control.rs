@@ -0,0 +1,105 @@
+// Conditional branches: every condition against Rust comparisons, and a loop, on a model of AIS
+
+mod common;
+
+use ais_asm::parse::parse_into;
+use ais_asm::{Cond, DpCntl, DynAsm, Instruction, Recovery, Register, SubOpXalu};
+
+// What x86 would do after `cmp a, b`, written with Rust comparisons
+fn expected(cond: Cond, a: u32, b: u32) -> bool {
+    let (sa, sb) = (a as i32, b as i32);
+    let result = a.wrapping_sub(b);
+    match cond {
+        Cond::O => sa.checked_sub(sb).is_none(),
+        Cond::B => a < b,
+        Cond::E => a == b,
+        Cond::BE => a <= b,
+        Cond::S => (result as i32) < 0,
+        Cond::P => (result & 0xFF).count_ones().is_multiple_of(2),
+        Cond::L => sa < sb,
+        Cond::LE => sa <= sb,
+        x => !expected(!x, a, b),
+    }
+}
+
+fn new(pic: bool) -> DynAsm {
+    match pic {
+        true => DynAsm::new_pic("R5".into()).unwrap(),
+        false => DynAsm::new(0x1000),
+    }
+}
+
+#[test]
+fn every_condition_branches_like_x86() {
+    const VALUES: [u32; 6] = [0, 1, 5, 0x7FFF_FFFF, 0x8000_0000, 0xFFFF_FFFF];
+    for (x, cond) in Cond::ALL.into_iter().enumerate() {
+        assert_eq!(cond as usize, x);
+        for (a, b) in VALUES.into_iter().flat_map(|a| VALUES.map(|b| (a, b))) {
+            let pic = (a ^ b) & 1 == 1;
+            let mut asm = new(pic);
+            asm.gen_header().unwrap();
+            let (ra, rb) = (asm.new_vreg(), asm.new_vreg());
+            asm.gen_load(ra.clone(), a).unwrap();
+            asm.gen_load(rb.clone(), b).unwrap();
+            let (taken, end) = (asm.new_sym(), asm.new_sym());
+            asm.gen_branch_if(cond, ra, rb, taken).unwrap();
+            asm.gen_load("EDX".into(), 1).unwrap();
+            asm.gen_jump(end).unwrap();
+            asm.set_sym_here(taken).unwrap();
+            asm.gen_load("EDX".into(), 2).unwrap();
+            asm.set_sym_here(end).unwrap();
+            asm.gen_footer().unwrap();
+
+            let image = asm.finish().unwrap();
+            let machine = common::run(image.bytes(), image.base());
+            let want = if expected(cond, a, b) { 2 } else { 1 };
+            assert_eq!(machine.edx(), want, "{:?} 0x{:X} 0x{:X}", cond, a, b);
+            assert!(machine.callee_saved_kept());
+        }
+    }
+}
+
+#[test]
+fn loops_count_down() {
+    // EDX = 10 + 9 + ... + 1, the counter and total stay in registers over the backward branch, also optimised
+    for pic in [false, true] {
+        let mut asm = new(pic);
+        asm.gen_header().unwrap();
+        let (count, total) = (asm.new_vreg(), asm.new_vreg());
+        asm.gen_load(count.clone(), 10).unwrap();
+        asm.gen_load(total.clone(), 0).unwrap();
+        let top = asm.new_sym_here();
+        let add = |op, dst: &Register, b: Register| Instruction::xalur(op, DpCntl::Word, dst.clone(), dst.clone(), b);
+        asm.gen(add(SubOpXalu::ADD, &total, count.clone())).unwrap();
+        asm.gen(Instruction::i_type(ais_asm::Opcode::ADDI, count.clone(), count.clone(), 0xFFFF)).unwrap();
+        asm.gen_branch_if(Cond::NE, count, 0.into(), top).unwrap();
+        asm.gen(add(SubOpXalu::OR, &"EDX".into(), total)).unwrap();
+        asm.gen_footer().unwrap();
+
+        let report = asm.allocate().unwrap();
+        assert_eq!(report.spilled, 0, "{}", report);
+        asm.optimize();
+        let image = asm.finish().unwrap();
+        assert_eq!(common::run(image.bytes(), image.base()).edx(), 55);
+    }
+}
+
+#[test]
+fn set_if_and_text_form() {
+    let mut asm = DynAsm::new(0x1000);
+    asm.gen_header().unwrap();
+    let flag = asm.gen_set_if(Cond::A, "ECX".into(), "R0".into()).unwrap();
+    asm.gen(Instruction::i_type(ais_asm::Opcode::ORI, "EDX".into(), flag, 0)).unwrap();
+    parse_into(&mut asm, "XALUR.SETCC EAX, R0, ge", "test.s").unwrap();
+    asm.gen_footer().unwrap();
+    let image = asm.finish().unwrap();
+
+    let machine = common::run(image.bytes(), image.base());
+    assert_eq!((machine.edx(), machine.eax()), (0, 1));
+
+    let text: Vec<String> = Instruction::iter(&image.bytes()[11..], Recovery::Stop)
+        .map_while(|(_, x)| x.ok().map(|x| x.to_string()))
+        .collect();
+    assert_eq!(text[1], "XALUR.SETCC R1, R0, A");
+    assert_eq!(text[3], "XALUR.SETCC EAX, R0, GE");
+}
@@ -0,0 +1,127 @@
+// Instruction iterator: where each recovery policy continues after bytes that don't decode, and the offsets it reports
+
+use ais_asm::ais::{Const, Size, EAX, EDX, ESP, R0};
+use ais_asm::{AisError, DpCntl, Instruction, Opcode, Recovery, SubOpXalu};
+
+// ORI at 0, two bytes of x86 at 6, a wrapped word with the unknown opcode 1 at 8, XJ at 14, half a wrapper at 20
+fn stream() -> Vec<u8> {
+    [
+        Instruction::i_type(Opcode::ORI, EAX, R0, 0x000B).encode().unwrap(),
+        vec![0x90, 0x90],
+        vec![0x62, 0x80, 0x00, 0x00, 0x00, 0x04],
+        Instruction::xj(EAX).encode().unwrap(),
+        vec![0x62, 0x80, 0x01],
+    ]
+    .concat()
+}
+
+// Offset and opcode of every instruction, or the error
+fn decoded(bytes: &[u8], recovery: Recovery) -> Vec<(usize, Result<Opcode, String>)> {
+    Instruction::iter(bytes, recovery)
+        .map(|(offset, x)| (offset, x.map(|x| x.opcode).map_err(|x| format!("{:?}", x))))
+        .collect()
+}
+
+fn err(offset: usize, e: &str) -> (usize, Result<Opcode, String>) {
+    (offset, Err(e.to_string()))
+}
+
+#[test]
+fn skip_byte_tries_every_offset() {
+    let expected = vec![
+        (0, Ok(Opcode::ORI)),
+        err(6, "NotWrapped([144, 144])"),
+        err(7, "NotWrapped([144, 98])"),
+        err(8, "UnknownOpcode(1)"),
+        err(9, "NotWrapped([128, 0])"),
+        err(10, "NotWrapped([0, 0])"),
+        err(11, "NotWrapped([0, 0])"),
+        err(12, "NotWrapped([0, 4])"),
+        err(13, "NotWrapped([4, 98])"),
+        (14, Ok(Opcode::XJ)),
+        err(20, "Truncated(3)"),
+        err(21, "Truncated(2)"),
+        err(22, "Truncated(1)"),
+    ];
+    assert_eq!(decoded(&stream(), Recovery::SkipByte), expected);
+}
+
+#[test]
+fn skip_to_wrapper_resynchronises_on_the_next_wrapper() {
+    let expected = vec![
+        (0, Ok(Opcode::ORI)),
+        err(6, "NotWrapped([144, 144])"),
+        // A wrapper with a bad word is skipped as a whole, its first byte doesn't count as the next wrapper
+        err(8, "UnknownOpcode(1)"),
+        (14, Ok(Opcode::XJ)),
+        // No wrapper after the truncated one, the rest is skipped
+        err(20, "Truncated(3)"),
+    ];
+    assert_eq!(decoded(&stream(), Recovery::SkipToWrapper), expected);
+
+    // Only garbage, one error for all of it
+    assert_eq!(decoded(&[0x90; 10], Recovery::SkipToWrapper), vec![err(0, "NotWrapped([144, 144])")]);
+}
+
+#[test]
+fn stop_ends_at_the_first_error() {
+    let expected = vec![(0, Ok(Opcode::ORI)), err(6, "NotWrapped([144, 144])")];
+    assert_eq!(decoded(&stream(), Recovery::Stop), expected);
+
+    let truncated = &stream()[14..22];
+    assert_eq!(decoded(truncated, Recovery::Stop), vec![(0, Ok(Opcode::XJ)), err(6, "Truncated(2)")]);
+}
+
+#[test]
+fn every_policy_agrees_on_valid_code() {
+    let code = &stream()[14..20];
+    let bytes = [&stream()[..6], code, code].concat();
+    for recovery in [Recovery::SkipByte, Recovery::SkipToWrapper, Recovery::Stop] {
+        let expected = vec![(0, Ok(Opcode::ORI)), (6, Ok(Opcode::XJ)), (12, Ok(Opcode::XJ))];
+        assert_eq!(decoded(&bytes, recovery), expected, "{:?}", recovery);
+        assert!(decoded(&[], recovery).is_empty());
+    }
+}
+
+#[test]
+fn decoded_instructions_encode_the_same() {
+    let dpcntls = [DpCntl::Word, DpCntl::Short, DpCntl::LL, DpCntl::HL, DpCntl::LH, DpCntl::HH];
+    let sizes = [Size::Bits8, Size::Bits16, Size::Bits32];
+    let mut instructions = Vec::new();
+    for dpcntl in dpcntls {
+        instructions.push(Instruction::xalur(SubOpXalu::ADD, dpcntl, EAX, EDX, ESP));
+        instructions.push(Instruction::xaluir(SubOpXalu::SHL, dpcntl, EDX, EAX, Const::Number(-3)));
+    }
+    for size in sizes {
+        instructions.extend([Instruction::xl(size, ESP, EAX), Instruction::xs(size, EAX, EDX)]);
+    }
+    // The encoder only takes byte wide port IO
+    instructions.extend([Instruction::xior(Size::Bits8, EDX, EAX), Instruction::xiow(Size::Bits8, EDX, EAX)]);
+
+    for instruction in instructions {
+        let bytes = instruction.encode().unwrap();
+        let (decoded, len) = Instruction::decode(&bytes).unwrap();
+        assert_eq!(len, 6);
+        assert_eq!(decoded.encode().unwrap(), bytes, "{}", instruction);
+        assert_eq!(decoded.to_string(), instruction.to_string());
+    }
+}
+
+#[test]
+fn decode_keeps_the_size_and_all_dpcntl_bits() {
+    let decoded = |x: Instruction| Instruction::decode(&x.encode().unwrap()).unwrap().0.to_string();
+    assert_eq!(decoded(Instruction::xl(Size::Bits32, ESP, EAX)), "XL.Bits32 EAX, [ESP+0]");
+    assert_eq!(decoded(Instruction::xs(Size::Bits8, EAX, EDX)), "XS.Bits8 EDX, [EAX+0]");
+    assert_eq!(decoded(Instruction::xalur(SubOpXalu::ADD, DpCntl::LH, EAX, EAX, EDX)), "XALUR.ADD.LH EAX, EAX, EDX");
+    assert_eq!(decoded(Instruction::xalur(SubOpXalu::ADD, DpCntl::HH, EAX, EAX, EDX)), "XALUR.ADD.HH EAX, EAX, EDX");
+
+    // DpCntl 6 and 7 and an unknown selector don't decode
+    let invalid = |x: Instruction, patch: fn(u32) -> u32| {
+        let bytes = x.encode().unwrap();
+        let word = patch(u32::from_le_bytes(bytes[2..].try_into().unwrap()));
+        let bytes = [&bytes[..2], &word.to_le_bytes()].concat();
+        matches!(Instruction::decode(&bytes), Err(AisError::InvalidFunction(_)))
+    };
+    assert!(invalid(Instruction::xalur(SubOpXalu::ADD, DpCntl::Word, EAX, EAX, EDX), |x| x | 6 << 5));
+    assert!(invalid(Instruction::xl(Size::Bits32, ESP, EAX), |x| x & !(0xF << 2)));
+}
@@ -0,0 +1,126 @@
+// The ais! macro: same bytes as the builder calls it stands for, and a payload written with it runs on the model
+
+mod common;
+
+use ais_asm::ais::{Const, Size, EAX, ECX, EDX, ESP, R0};
+use ais_asm::dynasm::Section;
+use ais_asm::{ais, Cond, DpCntl, DynAsm, Instruction, Opcode, SubOpXalu};
+
+#[test]
+fn statements_expand_to_builder_calls() {
+    let build = |dsl: bool| {
+        let mut asm = DynAsm::new(0x1000);
+        let (top, table) = (asm.new_sym(), asm.new_sym());
+        let v = asm.new_vreg();
+        let (imm, c, value) = (0x1234u16, -5i8, 0xCAFEu32);
+        if dsl {
+            ais!(asm =>
+                .header;
+                top:
+                ORI EAX, R0, 0x000B;
+                ORIU {v}, EAX, {imm};
+                XALUR.SHL EAX, EAX, ECX;
+                XALUR.ADD.Short EAX, {v}, R17;
+                XALUIR.SHL EAX, EAX, 4;
+                XALUIR.ADD EAX, EAX, -16;
+                XALUI.SUB EAX, EAX, {c};
+                XALUR.CMPS R0, EAX, EDX;
+                XALUR.SETCC EDX, R0, LE;
+                XL.Bits32 ESP, [ESP+0];
+                XS.Bits8 EAX, [{v}];
+                load {v}, 0xFFFF_FFFF;
+                load ECX, {value};
+                load EAX, table + 4;
+                XJ EAX;
+                jump top;
+                .footer;
+                .rodata;
+                .align 4;
+                {table}:
+                .long 7;
+                .long {value};
+                .long top;
+                .text;
+                ADDI EAX, EAX, 1;
+            )
+            .unwrap();
+        } else {
+            let xalur = |op, dp, dst, src, extra| Instruction::xalur(op, dp, dst, src, extra);
+            let xaluir = |opcode, op, c| {
+                let mut instr = Instruction::xaluir(op, DpCntl::Word, EAX, EAX, Const::Number(c));
+                instr.opcode = opcode;
+                instr
+            };
+            asm.gen_header().unwrap();
+            asm.set_sym_here(top).unwrap();
+            asm.gen(Instruction::i_type(Opcode::ORI, EAX, R0, 0x000B)).unwrap();
+            asm.gen(Instruction::i_type(Opcode::ORIU, v.clone(), EAX, imm)).unwrap();
+            asm.gen(xalur(SubOpXalu::SHL, DpCntl::Word, EAX, EAX, ECX)).unwrap();
+            asm.gen(xalur(SubOpXalu::ADD, DpCntl::Short, EAX, v.clone(), 17.into())).unwrap();
+            asm.gen(xaluir(Opcode::XALUIR, SubOpXalu::SHL, 4)).unwrap();
+            asm.gen(xaluir(Opcode::XALUIR, SubOpXalu::ADD, -16)).unwrap();
+            asm.gen(xaluir(Opcode::XALUI, SubOpXalu::SUB, c)).unwrap();
+            asm.gen(Instruction::cmps(EAX, EDX)).unwrap();
+            asm.gen(Instruction::setcc(Cond::LE, EDX)).unwrap();
+            asm.gen(Instruction::xl(Size::Bits32, ESP, ESP)).unwrap();
+            asm.gen(Instruction::xs(Size::Bits8, v.clone(), EAX)).unwrap();
+            asm.gen_load(v, 0xFFFF_FFFF).unwrap();
+            asm.gen_load(ECX, value).unwrap();
+            asm.gen_load_expr(EAX, table + 4).unwrap();
+            asm.gen(Instruction::xj(EAX)).unwrap();
+            asm.gen_jump(top).unwrap();
+            asm.gen_footer().unwrap();
+            asm.set_section(Section::RODATA).unwrap();
+            asm.gen_align(4).unwrap();
+            asm.set_sym_here(table).unwrap();
+            asm.gen_u32(7).unwrap();
+            asm.gen_u32(value).unwrap();
+            asm.gen_u32_expr(top.into()).unwrap();
+            asm.set_section(Section::TEXT).unwrap();
+            asm.gen(Instruction::i_type(Opcode::ADDI, EAX, EAX, 1)).unwrap();
+        }
+        asm
+    };
+
+    let (mut dsl, mut builder) = (build(true), build(false));
+    dsl.allocate().unwrap();
+    builder.allocate().unwrap();
+    assert_eq!(dsl.finish().unwrap().bytes(), builder.finish().unwrap().bytes());
+}
+
+#[test]
+fn payload_written_in_the_dsl() {
+    // Sum the words of a table until the zero at its end, in position independent code
+    let mut asm = DynAsm::new_pic("R5".into()).unwrap();
+    let (top, done, table) = (asm.new_sym(), asm.new_sym(), asm.new_sym());
+    let (ptr, word, total) = (asm.new_vreg(), asm.new_vreg(), asm.new_vreg());
+    ais!(asm =>
+        .header;
+        load {ptr}, table;
+        load {total}, 0;
+        top:
+        XL.Bits32 {word}, [{ptr}];
+    )
+    .unwrap();
+    asm.gen_branch_if(Cond::E, word.clone(), R0, done).unwrap();
+    ais!(asm =>
+        XALUR.ADD {total}, {total}, {word};
+        ADDI {ptr}, {ptr}, 4;
+        jump top;
+        done:
+        ORI EDX, {total}, 0;
+        .footer;
+        .rodata;
+        table:
+        .long 1;
+        .long 20;
+        .long 300;
+        .long 0;
+    )
+    .unwrap();
+
+    asm.allocate().unwrap();
+    asm.optimize();
+    let image = asm.finish().unwrap();
+    assert_eq!(common::run(image.bytes(), image.base()).edx(), 321);
+}
@@ -0,0 +1,55 @@
+// ELF objects: position independent code only, from the library and from the command line tool
+
+use ais_asm::ais::EAX;
+use ais_asm::elf::write_elf;
+use ais_asm::reloc::RelocKind;
+use ais_asm::{DynAsm, ElfError};
+
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+const SOURCE: &str = ".header\nload EAX, table\nXL.Bits32 EDX, [EAX]\n.footer\n.rodata\ntable:\n.long table\n";
+
+// Assemble SOURCE with the given options into a fresh directory, with the path of the object
+fn assemble(name: &str, options: &[&str]) -> (Output, PathBuf) {
+    let dir = std::env::temp_dir().join(format!("ais_asm_elf_{}_{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let source = dir.join("payload.s");
+    std::fs::write(&source, SOURCE).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_ais_asm"))
+        .args(["assemble", source.to_str().unwrap(), "--format", "elf"])
+        .args(options)
+        .output()
+        .unwrap();
+    (output, dir.join("payload.o"))
+}
+
+#[test]
+fn address_loads_have_no_i386_relocation() {
+    let mut asm = DynAsm::new(0x1000);
+    asm.set_relocatable(true);
+    let sym = asm.new_sym_here();
+    asm.gen_load_symbol(EAX, sym).unwrap();
+    let image = asm.finish().unwrap();
+
+    match write_elf(&image, &mut Vec::new()) {
+        Err(ElfError::UnsupportedReloc(x)) => assert_eq!((x.kind, x.offset), (RelocKind::LowImm, 0)),
+        x => panic!("expected an unsupported relocation, got {:?}", x),
+    }
+}
+
+#[test]
+fn cli_requires_pic_for_elf() {
+    let (output, object) = assemble("base", &["--base", "0x1000"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(2), "{}", stderr);
+    assert!(stderr.contains("--format elf needs --pic"), "{}", stderr);
+    assert!(!object.exists());
+    std::fs::remove_dir_all(object.parent().unwrap()).unwrap();
+
+    let (output, object) = assemble("pic", &["--pic", "R5"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(std::fs::read(&object).unwrap()[..4], *b"\x7FELF");
+    std::fs::remove_dir_all(object.parent().unwrap()).unwrap();
+}
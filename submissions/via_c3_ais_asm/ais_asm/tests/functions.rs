@@ -0,0 +1,168 @@
+// Functions: nested and recursive calls run on a model of AIS, the caller gets its registers and stack back
+
+mod common;
+
+use ais_asm::ais::Size;
+use ais_asm::dynasm::{FunctionBuilder, Section};
+use ais_asm::{DpCntl, DynAsm, DynAsmError, Instruction, Opcode, Register, Sym, SubOpXalu};
+
+fn alu(asm: &mut DynAsm, op: SubOpXalu, dst: &Register, a: &Register, b: &Register) -> Result<(), DynAsmError> {
+    asm.gen(Instruction::xalur(op, DpCntl::Word, dst.clone(), a.clone(), b.clone()))
+}
+
+fn finish_and_run(asm: DynAsm) -> common::Machine {
+    let image = asm.finish().unwrap();
+    let machine = common::run(image.bytes(), image.base());
+    assert!(machine.callee_saved_kept(), "{:X?}", machine.regs);
+    machine
+}
+
+#[test]
+fn nested_calls_keep_values() {
+    let mut asm = DynAsm::new(0x1000);
+    asm.gen_header().unwrap();
+
+    // add3 is generated after the code that calls it, kept lives across the call
+    let add3 = asm.new_named_sym("add3").unwrap();
+    let kept = asm.new_vreg();
+    asm.gen_load(kept.clone(), 100).unwrap();
+    let args: Vec<Register> = (1..=3).map(|_| asm.new_vreg()).collect();
+    for (x, arg) in args.iter().enumerate() {
+        asm.gen_load(arg.clone(), x as u32 + 1).unwrap();
+    }
+    let result = asm.gen_call(add3, &args).unwrap();
+    alu(&mut asm, SubOpXalu::ADD, &"EDX".into(), &result, &kept).unwrap();
+    asm.gen_footer().unwrap();
+
+    let add2 = asm
+        .function("add2", |f| {
+            let (a, b) = (f.arg(0)?, f.arg(1)?);
+            alu(f, SubOpXalu::ADD, &a, &a, &b)?;
+            f.ret(Some(a))
+        })
+        .unwrap();
+    asm.function("add3", |f| {
+        let (a, b, c) = (f.arg(0)?, f.arg(1)?, f.arg(2)?);
+        let sum = f.gen_call(add2, &[a, b])?;
+        let sum = f.gen_call(add2, &[sum, c])?;
+        f.ret(Some(sum))
+    })
+    .unwrap();
+
+    assert_eq!(finish_and_run(asm).edx(), 106);
+}
+
+// sum(n) = n + sum(n - 1), with a jump table to stop at 0
+fn sum(f: &mut FunctionBuilder, table: Sym) -> Result<(), DynAsmError> {
+    let n = f.arg(0)?;
+    let (target, index) = (f.new_vreg(), f.new_vreg());
+    f.gen_load(index.clone(), 2)?;
+    alu(f, SubOpXalu::SHL, &index, &n, &index)?;
+    f.gen_load_symbol(target.clone(), table)?;
+    alu(f, SubOpXalu::ADD, &target, &target, &index)?;
+    f.gen(Instruction::xl(Size::Bits32, target.clone(), target.clone()))?;
+    f.gen(Instruction::xj(target))?;
+
+    let (zero, more) = (f.new_named_sym("zero")?, f.new_named_sym("more")?);
+    f.set_sym_here(zero)?;
+    f.ret(Some(n.clone()))?;
+
+    f.set_sym_here(more)?;
+    let m = f.new_vreg();
+    f.gen(Instruction::i_type(Opcode::ADDI, m.clone(), n.clone(), 0xFFFF))?;
+    let own = f.sym();
+    let result = f.gen_call(own, &[m])?;
+    alu(f, SubOpXalu::ADD, &result, &result, &n)?;
+    f.ret(Some(result))
+}
+
+#[test]
+fn recursion_through_the_stack() {
+    // n lives across the recursive call. When the code names every callee saved register itself, it goes to a
+    // stack slot in the frame of each invocation instead.
+    for (pic, named) in [(false, false), (true, false), (false, true), (true, true)] {
+        let mut asm = match pic {
+            true => DynAsm::new_pic("R5".into()).unwrap(),
+            false => DynAsm::new(0x1000),
+        };
+
+        asm.gen_header().unwrap();
+        if named {
+            for reg in ["EBX", "EBP", "ESI", "EDI"] {
+                asm.gen(Instruction::i_type(Opcode::ORI, reg.into(), reg.into(), 0)).unwrap();
+            }
+        }
+        let function = asm.new_named_sym("sum").unwrap();
+        let n = asm.new_vreg();
+        asm.gen_load(n.clone(), 10).unwrap();
+        let result = asm.gen_call(function, &[n]).unwrap();
+        asm.gen(Instruction::i_type(Opcode::ORI, "EDX".into(), result, 0)).unwrap();
+        asm.gen_footer().unwrap();
+
+        let table = asm.new_sym();
+        asm.function("sum", |f| sum(f, table)).unwrap();
+
+        asm.set_section(Section::RODATA).unwrap();
+        asm.set_sym_here(table).unwrap();
+        let zero = asm.sym_by_name("zero").unwrap();
+        let more = asm.sym_by_name("more").unwrap();
+        asm.gen_u32_expr(zero.into()).unwrap();
+        for _ in 0..10 {
+            asm.gen_u32_expr(more.into()).unwrap();
+        }
+
+        let report = asm.allocate().unwrap();
+        assert_eq!((report.saved, report.spilled), if named { (0, 1) } else { (1, 0) }, "{}", report);
+        assert_eq!(finish_and_run(asm).edx(), 55);
+    }
+}
+
+#[test]
+fn functions_save_the_callee_saved_registers_they_write() {
+    let mut asm = DynAsm::new(0x1000);
+    asm.gen_header().unwrap();
+    let function = asm.new_named_sym("clobber").unwrap();
+    let result = asm.gen_call(function, &[]).unwrap();
+    asm.gen(Instruction::i_type(Opcode::ORI, "EDX".into(), result, 0)).unwrap();
+    asm.gen_footer().unwrap();
+
+    asm.function("clobber", |f| {
+        for reg in ["EBX", "EBP", "ESI", "EDI"] {
+            f.gen_load(reg.into(), 1)?;
+        }
+        f.gen_load("EAX".into(), 7)
+    })
+    .unwrap();
+
+    assert_eq!(finish_and_run(asm).edx(), 7);
+}
+
+#[test]
+fn arguments_in_argument_registers() {
+    // R2 and R1 trade places on the way in
+    let mut asm = DynAsm::new(0x1000);
+    asm.gen_header().unwrap();
+    asm.gen_load("R1".into(), 10).unwrap();
+    asm.gen_load("R2".into(), 3).unwrap();
+    let function = asm.new_named_sym("sub").unwrap();
+    let result = asm.gen_call(function, &["R2".into(), "R1".into()]).unwrap();
+    asm.gen(Instruction::i_type(Opcode::ORI, "EDX".into(), result, 0)).unwrap();
+    asm.gen_footer().unwrap();
+
+    asm.function("sub", |f| {
+        let (a, b) = (f.arg(0)?, f.arg(1)?);
+        alu(f, SubOpXalu::SUB, &a, &a, &b)?;
+        f.ret(Some(a))
+    })
+    .unwrap();
+    assert_eq!(finish_and_run(asm).edx(), 3u32.wrapping_sub(10));
+
+    let mut asm = DynAsm::new(0);
+    let args: Vec<Register> = (0..5).map(|_| asm.new_vreg()).collect();
+    let function = asm.new_sym();
+    assert!(matches!(asm.gen_call(function, &args), Err(DynAsmError::TooManyArguments(5))));
+    assert!(matches!(
+        asm.function("twice", |_| Ok(())).and_then(|_| asm.function("twice", |_| Ok(()))),
+        Err(DynAsmError::SymbolRedefined(_))
+    ));
+}
@@ -0,0 +1,130 @@
+// Layout of symbol loads: every load gets the shortest encoding its final value allows, and the symbols follow
+
+use ais_asm::{DynAsm, Image, Instruction, Opcode, Register};
+
+fn ori(dst: Register, imm: u16) -> Vec<u8> {
+    Instruction::i_type(Opcode::ORI, dst, 0.into(), imm).encode().unwrap()
+}
+
+fn oriu(dst: Register, src: Register, imm: u16) -> Vec<u8> {
+    Instruction::i_type(Opcode::ORIU, dst, src, imm).encode().unwrap()
+}
+
+// ORI and ORIU for both halves of value
+fn long(dst: Register, value: u32) -> Vec<u8> {
+    [ori(dst.clone(), value as u16), oriu(dst.clone(), dst, (value >> 16) as u16)].concat()
+}
+
+fn addr(image: &Image, name: &str) -> u32 {
+    image.symbol_table().iter().find(|x| x.name == name).unwrap().addr
+}
+
+#[test]
+fn loads_shrink_to_one_instruction() {
+    // Backward and forward reference to a symbol in the low 64K, one ORI each
+    let mut asm = DynAsm::new(0x1000);
+    let (back, ahead) = (asm.new_named_sym("back").unwrap(), asm.new_named_sym("ahead").unwrap());
+    asm.set_sym_here(back).unwrap();
+    asm.gen_load_symbol("EAX".into(), back).unwrap();
+    asm.gen_load_symbol("ECX".into(), ahead).unwrap();
+    asm.set_sym_here(ahead).unwrap();
+    let image = asm.finish().unwrap();
+    assert_eq!(addr(&image, "ahead"), 0x100C);
+    assert_eq!(image.bytes(), [ori("EAX".into(), 0x1000), ori("ECX".into(), 0x100C)].concat());
+
+    // Low half zero, only ORIU
+    let mut asm = DynAsm::new(0x20000);
+    let sym = asm.new_sym_here();
+    asm.gen_load_symbol("EAX".into(), sym).unwrap();
+    // The rest is padding up to the next section
+    assert_eq!(asm.finish().unwrap().bytes()[..6], oriu("EAX".into(), 0.into(), 2));
+
+    // With a relocation table the loads keep both halves, so any load address can be patched in
+    let mut asm = DynAsm::new(0x1000);
+    asm.set_relocatable(true);
+    let sym = asm.new_sym_here();
+    asm.gen_load_symbol("EAX".into(), sym).unwrap();
+    let image = asm.finish().unwrap();
+    assert_eq!(image.bytes(), long("EAX".into(), 0x1000));
+    assert_eq!(image.relocs().len(), 2);
+}
+
+#[test]
+fn load_grows_when_a_later_load_moves_its_symbol() {
+    // Short, near sits at 0xFFFC. far needs both halves, that pushes near over 64K, so its load grows as well.
+    let mut asm = DynAsm::new(0xFFE0);
+    let (near, far) = (asm.new_named_sym("near").unwrap(), asm.new_named_sym("far").unwrap());
+    asm.gen_load_symbol("EAX".into(), far).unwrap();
+    asm.gen_load_symbol("ECX".into(), near).unwrap();
+    asm.gen_zeros(0x10).unwrap();
+    asm.set_sym_here(near).unwrap();
+    asm.gen_zeros(0x100).unwrap();
+    asm.set_sym_here(far).unwrap();
+
+    let image = asm.finish().unwrap();
+    let (near, far) = (addr(&image, "near"), addr(&image, "far"));
+    assert_eq!((near, far), (0xFFE0 + 24 + 0x10, 0xFFE0 + 24 + 0x110));
+    assert_eq!(image.bytes()[..24], [long("EAX".into(), far), long("ECX".into(), near)].concat());
+    assert!(image.bytes()[24..].iter().all(|x| *x == 0));
+}
+
+#[test]
+fn align_pads_after_relaxation() {
+    // The load shrinks to 6 bytes, so 2 bytes of padding put the symbol at 8
+    let mut asm = DynAsm::new(0x1000);
+    let sym = asm.new_named_sym("aligned").unwrap();
+    asm.gen_load_symbol("EAX".into(), sym).unwrap();
+    asm.gen_align(8).unwrap();
+    asm.set_sym_here(sym).unwrap();
+    asm.gen_u32(0x12345678).unwrap();
+
+    let image = asm.finish().unwrap();
+    assert_eq!(addr(&image, "aligned"), 0x1008);
+    assert_eq!(image.bytes(), [ori("EAX".into(), 0x1008), vec![0; 2], 0x12345678u32.to_le_bytes().to_vec()].concat());
+
+    // Kept long the load takes 12 bytes, the padding becomes 4
+    let mut asm = DynAsm::new(0x1000);
+    asm.set_relocatable(true);
+    let sym = asm.new_named_sym("aligned").unwrap();
+    asm.gen_load_symbol("EAX".into(), sym).unwrap();
+    asm.gen_align(8).unwrap();
+    asm.set_sym_here(sym).unwrap();
+    let image = asm.finish().unwrap();
+    assert_eq!(addr(&image, "aligned"), 0x1010);
+    assert_eq!(image.bytes(), [long("EAX".into(), 0x1010), vec![0; 4]].concat());
+}
+
+#[test]
+fn symbols_match_the_final_layout() {
+    // A mix of short and long loads before and after every symbol, each load gets the address the table reports
+    let mut asm = DynAsm::new(0xFF00);
+    let names = ["a", "b", "c", "d"];
+    let syms: Vec<_> = names.iter().map(|x| asm.new_named_sym(x).unwrap()).collect();
+    for (i, sym) in syms.iter().enumerate() {
+        for other in &syms {
+            asm.gen_load_symbol("EAX".into(), *other).unwrap();
+        }
+        asm.gen_zeros(0x30).unwrap();
+        asm.set_sym_here(*sym).unwrap();
+        asm.gen_u32(i as u32).unwrap();
+    }
+
+    let image = asm.finish().unwrap();
+    let mut offset = 0;
+    for (i, name) in names.iter().enumerate() {
+        for other in names {
+            let value = addr(&image, other);
+            let expected = match value >> 16 {
+                0 => ori("EAX".into(), value as u16),
+                _ => long("EAX".into(), value),
+            };
+            assert_eq!(image.bytes()[offset..offset + expected.len()], expected, "{} in front of {}", other, name);
+            offset += expected.len();
+        }
+        offset += 0x30;
+        assert_eq!(image.base() + offset as u32, addr(&image, name));
+        assert_eq!(image.bytes()[offset..offset + 4], (i as u32).to_le_bytes());
+        offset += 4;
+    }
+    assert_eq!(offset, image.bytes().len());
+}
@@ -0,0 +1,145 @@
+// Listings: the annotated listing of an image, the disassembly of raw bytes and the field breakdown of one word
+
+use ais_asm::ais::{Size, EAX, ESP};
+use ais_asm::listing::{write_disasm, write_explain, write_listing};
+use ais_asm::parse::parse_into;
+use ais_asm::{DynAsm, Image, Instruction};
+
+const SOURCE: &str = "\
+.header
+top:
+ORI EAX, R0, 0x000B ; first
+XALUR.ADD.HH EAX, EAX, EDX
+XALUIR.SHL EDX, EAX, -3
+XALUR.CMPS R0, EAX, ECX
+XALUR.SETCC ECX, R0, NE
+XL.Bits32 EAX, [ESP+0]
+XS.Bits16 EDX, [EAX+0]
+XIOW.Bits8 EAX, [EDX+0]
+XJ EAX
+.footer
+.byte 1, 2, 3, 4, 5, 6, 7, 8
+.zero 3
+";
+
+fn assemble(source: &str) -> Image {
+    let mut asm = DynAsm::new(0x1000);
+    parse_into(&mut asm, source, "payload.s").unwrap();
+    asm.finish().unwrap()
+}
+
+fn text(write: impl FnOnce(&mut Vec<u8>) -> std::io::Result<()>) -> String {
+    let mut out = Vec::new();
+    write(&mut out).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+// Columns of a listing row: address, bytes, AIS, x86, label, source and comment
+fn columns(row: &str) -> Vec<String> {
+    let row = format!("{:<200}", row);
+    let bounds = [(0, 8), (10, 27), (29, 61), (63, 103), (105, 121), (123, 143), (145, 200)];
+    bounds.iter().map(|(a, b)| row[*a..*b].trim().to_string()).collect()
+}
+
+// AIS column of every row that has one
+fn ais_column(listing: &str) -> Vec<&str> {
+    listing.lines().skip(1).filter_map(|x| x.get(29..)?.split("  ").next()).filter(|x| !x.is_empty()).collect()
+}
+
+#[test]
+fn listing_rows() {
+    let listing = text(|w| write_listing(&assemble(SOURCE), w));
+    let rows: Vec<_> = listing.lines().collect();
+    assert_eq!(columns(rows[0]), ["Address", "Bytes", "AIS", "x86", "Label", "Source", "Comment"]);
+
+    // The header is x86 only, one row per instruction
+    assert_eq!(columns(rows[1]), ["00001000", "e8 00 00 00 00", "", "call $+5", "", "payload.s:1", ""]);
+    assert_eq!(columns(rows[4])[..4], ["00001009", "0f 3f", "", "jmpai eax"]);
+
+    let ori = columns(rows[5]);
+    let x86 = "bound eax,QWORD PTR [eax+0x3410000b]";
+    assert_eq!(ori, ["0000100B", "62 80 0b 00 10 34", "ORI EAX, R0, 0x000B", x86, "top", "payload.s:3", "; first"]);
+    assert_eq!(columns(rows[6])[2], "XALUR.ADD.HH EAX, EAX, EDX");
+    assert_eq!(columns(rows[10])[2], "XL.Bits32 EAX, [ESP+0]");
+    assert_eq!(columns(rows[14])[..4], ["00001041", "c3", "", "ret"]);
+
+    // One row per .byte, the zeros take no bytes
+    assert_eq!(columns(rows[15])[..3], ["00001042", "01", ".byte 0x01"]);
+    assert_eq!(columns(rows[15])[5], "payload.s:13");
+    assert_eq!(columns(rows[22])[..3], ["00001049", "08", ".byte 0x08"]);
+    assert_eq!(columns(rows[23])[..3], ["0000104A", "", ".zero 3"]);
+    assert_eq!(rows.len(), 24);
+
+    // Longer data in rows of 6 bytes, the source only on the first one
+    let mut asm = DynAsm::new(0);
+    asm.gen_bytes(&[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+    let listing = text(|w| write_listing(&asm.finish().unwrap(), w));
+    let rows: Vec<_> = listing.lines().skip(1).collect();
+    assert!(rows[0].starts_with("00000000  01 02 03 04 05 06  .byte 0x01, 0x02, 0x03, 0x04, 0x05, 0x06 "));
+    assert!(rows[0].ends_with(&format!("tests/listing.rs:{}", line!() - 4)));
+    assert_eq!(rows[1], "00000006  07 08              .byte 0x07, 0x08");
+}
+
+#[test]
+fn listing_parses_back() {
+    // Header and footer have no AIS view, the rest assembles to the same bytes
+    let source = SOURCE.replace(".header\n", "").replace(".footer\n", "");
+    let image = assemble(&source);
+    let listing = text(|w| write_listing(&image, w));
+    let ais = ais_column(&listing);
+    assert_eq!(ais.len(), 18);
+
+    let again = assemble(&ais.join("\n"));
+    assert_eq!(again.bytes(), image.bytes());
+    assert_eq!(again.size(), image.size());
+}
+
+#[test]
+fn disasm_without_symbols() {
+    let image = assemble(SOURCE);
+    let disasm = text(|w| write_disasm(image.bytes(), 0x1000, w));
+    let rows: Vec<_> = disasm.lines().collect();
+    assert_eq!(rows[0], "Address   Bytes              AIS                               x86");
+    assert_eq!(rows[1], "00001000  e8 00 00 00 00                                       call $+5");
+    let x86 = "bound eax,QWORD PTR [eax+0x3410000b]";
+    assert_eq!(columns(rows[5])[..4], ["0000100B", "62 80 0b 00 10 34", "ORI EAX, R0, 0x000B", x86]);
+    assert_eq!(columns(rows[12])[2], "XIOW.Bits8 EAX, [EDX+0]");
+    assert_eq!(rows[14], "00001041  c3                                                   ret");
+
+    // The zeros are only in memory, the bytes of the image end with the data
+    assert_eq!(rows[15], "00001042  01 02 03 04 05 06  .byte 0x01, 0x02, 0x03, 0x04, 0x05, 0x06");
+    assert_eq!(rows[16], "00001048  07 08 00 00 00 00  .byte 0x07, 0x08, 0x00, 0x00, 0x00, 0x00");
+
+    // A wrapper that doesn't decode is data, and disassembly goes on behind it
+    let bytes = [&[0x62, 0x80, 0, 0, 0, 4][..], &image.bytes()[0xB..0x11]].concat();
+    let disasm = text(|w| write_disasm(&bytes, 0, w));
+    let rows: Vec<_> = disasm.lines().skip(1).map(|x| columns(x)[2].clone()).collect();
+    assert_eq!(rows, ["(bad)", "ORI EAX, R0, 0x000B"]);
+
+    // Every AIS row parses back
+    let ais = ais_column(&text(|w| write_disasm(&image.bytes()[0xB..0x41], 0x100B, w))).join("\n");
+    assert_eq!(assemble(&ais).bytes()[..0x36], image.bytes()[0xB..0x41]);
+}
+
+#[test]
+fn explain_breaks_down_the_fields() {
+    let bytes = Instruction::xl(Size::Bits32, ESP, EAX).encode().unwrap();
+    let (instruction, _) = Instruction::decode(&bytes).unwrap();
+    let explain = text(|w| write_explain(&instruction, &bytes, w));
+    let expected = "\
+62 80 69 01 14 c2  XL.Bits32 EAX, [ESP+0]
+  x86       bound eax,QWORD PTR [eax-0x3debfe97]
+  word      0xC2140169
+  opcode    0o60 XL
+  rs        16 EAX
+  rt        20 ESP
+  function  Xls(Norm, Bits32, Bits32, Flat)
+";
+    assert_eq!(explain, expected);
+
+    let bytes = assemble("XALUR.ADD.HH EAX, EAX, EDX\n").bytes()[..6].to_vec();
+    let (instruction, _) = Instruction::decode(&bytes).unwrap();
+    let explain = text(|w| write_explain(&instruction, &bytes, w));
+    assert!(explain.ends_with("  sub op    0o20 ADD\n  dp cntl   HH\n"), "{}", explain);
+    assert!(explain.contains("  rd        16 EAX\n"), "{}", explain);
+}
@@ -0,0 +1,57 @@
+// Text front end: values that don't fit are errors, also from the command line tool
+
+use ais_asm::parse::{parse_into, ParseErrorKind};
+use ais_asm::DynAsm;
+
+use std::process::Command;
+
+const OVERFLOW: &str = ".header\nload EAX, 0x7FFFFFFFFFFFFFFF + 0x7FFFFFFFFFFFFFFF\n.footer\n";
+
+#[test]
+fn overflowing_values_are_out_of_range() {
+    for text in [
+        "load EAX, 0x7FFFFFFFFFFFFFFF + 0x7FFFFFFFFFFFFFFF",
+        "load EAX, -0x7FFFFFFFFFFFFFFF - 0x7FFFFFFFFFFFFFFF",
+    ] {
+        let mut asm = DynAsm::new(0);
+        let error = parse_into(&mut asm, text, "test.s").unwrap_err();
+        assert_eq!(error.line, 1);
+        assert!(matches!(error.kind, ParseErrorKind::OutOfRange(_)), "{}: {:?}", text, error);
+    }
+
+    let mut asm = DynAsm::new(0);
+    assert!(parse_into(&mut asm, "load EAX, 0x7FFFFFFF + 0x7FFFFFFF - 0x7FFFFFFF", "test.s").is_ok());
+}
+
+#[test]
+fn cli_reports_overflow_as_invalid_input() {
+    let dir = std::env::temp_dir().join(format!("ais_asm_parse_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let source = dir.join("overflow.s");
+    std::fs::write(&source, OVERFLOW).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_ais_asm"))
+        .args(["assemble", source.to_str().unwrap(), "-o"])
+        .arg(dir.join("overflow.bin"))
+        .output()
+        .unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(output.status.code(), Some(1), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+#[test]
+fn data_directives_only_take_values_that_fit() {
+    let bytes = |text: &str| {
+        let mut asm = DynAsm::new(0);
+        parse_into(&mut asm, text, "test.s").map(|_| asm.finish().unwrap().bytes().to_vec())
+    };
+    // The image is padded to the alignment of the section
+    assert_eq!(bytes(".byte 255, -128, -1").unwrap()[..3], [0xFF, 0x80, 0xFF]);
+    assert_eq!(bytes(".short 0xFFFF, -0x8000").unwrap(), [0xFF, 0xFF, 0x00, 0x80]);
+
+    let values = [(".byte 256", 256), (".byte -129", -129), (".short 0x10000", 0x10000), (".short -0x8001", -0x8001)];
+    for (text, value) in values {
+        let error = bytes(text).unwrap_err();
+        assert!(matches!(error.kind, ParseErrorKind::OutOfRange(x) if x == value), "{}: {:?}", text, error);
+    }
+}
@@ -0,0 +1,141 @@
+// Peephole optimiser: every program runs on a small model of AIS, with and without optimisation
+
+mod common;
+
+use ais_asm::{DpCntl, DynAsm, DynAsmError, Instruction, Opcode, SubOpXalu, Sym};
+
+// Same program without and with optimisation, the results must match
+fn compare(pic: bool, program: impl Fn(&mut DynAsm) -> Result<(), DynAsmError>) -> (ais_asm::dynasm::OptReport, u32) {
+    let new = || match pic {
+        true => DynAsm::new_pic("EBP".into()).unwrap(),
+        false => DynAsm::new(0x1000),
+    };
+
+    let mut plain = new();
+    program(&mut plain).unwrap();
+    let plain = plain.finish().unwrap();
+
+    let mut optimized = new();
+    program(&mut optimized).unwrap();
+    let report = optimized.optimize();
+    let optimized = optimized.finish().unwrap();
+
+    let result = common::run(plain.bytes(), plain.base()).edx();
+    assert_eq!(result, common::run(optimized.bytes(), optimized.base()).edx(), "{}", report);
+    // The report doesn't count padding
+    assert!(report.bytes_before as usize <= plain.bytes().len(), "{}", report);
+    assert!(report.bytes_after as usize <= optimized.bytes().len(), "{}", report);
+    (report, result)
+}
+
+fn add(asm: &mut DynAsm, dst: &str, src: &str) -> Result<(), DynAsmError> {
+    asm.gen(Instruction::xalur(SubOpXalu::ADD, DpCntl::Word, dst.into(), dst.into(), src.into()))
+}
+
+// Call with the return address in EBX
+fn call(asm: &mut DynAsm, function: Sym) -> Result<(), DynAsmError> {
+    let ret = asm.new_sym();
+    asm.gen_load_symbol("EBX".into(), ret)?;
+    asm.gen_jump(function)?;
+    asm.set_sym_here(ret)
+}
+
+#[test]
+fn repeated_jump_addresses_are_removed() {
+    for pic in [false, true] {
+        let (report, edx) = compare(pic, |asm| {
+            asm.gen_header()?;
+            asm.gen_load("EDX".into(), 0)?;
+            let function = asm.new_sym();
+            let end = asm.new_sym();
+            for x in 1..=5 {
+                asm.gen_load("ECX".into(), x)?;
+                call(asm, function)?;
+            }
+            asm.gen_jump(end)?;
+
+            asm.set_sym_here(function)?;
+            add(asm, "EDX", "ECX")?;
+            asm.gen(Instruction::xj("EBX".into()))?;
+
+            asm.set_sym_here(end)?;
+            asm.gen_footer()
+        });
+        assert_eq!(edx, 15);
+        assert_eq!(report.reloads, 4, "{}", report);
+    }
+}
+
+#[test]
+fn jump_to_next_and_dead_writes() {
+    let (report, edx) = compare(false, |asm| {
+        asm.gen_header()?;
+        let next = asm.new_sym();
+        asm.gen_jump(next)?;
+        asm.set_sym_here(next)?;
+        asm.gen_load("R4".into(), 7)?;
+        asm.gen_load("EDX".into(), 1)?;
+        add(asm, "EDX", "R4")?;
+        asm.gen_footer()
+    });
+    assert_eq!(edx, 8);
+    assert_eq!(report.jumps, 1, "{}", report);
+    assert_eq!(report.dead, 0, "{}", report);
+    // Only the XJ, its address stays loaded because every register is live at the symbol
+    assert_eq!(report.bytes_before - report.bytes_after, 6, "{}", report);
+
+    // The address for the removed jump is dead once R4 is loaded without a symbol in between
+    let (report, _) = compare(false, |asm| {
+        asm.gen_header()?;
+        let next = asm.new_sym();
+        asm.gen_jump(next)?;
+        asm.set_sym_here(next)?;
+        asm.gen_load("EDX".into(), 1)?;
+        asm.gen_load("EDX".into(), 2)?;
+        asm.gen_footer()
+    });
+    assert_eq!((report.jumps, report.dead), (1, 0), "{}", report);
+    assert_eq!(report.reloads + report.folded, 1, "{}", report);
+}
+
+#[test]
+fn constant_chains_are_folded() {
+    let (report, edx) = compare(false, |asm| {
+        asm.gen_header()?;
+        asm.gen_load("EDX".into(), 0x12345678)?;
+        asm.gen(Instruction::i_type(Opcode::ADDI, "EDX".into(), "EDX".into(), 0x1000))?;
+        asm.gen(Instruction::i_type(Opcode::XORIU, "EDX".into(), "EDX".into(), 0x1234))?;
+        asm.gen_footer()
+    });
+    assert_eq!(edx, 0x00006678);
+    assert_eq!(report.folded, 1, "{}", report);
+    // Four instructions become one, the padding before the aligned sections after the code doesn't count
+    assert_eq!(report.bytes_before - report.bytes_after, 18, "{}", report);
+}
+
+#[test]
+fn symbols_reached_with_other_values_keep_their_loads() {
+    // f is called once with EAX = 2 and entered once by falling through with EAX = 1
+    let (report, edx) = compare(true, |asm| {
+        asm.gen_header()?;
+        asm.gen_load("EDX".into(), 0)?;
+        let f = asm.new_sym();
+        let done = asm.new_sym();
+
+        asm.gen_load("EAX".into(), 2)?;
+        call(asm, f)?;
+        add(asm, "EDX", "EAX")?;
+        asm.gen_load("EAX".into(), 1)?;
+        asm.gen_load_symbol("EBX".into(), done)?;
+
+        asm.set_sym_here(f)?;
+        asm.gen_load("EAX".into(), 1)?;
+        add(asm, "EDX", "EAX")?;
+        asm.gen(Instruction::xj("EBX".into()))?;
+
+        asm.set_sym_here(done)?;
+        asm.gen_footer()
+    });
+    assert_eq!(edx, 3);
+    assert_eq!(report.bytes_before, report.bytes_after, "{}", report);
+}
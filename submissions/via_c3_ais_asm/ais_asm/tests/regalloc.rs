@@ -0,0 +1,131 @@
+// Register allocator: payloads with virtual registers run on a model of AIS, with and without spilling
+
+mod common;
+
+use ais_asm::dynasm::AllocReport;
+use ais_asm::{DpCntl, DynAsm, DynAsmError, Instruction, Opcode, Recovery, Register, SubOpXalu};
+
+fn add(asm: &mut DynAsm, dst: &Register, a: &Register, b: &Register) -> Result<(), DynAsmError> {
+    asm.gen(Instruction::xalur(SubOpXalu::ADD, DpCntl::Word, dst.clone(), a.clone(), b.clone()))
+}
+
+// Loads 1..=count into fresh values, then sums them into EDX. All of them are live at the same time.
+fn sum(asm: &mut DynAsm, count: u32) -> Result<(), DynAsmError> {
+    asm.gen_header()?;
+    let values: Vec<Register> = (1..=count).map(|_| asm.new_vreg()).collect();
+    for (x, value) in values.iter().enumerate() {
+        asm.gen_load(value.clone(), x as u32 + 1)?;
+    }
+    let total = asm.new_vreg();
+    asm.gen_load(total.clone(), 0)?;
+    for value in values.iter().rev() {
+        add(asm, &total, &total, value)?;
+    }
+    asm.gen(Instruction::i_type(Opcode::ORI, "EDX".into(), total, 0))?;
+    asm.gen_footer()
+}
+
+// Allocate, run and check that ESP is back where it started
+fn allocate_and_run(mut asm: DynAsm) -> (AllocReport, common::Machine) {
+    let report = asm.allocate().unwrap();
+    let image = asm.finish().unwrap();
+    let machine = common::run(image.bytes(), image.base());
+    assert_eq!(machine.regs[20], common::STACK, "{}", report);
+    (report, machine)
+}
+
+#[test]
+fn values_get_registers_from_the_pool() {
+    let mut asm = DynAsm::new(0x1000);
+    sum(&mut asm, 8).unwrap();
+    let (report, machine) = allocate_and_run(asm);
+
+    assert_eq!(machine.edx(), 36);
+    assert_eq!((report.vregs, report.registers, report.spilled, report.frame), (9, 9, 0, 0), "{}", report);
+}
+
+#[test]
+fn values_spill_to_the_stack() {
+    for pic in [false, true] {
+        let mut asm = match pic {
+            true => DynAsm::new_pic("R5".into()).unwrap(),
+            false => DynAsm::new(0x1000),
+        };
+        asm.set_alloc_pool(&["R6".into(), "R7".into(), "R8".into(), "R9".into(), "R10".into()])
+            .unwrap();
+        sum(&mut asm, 12).unwrap();
+        let (report, machine) = allocate_and_run(asm);
+
+        assert_eq!(machine.edx(), 78);
+        assert!(report.spilled > 0, "{}", report);
+        assert_eq!(report.frame, 4 * report.spilled as u32);
+        assert_eq!(report.registers, 5);
+        assert_eq!(machine.memory.len(), report.spilled);
+    }
+}
+
+#[test]
+fn values_survive_pseudo_calls() {
+    let mut asm = DynAsm::new(0x1000);
+    asm.set_alloc_pool(&["R6".into(), "R7".into(), "R8".into()]).unwrap();
+    asm.gen_header().unwrap();
+    asm.gen_load("EDX".into(), 0).unwrap();
+
+    // kept is live across the call, so the function can't get its register
+    let kept = asm.new_vreg();
+    asm.gen_load(kept.clone(), 100).unwrap();
+    let function = asm.new_sym();
+    let ret = asm.new_sym();
+    asm.gen_load_symbol("EBX".into(), ret).unwrap();
+    asm.gen_jump(function).unwrap();
+    asm.set_sym_here(ret).unwrap();
+    add(&mut asm, &"EDX".into(), &"EDX".into(), &kept).unwrap();
+    let end = asm.new_sym();
+    asm.gen_jump(end).unwrap();
+
+    asm.set_sym_here(function).unwrap();
+    let (a, b) = (asm.new_vreg(), asm.new_vreg());
+    asm.gen_load(a.clone(), 20).unwrap();
+    asm.gen_load(b.clone(), 3).unwrap();
+    add(&mut asm, &a, &a, &b).unwrap();
+    add(&mut asm, &"EDX".into(), &"EDX".into(), &a).unwrap();
+    asm.gen(Instruction::xj("EBX".into())).unwrap();
+
+    asm.set_sym_here(end).unwrap();
+    asm.gen_footer().unwrap();
+    let (report, machine) = allocate_and_run(asm);
+
+    assert_eq!(machine.edx(), 123);
+    assert_eq!((report.spilled, report.registers), (0, 3), "{}", report);
+}
+
+#[test]
+fn callee_saved_and_named_registers_are_kept() {
+    let mut asm = DynAsm::new(0);
+    for reg in ["EBX", "ESP", "EBP", "ESI", "EDI", "R0"] {
+        assert!(matches!(asm.set_alloc_pool(&[reg.into()]), Err(DynAsmError::NotAllocatable(_))));
+    }
+
+    // R1 is named by the code, so values only get R2
+    asm.set_alloc_pool(&["R1".into(), "R2".into()]).unwrap();
+    let value = asm.new_vreg();
+    asm.gen_load("R1".into(), 7).unwrap();
+    asm.gen_load(value.clone(), 5).unwrap();
+    add(&mut asm, &"EDX".into(), &"R1".into(), &value).unwrap();
+    let image = asm.finish().unwrap();
+
+    let written: Vec<u32> = Instruction::iter(image.bytes(), Recovery::Stop)
+        .map(|(_, x)| x.unwrap())
+        .map(|x| x.rt.unwrap().bits().unwrap())
+        .take(2)
+        .collect();
+    assert_eq!(written, [1, 2]);
+}
+
+#[test]
+fn spilling_needs_two_registers() {
+    let mut asm = DynAsm::new(0);
+    asm.set_alloc_pool(&["R6".into()]).unwrap();
+    sum(&mut asm, 2).unwrap();
+    assert!(matches!(asm.allocate(), Err(DynAsmError::OutOfRegisters)));
+}
@@ -0,0 +1,182 @@
+// Relocation tables: a payload moved with its table equals one assembled there, and fields patch like the encoder
+
+use ais_asm::ais::{Const, EAX, ECX, EDX, R0};
+use ais_asm::dynasm::{Section, SymRefKind};
+use ais_asm::reloc::{self, BitField, RelocEntry, RelocKind};
+use ais_asm::{DpCntl, DynAsm, Image, Instruction, Opcode, SubOpXalu};
+
+// Every kind of reference to symbols in three sections
+fn build(base: u32) -> Image {
+    let mut asm = DynAsm::new(base);
+    asm.set_relocatable(true);
+    let (code, table, data) = (asm.new_sym(), asm.new_sym(), asm.new_sym());
+
+    asm.set_sym_here(code).unwrap();
+    asm.gen_load_symbol(EAX, table).unwrap();
+    asm.gen_load_expr(ECX, data + 0x7FF0).unwrap();
+    asm.gen_load_expr_addi(EDX, data - 4).unwrap();
+    asm.gen_i_type_expr(Opcode::ORIU, EAX, R0, table + 0x8000, SymRefKind::HighImmAdj).unwrap();
+    asm.gen_i_type_expr(Opcode::ADDI, EAX, EAX, table + 0x8000, SymRefKind::LowImm).unwrap();
+    asm.gen_i_type_expr(Opcode::ORIU, EAX, R0, code.into(), SymRefKind::HighImm).unwrap();
+    asm.gen_word_expr(0xA5A5_A5A5, data.into(), SymRefKind::LowImm, BitField::new(4, 12).unwrap()).unwrap();
+    // Position independent already, no relocation
+    asm.gen_load_expr(EAX, data - code).unwrap();
+    asm.gen_load(ECX, 0x1234_5678).unwrap();
+
+    asm.set_section(Section::RODATA).unwrap();
+    asm.gen_align(4).unwrap();
+    asm.set_sym_here(table).unwrap();
+    asm.gen_u32_expr(code.into()).unwrap();
+    asm.gen_u32_expr(data + 8).unwrap();
+    asm.gen_u32_expr(table - code).unwrap();
+
+    asm.set_section(Section::DATA).unwrap();
+    asm.set_sym_here(data).unwrap();
+    asm.gen_u32_expr(data.into()).unwrap();
+    asm.gen_zeros(0x20).unwrap();
+    asm.finish().unwrap()
+}
+
+#[test]
+fn relocated_payload_matches_direct_assembly() {
+    const A: u32 = 0x1000;
+    let image = build(A);
+    let table = image.reloc_table();
+
+    // Bases with a low half of 0x8000 and beyond, so the adjusted high halves round differently
+    for b in [A, 0x0002_0000, 0x0123_8000, 0x7FFF_F000, 0xFFFF_0000] {
+        let mut moved = image.bytes().to_vec();
+        reloc::relocate(&mut moved, &table, b).unwrap();
+        assert_eq!(moved, build(b).bytes(), "0x{:08X}", b);
+    }
+}
+
+#[test]
+fn parse_reads_back_the_table() {
+    let image = build(0x1000);
+    let table = image.reloc_table();
+    let (base, entries) = reloc::parse(&table).unwrap();
+    let entries: Vec<_> = entries.map(|x| x.unwrap()).collect();
+
+    assert_eq!(base, 0x1000);
+    // 2 + 2 + 2 load halves, 3 immediates, 1 custom field and 3 data words
+    assert_eq!(entries.len(), 13);
+    assert_eq!(entries.len(), image.relocs().len());
+    for (entry, reloc) in entries.iter().zip(image.relocs()) {
+        assert_eq!(reloc::encode_entry(entry), reloc::encode_entry(reloc));
+    }
+
+    // The word with the custom field keeps the bits outside of it
+    let custom = entries.iter().find(|x| x.field != x.kind.default_field()).unwrap();
+    assert_eq!(custom.field, BitField::new(4, 12).unwrap());
+    let at = custom.offset as usize + 2;
+    let word = u32::from_le_bytes(image.bytes()[at..at + 4].try_into().unwrap());
+    assert_eq!(word, 0xA5A5_0005 | (custom.value & 0xFFF) << 4);
+
+    // Entries of loads and immediates point at instructions that decode, the data words at the value
+    for entry in entries.iter().filter(|x| x.field == x.kind.default_field()) {
+        let at = entry.offset as usize;
+        match entry.kind {
+            RelocKind::Word => assert_eq!(image.bytes()[at..at + 4], entry.value.to_le_bytes()),
+            _ => assert!(Instruction::decode(&image.bytes()[at..]).is_ok()),
+        }
+    }
+}
+
+#[test]
+fn broken_tables_are_rejected() {
+    let table = build(0x1000).reloc_table();
+    let mut payload = build(0x1000).bytes().to_vec();
+
+    assert!(matches!(reloc::parse(&table[..8]), Err(reloc::RelocError::Truncated)));
+    assert!(matches!(reloc::relocate(&mut payload, &table[..table.len() - 1], 0), Err(reloc::RelocError::Truncated)));
+    let mut bad = table.clone();
+    bad[0] = b'X';
+    assert!(matches!(reloc::parse(&bad), Err(reloc::RelocError::BadMagic)));
+
+    // The last entry is the data word in front of the 0x20 zeros, one byte less and it is cut off
+    let len = payload.len();
+    assert!(matches!(reloc::relocate(&mut payload[..len - 0x21], &table, 0), Err(reloc::RelocError::OutOfBounds(_))));
+}
+
+#[test]
+fn fields_replace_only_their_bits() {
+    assert_eq!(BitField::new(0, 16), Some(BitField::IMM));
+    assert_eq!(BitField::new(0, 32), Some(BitField::WORD));
+    assert_eq!(BitField::new(0, 0), None);
+    assert_eq!(BitField::new(30, 3), None);
+    assert_eq!(BitField::new(31, 1).unwrap().insert(0, 3), 0x8000_0000);
+
+    assert_eq!(BitField::IMM.insert(0xAAAA_AAAA, 0x1234_5678), 0xAAAA_5678);
+    assert_eq!(BitField::WORD.insert(0xAAAA_AAAA, 0x1234_5678), 0x1234_5678);
+    assert_eq!(BitField::new(4, 8).unwrap().insert(0xFFFF_FFFF, 0), 0xFFFF_F00F);
+    assert_eq!(BitField::new(16, 5).unwrap().insert(0, 0xFFFD), 0x001D_0000);
+}
+
+#[test]
+fn patch_matches_the_encoder() {
+    let ori = |imm| Instruction::i_type(Opcode::ORI, EAX, ECX, imm).encode().unwrap();
+    let value = 0x1234_ABCD;
+    for (kind, imm) in [(RelocKind::HighImm, 0x1234), (RelocKind::LowImm, 0xABCD), (RelocKind::HighImmAdj, 0x1235)] {
+        let mut bytes = ori(0xDEAD);
+        reloc::patch(&mut bytes, 0, kind, kind.default_field(), value).unwrap();
+        assert_eq!(bytes, ori(imm), "{:?}", kind);
+    }
+
+    // The constant of XALUI is 5 bits in rt, a field there encodes it like the constructor
+    let xaluir = |c| Instruction::xaluir(SubOpXalu::ADD, DpCntl::Word, EAX, EDX, Const::Number(c)).encode().unwrap();
+    let mut bytes = xaluir(0);
+    reloc::patch(&mut bytes, 0, RelocKind::LowImm, BitField::new(16, 5).unwrap(), -3i32 as u32).unwrap();
+    assert_eq!(bytes, xaluir(-3));
+
+    // Data words at any offset, and nothing written past the end
+    let mut bytes = [0x11; 7];
+    reloc::patch(&mut bytes, 3, RelocKind::Word, BitField::WORD, value).unwrap();
+    assert_eq!(bytes, [0x11, 0x11, 0x11, 0xCD, 0xAB, 0x34, 0x12]);
+    assert_eq!(reloc::patch(&mut bytes, 4, RelocKind::Word, BitField::WORD, value), None);
+    assert_eq!(reloc::patch(&mut bytes, 2, RelocKind::LowImm, BitField::IMM, value), None);
+    assert_eq!(reloc::patch(&mut bytes, usize::MAX, RelocKind::LowImm, BitField::IMM, value), None);
+}
+
+#[test]
+fn width_zero_means_the_default_field() {
+    let entry = |kind, field| RelocEntry {
+        offset: 0,
+        value: 0x1000,
+        kind,
+        section: 0,
+        field,
+    };
+    let table = |entries: &[[u8; reloc::ENTRY_SIZE]]| {
+        let mut table = reloc::MAGIC.to_vec();
+        table.extend_from_slice(&0x1000u32.to_le_bytes());
+        table.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+        entries.iter().for_each(|x| table.extend_from_slice(x));
+        table
+    };
+
+    // Width and shift 0 in the table, like a table written without fields
+    let mut imm = reloc::encode_entry(&entry(RelocKind::LowImm, BitField::IMM));
+    let mut word = reloc::encode_entry(&entry(RelocKind::Word, BitField::WORD));
+    imm[10..12].fill(0);
+    word[10..12].fill(0);
+    let both = table(&[imm, word]);
+    let (_, entries) = reloc::parse(&both).unwrap();
+    let fields: Vec<_> = entries.map(|x| x.unwrap().field).collect();
+    assert_eq!(fields, [BitField::IMM, BitField::WORD]);
+
+    // Same result as the explicit fields, both patch the whole immediate or word
+    let mut bytes = Instruction::i_type(Opcode::ORI, EAX, R0, 0x1000).encode().unwrap();
+    reloc::relocate(&mut bytes, &table(&[imm]), 0x2000).unwrap();
+    assert_eq!(bytes, Instruction::i_type(Opcode::ORI, EAX, R0, 0x2000).encode().unwrap());
+    let mut bytes = 0x1000u32.to_le_bytes();
+    reloc::relocate(&mut bytes, &table(&[word]), 0x0123_4000).unwrap();
+    assert_eq!(bytes, 0x0123_4000u32.to_le_bytes());
+
+    // Other widths are checked against the word
+    let mut bad = imm;
+    bad[10..12].copy_from_slice(&[30, 4]);
+    let bad = table(&[bad]);
+    let (_, mut entries) = reloc::parse(&bad).unwrap();
+    assert!(matches!(entries.next(), Some(Err(reloc::RelocError::BadField(30, 4)))));
+}
@@ -0,0 +1,84 @@
+// Scratch registers of the helpers: live registers are never clobbered, and a register passed per call wins
+
+use ais_asm::ais::{ECX, EDX};
+use ais_asm::{DynAsm, DynAsmError, Instruction, Opcode, Register};
+
+// Jump from 0x1000 to the symbol right after it, through reg
+fn jump(reg: Register) -> Vec<u8> {
+    let load = Instruction::i_type(Opcode::ORI, reg.clone(), 0.into(), 0x100C);
+    [load.encode().unwrap(), Instruction::xj(reg).encode().unwrap()].concat()
+}
+
+fn gen_jump(asm: DynAsm, via: Option<Register>) -> Result<Vec<u8>, DynAsmError> {
+    let mut asm = asm;
+    let target = asm.new_sym();
+    match via {
+        Some(reg) => asm.gen_jump_via(target, reg)?,
+        None => asm.gen_jump(target)?,
+    }
+    asm.set_sym_here(target)?;
+    Ok(asm.finish()?.bytes()[..12].to_vec())
+}
+
+fn is_live(result: Result<Vec<u8>, DynAsmError>, reg: Register) -> bool {
+    matches!(result, Err(DynAsmError::ScratchLive(x)) if x == reg)
+}
+
+#[test]
+fn default_scratch_register() {
+    assert_eq!(gen_jump(DynAsm::new(0x1000), None).unwrap(), jump("R4".into()));
+
+    let mut asm = DynAsm::new(0x1000);
+    asm.set_live("R4".into()).unwrap();
+    assert!(is_live(gen_jump(asm, None), "R4".into()));
+}
+
+#[test]
+fn first_free_scratch_register_is_used() {
+    let mut asm = DynAsm::new(0x1000);
+    asm.set_scratch(&["R4".into(), ECX, EDX]);
+    asm.set_live("R4".into()).unwrap();
+    asm.set_live(ECX).unwrap();
+    assert_eq!(gen_jump(asm, None).unwrap(), jump(EDX));
+
+    // All of them live, the error names the preferred one
+    let mut asm = DynAsm::new(0x1000);
+    asm.set_scratch(&["R4".into(), ECX]);
+    asm.set_live(ECX).unwrap();
+    asm.set_live("R4".into()).unwrap();
+    assert!(is_live(gen_jump(asm, None), "R4".into()));
+
+    // Released again
+    let mut asm = DynAsm::new(0x1000);
+    asm.set_live("R4".into()).unwrap();
+    asm.set_dead("R4".into()).unwrap();
+    assert_eq!(gen_jump(asm, None).unwrap(), jump("R4".into()));
+
+    let mut asm = DynAsm::new(0x1000);
+    asm.set_scratch(&[]);
+    assert!(matches!(gen_jump(asm, None), Err(DynAsmError::NoScratchRegister)));
+}
+
+#[test]
+fn register_per_call_overrides_the_scratch_list() {
+    // The default scratch register is live, the one passed to the call is used instead
+    let mut asm = DynAsm::new(0x1000);
+    asm.set_live("R4".into()).unwrap();
+    assert_eq!(gen_jump(asm, Some(ECX)).unwrap(), jump(ECX));
+
+    // Also when it isn't on the scratch list at all, but never when it is live
+    let mut asm = DynAsm::new(0x1000);
+    asm.set_scratch(&[]);
+    assert_eq!(gen_jump(asm, Some(EDX)).unwrap(), jump(EDX));
+
+    let mut asm = DynAsm::new(0x1000);
+    asm.set_live(EDX).unwrap();
+    assert!(is_live(gen_jump(asm, Some(EDX)), EDX));
+}
+
+#[test]
+fn functions_need_a_free_scratch_register() {
+    let mut asm = DynAsm::new(0x1000);
+    asm.set_live("R4".into()).unwrap();
+    assert!(matches!(asm.function("f", |_| Ok(())), Err(DynAsmError::ScratchLive(x)) if x == "R4".into()));
+}
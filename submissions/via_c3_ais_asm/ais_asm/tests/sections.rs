@@ -0,0 +1,159 @@
+// Sections: placed in order and aligned at the base, data directives, and .bss that only takes memory at runtime
+
+use ais_asm::ais::{EAX, R0};
+use ais_asm::dynasm::Section;
+use ais_asm::elf::write_elf;
+use ais_asm::listing::write_listing;
+use ais_asm::parse::parse_into;
+use ais_asm::{DynAsm, DynAsmError, Image, Instruction, Opcode};
+
+fn addr(image: &Image, name: &str) -> u32 {
+    image.symbol_table().iter().find(|x| x.name == name).unwrap().addr
+}
+
+// Three bytes of code, a string, an aligned word and a buffer, emitted out of order
+fn build() -> DynAsm {
+    let mut asm = DynAsm::new(0x1000);
+    let names = ["code", "text", "word", "buffer", "end"];
+    let [code, text, word, buffer, end] = names.map(|x| asm.new_named_sym(x).unwrap());
+
+    asm.set_section(Section::BSS).unwrap();
+    asm.set_sym_here(buffer).unwrap();
+    asm.gen_zeros(0x40).unwrap();
+    asm.set_section(Section::DATA).unwrap();
+    asm.gen_u8(0xAA).unwrap();
+    asm.gen_align(16).unwrap();
+    asm.set_sym_here(word).unwrap();
+    asm.gen_u32(0x1234_5678).unwrap();
+    asm.set_section(Section::TEXT).unwrap();
+    asm.set_sym_here(code).unwrap();
+    asm.gen_u8(1).unwrap();
+    asm.gen_u16(0x0302).unwrap();
+    asm.set_section(Section::RODATA).unwrap();
+    asm.set_sym_here(text).unwrap();
+    asm.gen_bytes(b"AIS\0").unwrap();
+    asm.gen_u8(0xBB).unwrap();
+    asm.set_section(Section::BSS).unwrap();
+    asm.gen_u32(0).unwrap();
+    asm.set_sym_here(end).unwrap();
+    asm
+}
+
+#[test]
+fn sections_are_placed_in_order_and_aligned() {
+    let image = build().finish().unwrap();
+    let sections: Vec<_> = image.sections().iter().map(|x| (x.name.as_str(), x.addr, x.size, x.align)).collect();
+    assert_eq!(
+        sections,
+        [
+            (".text", 0x1000, 3, 1),
+            (".rodata", 0x1004, 5, 4),
+            // gen_align raised the alignment of the section
+            (".data", 0x1010, 20, 16),
+            (".bss", 0x1024, 0x44, 4),
+        ]
+    );
+
+    assert_eq!(addr(&image, "code"), 0x1000);
+    assert_eq!(addr(&image, "text"), 0x1004);
+    assert_eq!(addr(&image, "word"), 0x1020);
+    let sections: Vec<_> = image.symbol_table().iter().map(|x| x.section.as_str()).collect();
+    assert_eq!(sections, [".text", ".rodata", ".data", ".bss", ".bss"]);
+
+    let bytes = image.bytes();
+    assert_eq!(bytes[..4], [1, 2, 3, 0]);
+    assert_eq!(bytes[4..9], *b"AIS\0\xBB");
+    assert_eq!(bytes[0x10], 0xAA);
+    assert!(bytes[0x11..0x20].iter().all(|x| *x == 0));
+    assert_eq!(bytes[0x20..0x24], 0x1234_5678u32.to_le_bytes());
+}
+
+#[test]
+fn bss_takes_memory_but_no_bytes() {
+    let image = build().finish().unwrap();
+    assert_eq!(image.bytes().len(), 0x24);
+    assert_eq!(image.size(), 0x24 + 0x44);
+    assert_eq!(addr(&image, "buffer"), 0x1024);
+    assert_eq!(addr(&image, "end"), 0x1068);
+    assert!(image.bytes_at(0x1024, 0x44).is_empty());
+
+    // The listing still shows the buffer
+    let mut listing = Vec::new();
+    write_listing(&image, &mut listing).unwrap();
+    assert!(String::from_utf8(listing).unwrap().contains(".zero 64"));
+
+    // Only zeros go in .bss, no data or code
+    let mut asm = build();
+    let buffer = asm.sym_by_name("buffer").unwrap();
+    assert!(matches!(asm.gen_u8(1), Err(DynAsmError::NoBitsData(x)) if x == ".bss"));
+    assert!(matches!(asm.gen_u32_expr(buffer.into()), Err(DynAsmError::NoBitsData(_))));
+    assert!(matches!(asm.gen_load_symbol(EAX, buffer), Err(DynAsmError::NoBitsData(_))));
+
+    // Code can take the address of the buffer, the text section grows by the ORI
+    asm.set_section(Section::TEXT).unwrap();
+    asm.gen_load_symbol(EAX, buffer).unwrap();
+    let image = asm.finish().unwrap();
+    assert_eq!(addr(&image, "buffer"), 0x1034);
+    assert_eq!(image.bytes()[3..9], Instruction::i_type(Opcode::ORI, EAX, R0, 0x1034).encode().unwrap());
+}
+
+#[test]
+fn added_sections_follow_bss() {
+    let mut asm = build();
+    assert!(matches!(asm.add_section(".data", 4, false), Err(DynAsmError::DuplicateSection(_))));
+    let table = asm.add_section(".table", 8, false).unwrap();
+    assert_eq!(asm.section_by_name(".table"), Some(table));
+    asm.set_section(table).unwrap();
+    asm.gen_u32(7).unwrap();
+
+    // Data after .bss, so the buffer is in the image as zeros
+    let image = asm.finish().unwrap();
+    let table = image.sections().last().unwrap();
+    assert_eq!((table.name.as_str(), table.addr), (".table", 0x1068));
+    assert_eq!(image.bytes().len(), 0x6C);
+    assert!(image.bytes()[0x24..0x68].iter().all(|x| *x == 0));
+    assert_eq!(image.size(), 0x6C);
+}
+
+#[test]
+fn directives_build_the_same_sections() {
+    let source = "\
+.bss
+buffer:
+.zero 0x40
+.data
+.byte 0xAA
+.align 16
+word:
+.long 0x12345678
+.text
+code:
+.byte 1
+.short 0x0302
+.rodata
+text:
+.byte 0x41, 0x49, 0x53, 0
+.byte 0xBB
+.bss
+.long 0
+end:
+";
+    let mut asm = DynAsm::new(0x1000);
+    parse_into(&mut asm, source, "sections.s").unwrap();
+    let (parsed, built) = (asm.finish().unwrap(), build().finish().unwrap());
+    assert_eq!(parsed.bytes(), built.bytes());
+    assert_eq!(parsed.size(), built.size());
+    assert_eq!(addr(&parsed, "end"), addr(&built, "end"));
+}
+
+#[test]
+fn bss_is_zero_filled_in_objects() {
+    // The object has no nobits section, the buffer goes in .text.ais so its symbol stays inside
+    let image = build().finish().unwrap();
+    let mut object = Vec::new();
+    write_elf(&image, &mut object).unwrap();
+    // After the 52 byte header, aligned like .data
+    let text = &object[64..64 + image.size() as usize];
+    assert_eq!(text[..0x24], *image.bytes());
+    assert!(text[0x24..].iter().all(|x| *x == 0));
+}
@@ -0,0 +1,102 @@
+// Source locations: every byte of an image maps back to the Rust line, or the source file line, that generated it
+
+use ais_asm::ais::{Instruction, Opcode};
+use ais_asm::dynasm::{DynAsm, Image, SourceLoc};
+use ais_asm::parse::parse_into;
+
+// File and line that generated the byte at addr
+fn source(image: &Image, addr: u32) -> (&'static str, u32) {
+    let source = image.source_at(addr).unwrap();
+    (source.file, source.line)
+}
+
+// Reports the line of its caller
+#[track_caller]
+fn gen_clear(asm: &mut DynAsm) {
+    asm.gen(Instruction::i_type(Opcode::ORI, "EAX".into(), 0.into(), 0)).unwrap();
+    asm.gen(Instruction::i_type(Opcode::ORI, "EDX".into(), 0.into(), 0)).unwrap();
+}
+
+// Without the attribute the line in here is reported
+fn gen_plain(asm: &mut DynAsm) -> u32 {
+    let line = line!() + 1;
+    asm.gen(Instruction::i_type(Opcode::ORI, "ECX".into(), 0.into(), 0)).unwrap();
+    line
+}
+
+#[test]
+fn instructions_report_the_calling_line() {
+    let mut asm = DynAsm::new(0x1000);
+    let sym = asm.new_sym();
+
+    let direct = line!() + 1;
+    asm.gen(Instruction::i_type(Opcode::ADDI, "EAX".into(), "EAX".into(), 1)).unwrap();
+    let helper = line!() + 1;
+    gen_clear(&mut asm);
+    let plain = gen_plain(&mut asm);
+    let load = line!() + 1;
+    asm.gen_load_expr("EAX".into(), sym + 0x10000).unwrap();
+    let data = line!() + 1;
+    asm.gen_u32(7).unwrap();
+    asm.set_sym_here(sym).unwrap();
+
+    let image = asm.finish().unwrap();
+    let file = file!();
+    // The load takes both halves, 0x101E is its ORIU
+    let expected = [
+        (0x1000, direct),
+        (0x1005, direct),
+        (0x1006, helper),
+        (0x100C, helper),
+        (0x1012, plain),
+        (0x1018, load),
+        (0x101E, load),
+        (0x1024, data),
+        (0x1027, data),
+    ];
+    for (addr, line) in expected {
+        assert_eq!(source(&image, addr), (file, line), "0x{:X}", addr);
+    }
+    assert!(image.source_at(0xFFF).is_none());
+    assert!(image.source_at(0x1028).is_none());
+}
+
+#[test]
+fn lines_cover_the_payload_in_order() {
+    let mut asm = DynAsm::new(0);
+    let first = line!() + 1;
+    asm.gen_u32(1).unwrap();
+    asm.comment("second");
+    let second = line!() + 1;
+    asm.gen_u16(2).unwrap();
+
+    let image = asm.finish().unwrap();
+    let lines: Vec<_> = image.lines().iter().filter(|x| x.len > 0).map(|x| (x.addr, x.len, x.source.line)).collect();
+    assert_eq!(lines, [(0, 4, first), (4, 2, second)]);
+    assert_eq!(image.line_at(5).unwrap().comment.as_deref(), Some("second"));
+}
+
+#[test]
+fn set_source_overrides_the_caller() {
+    let mut asm = DynAsm::new(0);
+    asm.set_source(Some(SourceLoc { file: "gen.s", line: 7 }));
+    asm.gen_u32(1).unwrap();
+    asm.set_source(None);
+    let line = line!() + 1;
+    asm.gen_u32(2).unwrap();
+
+    let image = asm.finish().unwrap();
+    assert_eq!(source(&image, 0), ("gen.s", 7));
+    assert_eq!(source(&image, 4), (file!(), line));
+}
+
+#[test]
+fn parsed_lines_report_the_source_file() {
+    let mut asm = DynAsm::new(0x2000);
+    parse_into(&mut asm, "ORI EAX, R0, 1\n\n; comment\nADDI EAX, EAX, 2\n.long 3\n", "payload.s").unwrap();
+
+    let image = asm.finish().unwrap();
+    assert_eq!(source(&image, 0x2000), ("payload.s", 1));
+    assert_eq!(source(&image, 0x2006), ("payload.s", 4));
+    assert_eq!(source(&image, 0x200F), ("payload.s", 5));
+}
@@ -0,0 +1,145 @@
+// Structured control flow: if/else, loops and switches run on a model of AIS, with and without PIC
+
+mod common;
+
+use ais_asm::ais::Size;
+use ais_asm::dynasm::Section;
+use ais_asm::{Cond, DpCntl, DynAsm, Instruction, Opcode, Register, Structured, SubOpXalu};
+
+fn new(pic: bool) -> DynAsm {
+    match pic {
+        true => DynAsm::new_pic("R5".into()).unwrap(),
+        false => DynAsm::new(0x1000),
+    }
+}
+
+fn alu(op: SubOpXalu, dst: &Register, a: &Register, b: &Register) -> Instruction {
+    Instruction::xalur(op, DpCntl::Word, dst.clone(), a.clone(), b.clone())
+}
+
+fn mov(dst: &str, src: Register) -> Instruction {
+    Instruction::i_type(Opcode::ORI, dst.into(), src, 0)
+}
+
+fn finish_and_run(mut asm: DynAsm) -> common::Machine {
+    asm.allocate().unwrap();
+    asm.optimize();
+    let image = asm.finish().unwrap();
+    let machine = common::run(image.bytes(), image.base());
+    assert!(machine.callee_saved_kept(), "{:X?}", machine.regs);
+    machine
+}
+
+#[test]
+fn gcd_with_nested_blocks() {
+    // while a != b { if a > b { a -= b } else { b -= a } }
+    for (pic, a, b, gcd) in [(false, 1071, 462, 21), (true, 48, 18, 6), (false, 7, 7, 7), (true, 1, 0x1000, 1)] {
+        let mut asm = new(pic);
+        asm.gen_header().unwrap();
+        let (ra, rb) = (asm.new_vreg(), asm.new_vreg());
+        asm.gen_load(ra.clone(), a).unwrap();
+        asm.gen_load(rb.clone(), b).unwrap();
+        asm.while_loop(Cond::NE, ra.clone(), rb.clone(), |asm| {
+            asm.if_else(
+                Cond::A,
+                ra.clone(),
+                rb.clone(),
+                |asm| asm.gen(alu(SubOpXalu::SUB, &ra, &ra, &rb)),
+                |asm| asm.gen(alu(SubOpXalu::SUB, &rb, &rb, &ra)),
+            )
+        })
+        .unwrap();
+        asm.gen(mov("EDX", ra)).unwrap();
+        asm.gen_footer().unwrap();
+
+        assert_eq!(finish_and_run(asm).edx(), gcd);
+    }
+}
+
+#[test]
+fn memcpy_with_a_counted_loop() {
+    const WORDS: [u32; 5] = [0x11, 0x2222, 0x333333, 0x44444444, 0xDEADBEEF];
+    const DST: u32 = 0x9000;
+    for pic in [false, true] {
+        let mut asm = new(pic);
+        asm.gen_header().unwrap();
+        let source = asm.new_sym();
+        let (src, dst, count) = (asm.new_vreg(), asm.new_vreg(), asm.new_vreg());
+        asm.gen_load_symbol(src.clone(), source).unwrap();
+        asm.gen_load(dst.clone(), DST).unwrap();
+        asm.gen_load(count.clone(), WORDS.len() as u32).unwrap();
+        asm.for_loop(0.into(), count, |asm, i| {
+            let (offset, word, at) = (asm.new_vreg(), asm.new_vreg(), asm.new_vreg());
+            asm.gen_load(offset.clone(), 2)?;
+            asm.gen(alu(SubOpXalu::SHL, &offset, &i, &offset))?;
+            asm.gen(alu(SubOpXalu::ADD, &at, &src, &offset))?;
+            asm.gen(Instruction::xl(Size::Bits32, at.clone(), word.clone()))?;
+            asm.gen(alu(SubOpXalu::ADD, &at, &dst, &offset))?;
+            asm.gen(Instruction::xs(Size::Bits32, at, word))
+        })
+        .unwrap();
+        asm.gen_footer().unwrap();
+
+        asm.set_section(Section::RODATA).unwrap();
+        asm.set_sym_here(source).unwrap();
+        for word in WORDS {
+            asm.gen_u32(word).unwrap();
+        }
+
+        let machine = finish_and_run(asm);
+        for (x, word) in WORDS.into_iter().enumerate() {
+            assert_eq!(machine.memory.get(&(DST + 4 * x as u32)), Some(&word));
+        }
+    }
+}
+
+#[test]
+fn switch_through_a_table() {
+    for (pic, index) in [false, true].into_iter().flat_map(|pic| [0, 1, 2, 3, 0xFFFF_FFFF].map(|x| (pic, x))) {
+        let mut asm = new(pic);
+        asm.gen_header().unwrap();
+        let value = asm.new_vreg();
+        asm.gen_load(value.clone(), index).unwrap();
+        asm.switch(value, 3, |asm, case| match case {
+            Some(case) => asm.gen_load("EDX".into(), 10 * (case as u32 + 1)),
+            None => asm.gen_load("EDX".into(), 99),
+        })
+        .unwrap();
+        asm.gen_footer().unwrap();
+
+        let machine = finish_and_run(asm);
+        let want = if index < 3 { 10 * (index + 1) } else { 99 };
+        assert_eq!(machine.edx(), want, "index {}", index as i32);
+    }
+}
+
+#[test]
+fn functions_return_from_inside_blocks() {
+    // fib(n) = n when n < 2, else fib(n - 1) + fib(n - 2)
+    for pic in [false, true] {
+        let mut asm = new(pic);
+        asm.gen_header().unwrap();
+        let fib = asm.new_named_sym("fib").unwrap();
+        let n = asm.new_vreg();
+        asm.gen_load(n.clone(), 10).unwrap();
+        let result = asm.gen_call(fib, &[n]).unwrap();
+        asm.gen(mov("EDX", result)).unwrap();
+        asm.gen_footer().unwrap();
+
+        asm.function("fib", |f| {
+            let (n, two) = (f.arg(0)?, f.new_vreg());
+            f.gen_load(two.clone(), 2)?;
+            f.if_else(Cond::B, n.clone(), two, |f| f.ret(Some(n.clone())), |_| Ok(()))?;
+            let (m, k) = (f.new_vreg(), f.new_vreg());
+            f.gen(Instruction::i_type(Opcode::ADDI, m.clone(), n.clone(), 0xFFFF))?;
+            f.gen(Instruction::i_type(Opcode::ADDI, k.clone(), n, 0xFFFE))?;
+            let a = f.gen_call(fib, &[m])?;
+            let b = f.gen_call(fib, &[k])?;
+            f.gen(alu(SubOpXalu::ADD, &a, &a, &b))?;
+            f.ret(Some(a))
+        })
+        .unwrap();
+
+        assert_eq!(finish_and_run(asm).edx(), 55);
+    }
+}
@@ -0,0 +1,104 @@
+// Symbols: names are unique and can be looked up, the map lists them, and symbols that can't be resolved because they
+// were never placed or were handed to the wrong DynAsm
+
+use ais_asm::dynasm::Section;
+use ais_asm::{DynAsm, DynAsmError};
+
+#[test]
+fn names_are_unique_and_looked_up() {
+    let mut asm = DynAsm::new(0x1000);
+    let entry = asm.new_named_sym("entry").unwrap();
+    let anonymous = asm.new_sym();
+    let table = asm.new_named_sym("table").unwrap();
+
+    let Err(error) = asm.new_named_sym("entry") else {
+        panic!("expected a duplicate symbol");
+    };
+    assert!(matches!(&error, DynAsmError::DuplicateSymbol(x) if x == "entry"));
+    assert_eq!(error.to_string(), "symbol `entry` is already defined");
+    // Anonymous symbols don't take a name, the generated one isn't looked up
+    assert!(asm.new_sym() != anonymous);
+    assert!(asm.sym_by_name("sym#1").is_none());
+
+    assert!(asm.sym_by_name("entry") == Some(entry));
+    assert!(asm.sym_by_name("table") == Some(table));
+    assert!(asm.sym_by_name("Entry").is_none());
+    assert_eq!(asm.sym_name(table).unwrap(), "table");
+    assert_eq!(asm.sym_name(anonymous).unwrap(), "sym#1");
+}
+
+#[test]
+fn map_lists_address_size_and_section() {
+    let mut asm = DynAsm::new(0x1000);
+    let [entry, next, table] = ["entry", "next", "table"].map(|x| asm.new_named_sym(x).unwrap());
+    asm.set_sym_here(entry).unwrap();
+    asm.gen_u32(1).unwrap();
+    // Anonymous symbols aren't listed and don't end the size of the named one before them
+    asm.new_sym_here();
+    asm.gen_u16(2).unwrap();
+    asm.set_sym_here(next).unwrap();
+    asm.gen_u8(3).unwrap();
+    asm.set_section(Section::RODATA).unwrap();
+    asm.set_sym_here(table).unwrap();
+    asm.gen_u32(4).unwrap();
+    asm.gen_u32_expr(table.into()).unwrap();
+    // Named but never placed or used, it isn't listed
+    asm.new_named_sym("unused").unwrap();
+
+    let mut map = Vec::new();
+    asm.finish().unwrap().write_map(&mut map).unwrap();
+    let expected = "\
+Address    Size       Section
+0x00001000 0x00000007 .text
+0x00001008 0x00000008 .rodata
+0x00001010 0x00000000 .data
+0x00001010 0x00000000 .bss
+
+Address    Size       Section    Name
+0x00001000 0x00000006 .text      entry
+0x00001006 0x00000001 .text      next
+0x00001008 0x00000008 .rodata    table
+";
+    assert_eq!(String::from_utf8(map).unwrap(), expected);
+}
+
+#[test]
+fn unplaced_symbols_are_reported_with_their_references() {
+    let mut asm = DynAsm::new(0x1000);
+    let (missing, anonymous, placed) = (asm.new_named_sym("missing").unwrap(), asm.new_sym(), asm.new_sym());
+    asm.gen_load_symbol("EAX".into(), missing).unwrap();
+    asm.gen_u32_expr(missing + 4).unwrap();
+    asm.set_sym_here(placed).unwrap();
+    asm.gen_u32_expr(anonymous - placed).unwrap();
+
+    let Err(DynAsmError::Unresolved(unresolved)) = asm.finish() else {
+        panic!("expected unresolved symbols");
+    };
+    assert_eq!(unresolved.len(), 2);
+    assert_eq!((unresolved[0].name.as_str(), unresolved[0].refs.as_slice()), ("missing", [0, 6].as_slice()));
+    assert_eq!((unresolved[1].name.as_str(), unresolved[1].refs.as_slice()), ("sym#1", [10].as_slice()));
+    assert_eq!(
+        DynAsmError::Unresolved(unresolved).to_string(),
+        "unresolved symbols: `missing` referenced at 0x0, 0x6, `sym#1` referenced at 0xA"
+    );
+}
+
+#[test]
+fn symbols_of_another_asm_are_rejected() {
+    let mut other = DynAsm::new(0);
+    let foreign = other.new_named_sym("foreign").unwrap();
+
+    let mut asm = DynAsm::new(0x1000);
+    let own = asm.new_sym_here();
+    assert!(matches!(asm.gen_load_symbol("EAX".into(), foreign), Err(DynAsmError::ForeignSym)));
+    assert!(matches!(asm.gen_u32_expr(foreign + 4), Err(DynAsmError::ForeignSym)));
+    assert!(matches!(asm.gen_u32_expr(own - foreign), Err(DynAsmError::ForeignSym)));
+    assert!(matches!(asm.set_sym_here(foreign), Err(DynAsmError::ForeignSym)));
+    assert!(matches!(asm.gen_jump_via(foreign, "EAX".into()), Err(DynAsmError::ForeignSym)));
+    assert!(matches!(asm.sym_name(foreign), Err(DynAsmError::ForeignSym)));
+
+    // Nothing of the rejected calls ended up in the payload, and the other asm still owns its symbol
+    assert!(asm.finish().unwrap().bytes().is_empty());
+    other.set_sym_here(foreign).unwrap();
+    assert!(other.finish().is_ok());
+}
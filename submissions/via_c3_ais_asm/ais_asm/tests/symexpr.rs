@@ -0,0 +1,88 @@
+// Values of symbol expressions: addends, differences and the halves that are patched into immediates
+
+use ais_asm::dynasm::SymRefKind;
+use ais_asm::{DynAsm, Instruction, Opcode, Register, Sym, SymExpr};
+
+const BASE: u32 = 0x1234_0000;
+
+type Expr = fn(Sym, Sym) -> SymExpr;
+
+// a at 0x12348000, its low half has the sign bit set, b 16 bytes after it. The code follows at 0x12348010.
+fn new() -> (DynAsm, Sym, Sym) {
+    let mut asm = DynAsm::new(BASE);
+    asm.gen_zeros(0x8000).unwrap();
+    let a = asm.new_sym_here();
+    asm.gen_zeros(0x10).unwrap();
+    let b = asm.new_sym_here();
+    (asm, a, b)
+}
+
+fn code(asm: DynAsm) -> Vec<u8> {
+    asm.finish().unwrap().bytes()[0x8010..].to_vec()
+}
+
+fn i_type(opcode: Opcode, dst: Register, src: Register, imm: u16) -> Vec<u8> {
+    Instruction::i_type(opcode, dst, src, imm).encode().unwrap()
+}
+
+#[test]
+fn halves_of_expressions() {
+    let cases: [(Expr, u32); 7] = [
+        (|a, _| a.into(), 0x1234_8000),
+        (|a, _| a + 0x7FFF, 0x1234_FFFF),
+        (|a, _| a + 0x8000, 0x1235_0000),
+        (|a, _| a - 0x8001, 0x1233_FFFF),
+        (|a, _| a - 0x1234_8000, 0),
+        (|a, b| b - a, 0x10),
+        (|a, b| a - b - 0x20, 0xFFFF_FFD0),
+    ];
+
+    for (expr, value) in cases {
+        let (mut asm, a, b) = new();
+        let expr = expr(a, b);
+        asm.gen_i_type_expr(Opcode::ORIU, "EAX".into(), 0.into(), expr, SymRefKind::HighImm).unwrap();
+        asm.gen_i_type_expr(Opcode::ORIU, "EAX".into(), 0.into(), expr, SymRefKind::HighImmAdj).unwrap();
+        asm.gen_i_type_expr(Opcode::ADDI, "EAX".into(), "EAX".into(), expr, SymRefKind::LowImm).unwrap();
+        asm.gen_u32_expr(expr).unwrap();
+
+        let adj = (value.wrapping_add(0x8000) >> 16) as u16;
+        let expected = [
+            i_type(Opcode::ORIU, "EAX".into(), 0.into(), (value >> 16) as u16),
+            i_type(Opcode::ORIU, "EAX".into(), 0.into(), adj),
+            i_type(Opcode::ADDI, "EAX".into(), "EAX".into(), value as u16),
+            value.to_le_bytes().to_vec(),
+        ];
+        assert_eq!(code(asm)[..22], expected.concat(), "0x{:08X}", value);
+
+        // The adjusted high half and the sign extended low half add up to the value again
+        assert_eq!(((adj as u32) << 16).wrapping_add(value as u16 as i16 as u32), value);
+    }
+}
+
+#[test]
+fn hiadj_for_known_values() {
+    // Low half below 0x8000 keeps the high half, from 0x8000 on it rounds up, and wraps at the top
+    for (value, adj) in [(0x1234_7FFFu32, 0x1234), (0x1234_8000, 0x1235), (0xFFFF_8000, 0), (0xFFFF_FFF0, 0)] {
+        let (mut asm, a, _) = new();
+        let expr = a + value.wrapping_sub(0x1234_8000) as i32;
+        asm.gen_i_type_expr(Opcode::ORIU, "EAX".into(), 0.into(), expr, SymRefKind::HighImmAdj).unwrap();
+        assert_eq!(code(asm)[..6], i_type(Opcode::ORIU, "EAX".into(), 0.into(), adj), "0x{:08X}", value);
+    }
+}
+
+#[test]
+fn addi_loads_use_the_adjusted_half() {
+    let (mut asm, a, b) = new();
+    asm.gen_load_expr_addi("EAX".into(), a.into()).unwrap();
+    asm.gen_load_expr_addi("EAX".into(), a - 0x8001).unwrap();
+    // Negative differences fit the sign extended low half alone
+    asm.gen_load_expr_addi("EAX".into(), a - b).unwrap();
+    let expected = [
+        i_type(Opcode::ORIU, "EAX".into(), 0.into(), 0x1235),
+        i_type(Opcode::ADDI, "EAX".into(), "EAX".into(), 0x8000),
+        i_type(Opcode::ORIU, "EAX".into(), 0.into(), 0x1234),
+        i_type(Opcode::ADDI, "EAX".into(), "EAX".into(), 0xFFFF),
+        i_type(Opcode::ADDI, "EAX".into(), 0.into(), 0xFFF0),
+    ];
+    assert_eq!(code(asm)[..30], expected.concat());
+}
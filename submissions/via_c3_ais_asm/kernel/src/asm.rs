@@ -65,4 +65,13 @@ pub fn cpuid(val: u32) -> [u32; 4] {
     }
 
     [eax,ebx,ecx,edx]
-}
\ No newline at end of file
+}
+
+#[inline]
+pub fn cr0() -> u32 {
+    let cr0;
+    unsafe {
+        asm!("mov {cr0}, cr0", cr0 = out(reg) cr0, options(nomem, nostack, preserves_flags));
+    }
+    cr0
+}
@@ -0,0 +1,43 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{addr_of_mut, null_mut};
+use spin::Mutex;
+
+// Bump allocator for the assembler. Memory is never freed, the kernel only assembles a few small payloads.
+// The self-test runs the code it assembles straight from the heap. That only works because GRUB starts the kernel
+// with paging off and the kernel never turns it on, so all memory is executable. Check executable() before jumping.
+const HEAP_SIZE: usize = 1024 * 1024;
+
+// Paging bit of CR0
+const CR0_PG: u32 = 1 << 31;
+
+static mut HEAP: [u8; HEAP_SIZE] = [0; HEAP_SIZE];
+
+pub struct BumpAllocator {
+    next: Mutex<usize>,
+}
+
+unsafe impl GlobalAlloc for BumpAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut next = self.next.lock();
+        let heap = addr_of_mut!(HEAP) as *mut u8;
+
+        let start = (heap as usize + *next).next_multiple_of(layout.align()) - heap as usize;
+        let end = match start.checked_add(layout.size()) {
+            Some(end) if end <= HEAP_SIZE => end,
+            _ => return null_mut(),
+        };
+
+        *next = end;
+        heap.add(start)
+    }
+
+    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
+}
+
+// Whether code on the heap can run, true as long as paging is off
+pub fn executable() -> bool {
+    crate::asm::cr0() & CR0_PG == 0
+}
+
+#[global_allocator]
+static ALLOCATOR: BumpAllocator = BumpAllocator { next: Mutex::new(0) };
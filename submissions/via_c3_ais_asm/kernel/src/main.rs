@@ -4,20 +4,29 @@
 #![feature(asm_sym)]
 #![feature(naked_functions)]
 
+extern crate alloc;
+
 mod asm;
+mod heap;
 mod multiboot;
 mod panic;
 mod print;
 mod uart;
 
+use ais_asm::dynasm::LineKind;
+use ais_asm::{reloc, DynAsm, DynAsmError, Image, Instruction};
 use core::arch::asm;
 use crate::print::SERIAL1;
 
-// Include the payload, linker script will place it a 0x48000
+// Include the payload, the linker script can place it anywhere. It is mutable so it can be relocated.
 #[link_section = ".payload"]
-static PAYLOAD: [u8; PAYLOAD_LEN] = *core::include_bytes!("../../ais_asm/out.bin");
+static mut PAYLOAD: [u8; PAYLOAD_LEN] = *core::include_bytes!("../../ais_asm/out.bin");
 const PAYLOAD_LEN: usize = core::include_bytes!("../../ais_asm/out.bin").len();
 
+// Relocation table of the payload. It always has the 12 byte header, relocate() rejects an empty file. A position
+// independent payload still has a Word entry for every absolute data word, the demo has none so only the header.
+static PAYLOAD_RELOCS: &[u8] = core::include_bytes!("../../ais_asm/out.rel");
+
 pub fn multiboot_entry(_: &[u8]) {
     println!("");
     println!("Kernel started");
@@ -37,7 +46,14 @@ pub fn multiboot_entry(_: &[u8]) {
 
     println!("AIS is supported and has been enabled");
 
-    println!("Run payload at 0x{:08X}", PAYLOAD.as_ptr() as u32);
+    // Patch the absolute addresses in the payload for the address it ended up at
+    let payload = unsafe { &mut *core::ptr::addr_of_mut!(PAYLOAD) };
+    let payload_addr = payload.as_ptr() as u32;
+    if let Err(e) = reloc::relocate(payload, PAYLOAD_RELOCS, payload_addr) {
+        panic!("Payload relocation failed: {}", e);
+    }
+
+    println!("Run payload at 0x{:08X}", payload_addr);
 
     // Flush serial
     while !SERIAL1.lock().tx_empty() {
@@ -45,14 +61,49 @@ pub fn multiboot_entry(_: &[u8]) {
     }
 
     // Run payload
-    let payload: extern "C" fn() -> u32 = unsafe { core::mem::transmute(PAYLOAD.as_ptr()) };
+    let payload: extern "C" fn() -> u32 = unsafe { core::mem::transmute(payload.as_ptr()) };
     let r = payload();
 
     // Show result
     println!("Result EAX = 0x{:08X}", r);
 
+    self_test();
+
     println!("Done");
     loop {
         asm::halt()
     }
 }
+
+// Assemble a payload on the target itself, show its disassembly, run it and check the result
+fn self_test() {
+    let image = assemble_self_test().unwrap_or_else(|e| panic!("Self-test assembly failed: {}", e));
+
+    println!("Self-test payload:");
+    for line in image.lines().iter().filter(|x| x.kind == LineKind::Instruction) {
+        match Instruction::decode(image.bytes_at(line.addr, line.len)) {
+            Ok((instr, _)) => {
+                println!("  {:04X}  {}", line.addr, instr);
+            }
+            Err(e) => {
+                println!("  {:04X}  {}", line.addr, e);
+            }
+        }
+    }
+
+    // Position independent, so it runs from the heap. The heap is only executable without paging.
+    assert!(heap::executable(), "Paging is on, the heap may not be executable");
+    let payload: extern "C" fn() -> u32 = unsafe { core::mem::transmute(image.bytes().as_ptr()) };
+    let r = payload();
+    assert!(r == 0xC0FFEE, "Self-test returned EAX = 0x{:08X}", r);
+
+    println!("Self-test passed");
+}
+
+fn assemble_self_test() -> Result<Image, DynAsmError> {
+    let mut asm = DynAsm::new_pic("R5".into())?;
+    asm.gen_header()?;
+    asm.gen_load("EAX".into(), 0xC0FFEE)?;
+    asm.gen_footer()?;
+    asm.finish()
+}